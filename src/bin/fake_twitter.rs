@@ -0,0 +1,663 @@
+#![feature(futures_api, async_await, await_macro)]
+
+//! A stand-in for the parts of the twitter API that de-list-server uses, backed by an in-memory
+//! fixture of users and lists, so that the whole login -> memberships -> removal flow can be run
+//! without twitter credentials. Point the server at it with `TWITTER_API_BASE_URL`.
+//!
+//! Every request's OAuth signature is checked with the same code that signs the server's
+//! requests, so anything that real twitter would reject gets rejected here too.
+
+use de_list_server::egg_mode_2;
+use egg_mode::KeyPair;
+use egg_mode_2::{ApiEndpoints, Endpoint, ParamList, TwitterOAuth};
+use http_service::Body;
+use hyper::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE, LOCATION};
+use hyper::{Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use rand::distributions::{Alphanumeric, Distribution};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::fs;
+use std::sync::{Mutex, MutexGuard};
+use url::{form_urlencoded, Url};
+
+const DEFAULT_FIXTURE: &'static str = include_str!("../../fixtures/fake_twitter.json");
+
+#[derive(Clone, Deserialize)]
+struct User {
+    id: u64,
+    screen_name: String,
+    name: String,
+    /// Following them needs their approval, which never comes.
+    #[serde(default)]
+    protected: bool,
+}
+
+#[derive(Clone, Deserialize)]
+struct List {
+    id: u64,
+    owner_id: u64,
+    name: String,
+    slug: String,
+    #[serde(default)]
+    description: String,
+    members: BTreeSet<u64>,
+}
+
+#[derive(Deserialize)]
+struct Block {
+    blocker: u64,
+    blocked: u64,
+}
+
+#[derive(Deserialize)]
+struct Follow {
+    follower: u64,
+    followed: u64,
+}
+
+#[derive(Deserialize)]
+struct Fixture {
+    users: Vec<User>,
+    lists: Vec<List>,
+    /// Anyone who's already blocked someone before the server gets involved.
+    #[serde(default)]
+    blocks: Vec<Block>,
+    #[serde(default)]
+    follows: Vec<Follow>,
+}
+
+/// Someone part way through logging in.
+struct PendingLogin {
+    secret: String,
+    callback: String,
+    /// Set once they've picked who to log in as.
+    authorized: Option<(u64, String)>,
+}
+
+struct AccessToken {
+    secret: String,
+    user_id: u64,
+}
+
+struct FakeTwitter {
+    users: HashMap<u64, User>,
+    lists: Vec<List>,
+    request_tokens: HashMap<String, PendingLogin>,
+    access_tokens: HashMap<String, AccessToken>,
+    /// (blocker, blocked)
+    blocks: BTreeSet<(u64, u64)>,
+    /// (follower, followed)
+    follows: BTreeSet<(u64, u64)>,
+}
+
+impl FakeTwitter {
+    fn new(fixture: Fixture) -> Self {
+        FakeTwitter {
+            users: fixture
+                .users
+                .into_iter()
+                .map(|user| (user.id, user))
+                .collect(),
+            lists: fixture.lists,
+            request_tokens: HashMap::new(),
+            access_tokens: HashMap::new(),
+            blocks: fixture
+                .blocks
+                .into_iter()
+                .map(|block| (block.blocker, block.blocked))
+                .collect(),
+            follows: fixture
+                .follows
+                .into_iter()
+                .map(|follow| (follow.follower, follow.followed))
+                .collect(),
+        }
+    }
+}
+
+lazy_static! {
+    /// Should be the same as the server's `CONSUMER_KEY` and `CONSUMER_SECRET`.
+    static ref CONSUMER_TOKEN: KeyPair = {
+        let consumer_key =
+            env::var("CONSUMER_KEY").unwrap_or_else(|_| "fake-consumer-key".to_owned());
+        let consumer_secret =
+            env::var("CONSUMER_SECRET").unwrap_or_else(|_| "fake-consumer-secret".to_owned());
+        KeyPair::new(consumer_key, consumer_secret)
+    };
+
+    static ref ADDRESS: String =
+        env::var("FAKE_TWITTER_ADDRESS").unwrap_or_else(|_| "127.0.0.1:4000".to_owned());
+
+    /// The urls that requests are signed with, which should be the same as the server's
+    /// `TWITTER_API_BASE_URL`.
+    static ref ENDPOINTS: ApiEndpoints = {
+        let base_url = env::var("FAKE_TWITTER_BASE_URL")
+            .unwrap_or_else(|_| format!("http://{}", *ADDRESS));
+        ApiEndpoints::new(&base_url).unwrap()
+    };
+
+    /// Set `FAKE_TWITTER_FIXTURE` to the path of a json file to use instead of the built in
+    /// fixture, see `fixtures/fake_twitter.json` for the format.
+    static ref STATE: Mutex<FakeTwitter> = {
+        let fixture = match env::var("FAKE_TWITTER_FIXTURE") {
+            Ok(path) => fs::read_to_string(path).unwrap(),
+            Err(_) => DEFAULT_FIXTURE.to_owned(),
+        };
+        Mutex::new(FakeTwitter::new(serde_json::from_str(&fixture).unwrap()))
+    };
+}
+
+type HandlerResult = Result<Response<Body>, Response<Body>>;
+
+fn state() -> MutexGuard<'static, FakeTwitter> {
+    STATE.lock().unwrap()
+}
+
+fn random_token() -> String {
+    Alphanumeric
+        .sample_iter(&mut rand::thread_rng())
+        .take(32)
+        .collect()
+}
+
+/// An error like twitter's, see https://developer.twitter.com/en/docs/basics/response-codes
+fn twitter_error(status: StatusCode, code: i32, message: &str) -> Response<Body> {
+    let body = json!({ "errors": [{ "code": code, "message": message }] });
+    let mut response = json_response(&body);
+    *response.status_mut() = status;
+    response
+}
+
+fn could_not_authenticate() -> Response<Body> {
+    twitter_error(StatusCode::UNAUTHORIZED, 32, "Could not authenticate you.")
+}
+
+fn invalid_token() -> Response<Body> {
+    twitter_error(StatusCode::UNAUTHORIZED, 89, "Invalid or expired token.")
+}
+
+fn not_found() -> Response<Body> {
+    twitter_error(
+        StatusCode::NOT_FOUND,
+        34,
+        "Sorry, that page does not exist.",
+    )
+}
+
+fn json_response(body: &serde_json::Value) -> Response<Body> {
+    let mut response = Response::new(Body::from(body.to_string()));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+/// The oauth endpoints answer with form encoded bodies rather than json.
+fn form_response(pairs: &[(&str, &str)]) -> Response<Body> {
+    let body = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(pairs)
+        .finish();
+    Response::new(Body::from(body))
+}
+
+fn redirect(url: &Url) -> HandlerResult {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::FOUND;
+    let location = HeaderValue::from_str(url.as_str()).map_err(|_| not_found())?;
+    response.headers_mut().insert(LOCATION, location);
+    Ok(response)
+}
+
+fn query_params(request: &Request<Body>) -> ParamList<'static> {
+    let query = request.uri().query().unwrap_or_default();
+    form_urlencoded::parse(query.as_bytes())
+        .map(|(key, value)| (key.into_owned().into(), value.into_owned().into()))
+        .collect()
+}
+
+fn param<'a>(params: &'a ParamList, key: &str) -> Result<&'a str, Response<Body>> {
+    params.get(key).map(|value| value.as_ref()).ok_or_else(|| {
+        let message = format!("Missing required parameter: {}.", key);
+        twitter_error(StatusCode::BAD_REQUEST, 38, &message)
+    })
+}
+
+fn user_id_param(params: &ParamList) -> Result<u64, Response<Body>> {
+    param(params, "user_id")?.parse().map_err(|_| not_found())
+}
+
+fn oauth_header(request: &Request<Body>) -> Result<TwitterOAuth, Response<Body>> {
+    let header = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| twitter_error(StatusCode::BAD_REQUEST, 215, "Bad Authentication data."))?;
+    let oauth = TwitterOAuth::from_header_value(header).map_err(|e| {
+        log::warn!("Could not parse Authorization header {}: {}", header, e);
+        twitter_error(StatusCode::BAD_REQUEST, 215, "Bad Authentication data.")
+    })?;
+    if oauth.consumer_key() != CONSUMER_TOKEN.key {
+        return Err(could_not_authenticate());
+    }
+    Ok(oauth)
+}
+
+/// Check the request was signed for this endpoint by our consumer and, if given, the token.
+fn check_signature(
+    request: &Request<Body>,
+    oauth: &TwitterOAuth,
+    endpoint: Endpoint,
+    token: Option<&KeyPair>,
+) -> Result<ParamList<'static>, Response<Body>> {
+    let params = query_params(request);
+    let uri = ENDPOINTS.url(endpoint);
+    let method = request.method().clone();
+    if egg_mode_2::verify(oauth, method, &uri, &params, &CONSUMER_TOKEN, token) {
+        Ok(params)
+    } else {
+        log::warn!("Bad signature for {}", uri);
+        Err(could_not_authenticate())
+    }
+}
+
+/// Check the request was signed with one of the access tokens we've handed out, and say whose it
+/// is.
+fn authenticate_user(
+    request: &Request<Body>,
+    endpoint: Endpoint,
+) -> Result<(u64, ParamList<'static>), Response<Body>> {
+    let oauth = oauth_header(request)?;
+    let token = oauth.token().ok_or_else(invalid_token)?;
+    let (secret, user_id) = match state().access_tokens.get(token) {
+        Some(access_token) => (access_token.secret.clone(), access_token.user_id),
+        None => return Err(invalid_token()),
+    };
+    let access_token = KeyPair::new(token.to_owned(), secret);
+    let params = check_signature(request, &oauth, endpoint, Some(&access_token))?;
+    Ok((user_id, params))
+}
+
+fn request_token(request: &Request<Body>) -> HandlerResult {
+    let oauth = oauth_header(request)?;
+    check_signature(request, &oauth, Endpoint::RequestToken, None)?;
+    let callback = oauth.callback().ok_or_else(could_not_authenticate)?;
+
+    let token = random_token();
+    let secret = random_token();
+    state().request_tokens.insert(
+        token.clone(),
+        PendingLogin {
+            secret: secret.clone(),
+            callback: callback.to_owned(),
+            authorized: None,
+        },
+    );
+    Ok(form_response(&[
+        ("oauth_token", &token),
+        ("oauth_token_secret", &secret),
+        ("oauth_callback_confirmed", "true"),
+    ]))
+}
+
+/// Instead of a login form, just ask which of the fixture's users to log in as.
+fn authenticate(request: &Request<Body>) -> HandlerResult {
+    let params = query_params(request);
+    let token = param(&params, "oauth_token")?;
+    if !state().request_tokens.contains_key(token) {
+        return Err(invalid_token());
+    }
+
+    let mut users: Vec<User> = state().users.values().cloned().collect();
+    users.sort_by_key(|user| user.id);
+    let links = users
+        .iter()
+        .map(|user| {
+            format!(
+                "<li><a href=\"/oauth/authenticate/approve?oauth_token={}&user_id={}\">@{}</a></li>",
+                token, user.id, user.screen_name
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let body = format!(
+        "<html>\n<header><title>Fake twitter</title></header>\n<body>\n\
+         <p>Log in as:</p>\n<ul>\n{}\n</ul>\n\
+         <a href=\"/oauth/authenticate/deny?oauth_token={}\">Cancel</a>\n</body>\n</html>",
+        links, token
+    );
+    Ok(Response::new(Body::from(body)))
+}
+
+fn approve(request: &Request<Body>) -> HandlerResult {
+    let params = query_params(request);
+    let token = param(&params, "oauth_token")?;
+    let user_id = user_id_param(&params)?;
+
+    let mut state = state();
+    if !state.users.contains_key(&user_id) {
+        return Err(not_found());
+    }
+    let login = state
+        .request_tokens
+        .get_mut(token)
+        .ok_or_else(invalid_token)?;
+    let verifier = random_token();
+    login.authorized = Some((user_id, verifier.clone()));
+
+    let mut callback = Url::parse(&login.callback).map_err(|_| not_found())?;
+    callback
+        .query_pairs_mut()
+        .append_pair("oauth_token", token)
+        .append_pair("oauth_verifier", &verifier);
+    redirect(&callback)
+}
+
+fn deny(request: &Request<Body>) -> HandlerResult {
+    let params = query_params(request);
+    let token = param(&params, "oauth_token")?;
+    let login = state()
+        .request_tokens
+        .remove(token)
+        .ok_or_else(invalid_token)?;
+
+    let mut callback = Url::parse(&login.callback).map_err(|_| not_found())?;
+    callback.query_pairs_mut().append_pair("denied", token);
+    redirect(&callback)
+}
+
+fn access_token(request: &Request<Body>) -> HandlerResult {
+    let oauth = oauth_header(request)?;
+    let token = oauth.token().ok_or_else(invalid_token)?;
+
+    let mut state = state();
+    let (secret, authorized) = match state.request_tokens.get(token) {
+        Some(login) => (login.secret.clone(), login.authorized.clone()),
+        None => return Err(invalid_token()),
+    };
+    let request_token = KeyPair::new(token.to_owned(), secret);
+    check_signature(request, &oauth, Endpoint::AccessToken, Some(&request_token))?;
+    let user_id = match &authorized {
+        Some((user_id, verifier)) if oauth.verifier() == Some(verifier.as_str()) => *user_id,
+        _ => return Err(invalid_token()),
+    };
+    state.request_tokens.remove(token);
+
+    let access_token = format!("{}-{}", user_id, random_token());
+    let access_secret = random_token();
+    state.access_tokens.insert(
+        access_token.clone(),
+        AccessToken {
+            secret: access_secret.clone(),
+            user_id,
+        },
+    );
+    let screen_name = state.users[&user_id].screen_name.clone();
+    Ok(form_response(&[
+        ("oauth_token", &access_token),
+        ("oauth_token_secret", &access_secret),
+        ("user_id", &user_id.to_string()),
+        ("screen_name", &screen_name),
+    ]))
+}
+
+fn user_json(user: &User) -> serde_json::Value {
+    json!({
+        "id": user.id,
+        "id_str": user.id.to_string(),
+        "name": user.name,
+        "screen_name": user.screen_name,
+        "protected": user.protected,
+        "profile_image_url_https":
+            format!("https://abs.twimg.com/sticky/default_profile_images/{}.png", user.id),
+    })
+}
+
+/// The cursor is just how many lists to skip.
+fn memberships(request: &Request<Body>) -> HandlerResult {
+    let (authenticated_id, params) = authenticate_user(request, Endpoint::Memberships)?;
+    let user_id = match params.get("user_id") {
+        Some(_) => user_id_param(&params)?,
+        None => authenticated_id,
+    };
+    let cursor: i64 = params
+        .get("cursor")
+        .and_then(|cursor| cursor.parse().ok())
+        .unwrap_or(-1);
+    let count: usize = params
+        .get("count")
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(20)
+        .min(1000);
+
+    let state = state();
+    let lists: Vec<&List> = state
+        .lists
+        .iter()
+        .filter(|list| list.members.contains(&user_id))
+        .collect();
+    let start = cursor.max(0) as usize;
+    let end = (start + count).min(lists.len());
+    let page = lists.get(start..end).unwrap_or_default();
+    let next_cursor = if end < lists.len() { end as i64 } else { 0 };
+    let previous_cursor = if start == 0 { 0 } else { -(start as i64) };
+
+    let page_json = page
+        .iter()
+        .map(|list| {
+            let owner = &state.users[&list.owner_id];
+            json!({
+                "id": list.id,
+                "id_str": list.id.to_string(),
+                "name": list.name,
+                "slug": list.slug,
+                "full_name": format!("@{}/{}", owner.screen_name, list.slug),
+                "description": list.description,
+                "mode": "public",
+                "member_count": list.members.len(),
+                "subscriber_count": 0,
+                "user": user_json(owner),
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(json_response(&json!({
+        "lists": page_json,
+        "next_cursor": next_cursor,
+        "next_cursor_str": next_cursor.to_string(),
+        "previous_cursor": previous_cursor,
+        "previous_cursor_str": previous_cursor.to_string(),
+    })))
+}
+
+/// Blocking someone also takes you off all of their lists, which is the whole point, and makes
+/// you unfollow each other, which isn't.
+fn blocks_create(request: &Request<Body>) -> HandlerResult {
+    let (blocker_id, params) = authenticate_user(request, Endpoint::BlocksCreate)?;
+    let blocked_id = user_id_param(&params)?;
+
+    let mut state = state();
+    let blocked = state
+        .users
+        .get(&blocked_id)
+        .cloned()
+        .ok_or_else(not_found)?;
+    state.blocks.insert((blocker_id, blocked_id));
+    state.follows.remove(&(blocker_id, blocked_id));
+    state.follows.remove(&(blocked_id, blocker_id));
+    for list in state
+        .lists
+        .iter_mut()
+        .filter(|list| list.owner_id == blocked_id)
+    {
+        list.members.remove(&blocker_id);
+    }
+    log::info!("{} blocked {}", blocker_id, blocked_id);
+    Ok(json_response(&user_json(&blocked)))
+}
+
+fn blocks_destroy(request: &Request<Body>) -> HandlerResult {
+    let (blocker_id, params) = authenticate_user(request, Endpoint::BlocksDestroy)?;
+    let blocked_id = user_id_param(&params)?;
+
+    let mut state = state();
+    let blocked = state
+        .users
+        .get(&blocked_id)
+        .cloned()
+        .ok_or_else(not_found)?;
+    state.blocks.remove(&(blocker_id, blocked_id));
+    log::info!("{} unblocked {}", blocker_id, blocked_id);
+    Ok(json_response(&user_json(&blocked)))
+}
+
+/// `user_id` is a comma separated list of up to 100 ids, anyone we don't know is left out.
+fn friendships_lookup(request: &Request<Body>) -> HandlerResult {
+    let (user_id, params) = authenticate_user(request, Endpoint::FriendshipsLookup)?;
+    let other_ids = param(&params, "user_id")?
+        .split(',')
+        .map(|id| id.parse::<u64>().map_err(|_| not_found()))
+        .collect::<Result<Vec<_>, _>>()?;
+    if other_ids.len() > 100 {
+        let message = "Too many terms specified in query.";
+        return Err(twitter_error(StatusCode::FORBIDDEN, 18, message));
+    }
+
+    let state = state();
+    let friendships = other_ids
+        .iter()
+        .filter_map(|other_id| state.users.get(other_id))
+        .map(|other| {
+            let mut connections = Vec::new();
+            if state.follows.contains(&(user_id, other.id)) {
+                connections.push("following");
+            }
+            if state.follows.contains(&(other.id, user_id)) {
+                connections.push("followed_by");
+            }
+            if state.blocks.contains(&(user_id, other.id)) {
+                connections.push("blocking");
+            }
+            if connections.is_empty() {
+                connections.push("none");
+            }
+            json!({
+                "id": other.id,
+                "id_str": other.id.to_string(),
+                "name": other.name,
+                "screen_name": other.screen_name,
+                "connections": connections,
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(json_response(&json!(friendships)))
+}
+
+/// Protected users only get a follow request, which is never approved.
+fn friendships_create(request: &Request<Body>) -> HandlerResult {
+    let (follower_id, params) = authenticate_user(request, Endpoint::FriendshipsCreate)?;
+    let followed_id = user_id_param(&params)?;
+
+    let mut state = state();
+    let followed = state
+        .users
+        .get(&followed_id)
+        .cloned()
+        .ok_or_else(not_found)?;
+    if state.blocks.contains(&(followed_id, follower_id)) {
+        let message =
+            "You have been blocked from following this account at the request of the user.";
+        return Err(twitter_error(StatusCode::FORBIDDEN, 162, message));
+    }
+    let mut user = user_json(&followed);
+    if followed.protected {
+        user["follow_request_sent"] = json!(true);
+        log::info!("{} asked to follow {}", follower_id, followed_id);
+    } else {
+        state.follows.insert((follower_id, followed_id));
+        log::info!("{} followed {}", follower_id, followed_id);
+    }
+    Ok(json_response(&user))
+}
+
+/// Everyone fits on one page, so there's never a next cursor.
+fn blocks_ids(request: &Request<Body>) -> HandlerResult {
+    let (blocker_id, _) = authenticate_user(request, Endpoint::BlocksIds)?;
+
+    let state = state();
+    let ids = state
+        .blocks
+        .iter()
+        .filter(|(blocker, _)| *blocker == blocker_id)
+        .map(|(_, blocked)| *blocked)
+        .collect::<Vec<_>>();
+    Ok(json_response(&json!({
+        "ids": ids,
+        "next_cursor": 0,
+        "next_cursor_str": "0",
+        "previous_cursor": 0,
+        "previous_cursor_str": "0",
+    })))
+}
+
+/// Forget the access token that the request was signed with, so it can't be used again.
+fn invalidate_token(request: &Request<Body>) -> HandlerResult {
+    let (user_id, _params) = authenticate_user(request, Endpoint::InvalidateToken)?;
+    let oauth = oauth_header(request)?;
+    let token = oauth.token().ok_or_else(invalid_token)?;
+    state().access_tokens.remove(token);
+    log::info!("{} invalidated their access token", user_id);
+    Ok(json_response(&json!({ "access_token": token })))
+}
+
+async fn handle(
+    context: tide::Context<()>,
+    handler: fn(&Request<Body>) -> HandlerResult,
+) -> Response<Body> {
+    let request = context.request();
+    let response = handler(request).unwrap_or_else(|response| response);
+    log::debug!(
+        "{} {} -> {}",
+        request.method(),
+        request.uri(),
+        response.status()
+    );
+    response
+}
+
+fn main() -> std::io::Result<()> {
+    env_logger::init();
+
+    lazy_static::initialize(&ENDPOINTS);
+    lazy_static::initialize(&STATE);
+
+    let mut app = tide::App::new(());
+
+    app.at(Endpoint::RequestToken.path())
+        .post(|c| handle(c, request_token));
+    app.at(Endpoint::Authenticate.path())
+        .get(|c| handle(c, authenticate));
+    app.at("/oauth/authenticate/approve")
+        .get(|c| handle(c, approve));
+    app.at("/oauth/authenticate/deny").get(|c| handle(c, deny));
+    app.at(Endpoint::AccessToken.path())
+        .post(|c| handle(c, access_token));
+    app.at(Endpoint::InvalidateToken.path())
+        .post(|c| handle(c, invalidate_token));
+    app.at(Endpoint::Memberships.path())
+        .get(|c| handle(c, memberships));
+    app.at(Endpoint::BlocksCreate.path())
+        .post(|c| handle(c, blocks_create));
+    app.at(Endpoint::BlocksDestroy.path())
+        .post(|c| handle(c, blocks_destroy));
+    app.at(Endpoint::BlocksIds.path())
+        .get(|c| handle(c, blocks_ids));
+    app.at(Endpoint::FriendshipsLookup.path())
+        .get(|c| handle(c, friendships_lookup));
+    app.at(Endpoint::FriendshipsCreate.path())
+        .post(|c| handle(c, friendships_create));
+
+    log::info!("Fake twitter listening on {}", *ADDRESS);
+    app.serve(ADDRESS.as_str())
+}
@@ -0,0 +1,901 @@
+use crate::crypto::{Keyring, SigningKey};
+use crate::egg_mode_2::ApiEndpoints;
+use crate::error::*;
+use egg_mode::KeyPair;
+use failure::Fail;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tera::Tera;
+use url::Url;
+
+/// Used if it exists and no other config file is given.
+const DEFAULT_CONFIG_FILE: &'static str = "de-list.toml";
+
+const USAGE: &'static str = "\
+Usage: de-list-server [OPTIONS]
+
+Options:
+    --config <PATH>                 TOML config file [env: CONFIG_FILE, default: de-list.toml]
+    --bind-address <ADDRESS>        Address to listen on [env: BIND_ADDRESS]
+    --metrics-address <ADDRESS>     Address to serve /metrics on, which is off unless this is set.
+                                    Keep it private, it's not authenticated [env: METRICS_ADDRESS]
+    --public-url <URL>              Url that users reach the server at [env: PUBLIC_URL]
+    --database-path <PATH>          Sqlite database [env: DATABASE_PATH]
+    --template-dir <PATH>           Directory of tera templates [env: TEMPLATE_DIR]
+    --log-level <LEVEL>             off, error, warn, info, debug or trace [env: LOG_LEVEL]
+    --twitter-api-base-url <URL>    Where to find the twitter API [env: TWITTER_API_BASE_URL]
+    --token-store <STORE>           sqlite or memory [env: TOKEN_STORE]
+    --worker-count <COUNT>          How many removal jobs to run at once [env: WORKER_COUNT]
+    --request-token-ttl-secs <SECS> How long logins can take [env: REQUEST_TOKEN_TTL_SECS]
+    --session-ttl-secs <SECS>       How long users stay logged in [env: SESSION_TTL_SECS]
+    --token-retention <POLICY>      after_job to discard access tokens once a user's removal is
+                                    done, or e.g. 30d to keep them that long [env: TOKEN_RETENTION]
+    --check-config                  Check the config, print it and exit
+    --help                          Print this and exit
+
+Secrets can only be set in the config file or the environment, so that they don't show up in the
+process list: consumer_key [CONSUMER_KEY], consumer_secret [CONSUMER_SECRET], master_key
+[MASTER_KEY], previous_master_keys [PREVIOUS_MASTER_KEYS, comma separated] and session_key
+[SESSION_KEY].
+";
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum TokenStoreKind {
+    Sqlite,
+    Memory,
+}
+
+impl FromStr for TokenStoreKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "sqlite" => Ok(TokenStoreKind::Sqlite),
+            "memory" => Ok(TokenStoreKind::Memory),
+            other => Err(format!("expected sqlite or memory, got {}", other)),
+        }
+    }
+}
+
+/// How long users' access tokens are kept once their removals are done with them. Users who've
+/// opted into recurring runs keep theirs until they revoke it, whatever this says.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum TokenRetention {
+    /// Discard it as soon as the user's job finishes.
+    AfterJob,
+    /// Keep it for this many days after the user's last job, so they can come back without
+    /// logging in again.
+    Days(u64),
+}
+
+impl TokenRetention {
+    pub fn duration(self) -> Duration {
+        match self {
+            TokenRetention::AfterJob => Duration::from_secs(0),
+            TokenRetention::Days(days) => Duration::from_secs(days * 24 * 60 * 60),
+        }
+    }
+}
+
+impl FromStr for TokenRetention {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s == "after_job" {
+            return Ok(TokenRetention::AfterJob);
+        }
+        let days = if s.ends_with('d') {
+            s[..s.len() - 1].parse().ok()
+        } else {
+            None
+        };
+        days.map(TokenRetention::Days)
+            .ok_or_else(|| format!("expected after_job or a number of days like 30d, got {}", s))
+    }
+}
+
+impl fmt::Display for TokenRetention {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenRetention::AfterJob => write!(f, "after_job"),
+            TokenRetention::Days(days) => write!(f, "{}d", days),
+        }
+    }
+}
+
+/// Settings from a single source, any of which can be missing. Later sources override earlier
+/// ones: the config file, then the environment, then the command line.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    bind_address: Option<String>,
+    metrics_address: Option<String>,
+    public_url: Option<String>,
+    database_path: Option<PathBuf>,
+    template_dir: Option<PathBuf>,
+    log_level: Option<String>,
+    twitter_api_base_url: Option<String>,
+    token_store: Option<String>,
+    worker_count: Option<usize>,
+    request_token_ttl_secs: Option<u64>,
+    session_ttl_secs: Option<u64>,
+    token_retention: Option<String>,
+    consumer_key: Option<String>,
+    consumer_secret: Option<String>,
+    master_key: Option<String>,
+    previous_master_keys: Option<Vec<String>>,
+    session_key: Option<String>,
+}
+
+impl Settings {
+    fn from_file(path: &Path) -> Result<Settings> {
+        // The reasons go in the message, as they're what the person running the server needs.
+        let contents = fs::read_to_string(path).map_err(|e| -> Error {
+            let message = format!("could not read config file {}: {}", path.display(), e);
+            ErrorKind::ConfigError(message).into()
+        })?;
+        toml::from_str(&contents).map_err(|e| {
+            let message = format!("could not parse config file {}: {}", path.display(), e);
+            ErrorKind::ConfigError(message).into()
+        })
+    }
+
+    /// Bad numbers are added to `problems`, so they're reported along with everything else.
+    fn from_env(problems: &mut Problems) -> Settings {
+        Settings::from_vars(|name| env::var(name).ok(), problems)
+    }
+
+    fn from_vars(lookup: impl Fn(&str) -> Option<String>, problems: &mut Problems) -> Settings {
+        fn number<T: FromStr>(
+            var: &dyn Fn(&str) -> Option<String>,
+            problems: &mut Problems,
+            name: &str,
+        ) -> Option<T> {
+            var(name).and_then(|value| problems.check(parse_number(name, &value)))
+        }
+
+        let var = |name: &str| lookup(name).filter(|value| !value.is_empty());
+
+        Settings {
+            bind_address: var("BIND_ADDRESS"),
+            metrics_address: var("METRICS_ADDRESS"),
+            public_url: var("PUBLIC_URL"),
+            database_path: var("DATABASE_PATH").map(PathBuf::from),
+            template_dir: var("TEMPLATE_DIR").map(PathBuf::from),
+            log_level: var("LOG_LEVEL"),
+            twitter_api_base_url: var("TWITTER_API_BASE_URL"),
+            token_store: var("TOKEN_STORE"),
+            worker_count: number(&var, problems, "WORKER_COUNT"),
+            request_token_ttl_secs: number(&var, problems, "REQUEST_TOKEN_TTL_SECS"),
+            session_ttl_secs: number(&var, problems, "SESSION_TTL_SECS"),
+            token_retention: var("TOKEN_RETENTION"),
+            consumer_key: var("CONSUMER_KEY"),
+            consumer_secret: var("CONSUMER_SECRET"),
+            master_key: var("MASTER_KEY"),
+            previous_master_keys: var("PREVIOUS_MASTER_KEYS").map(|keys| {
+                keys.split(',')
+                    .map(str::trim)
+                    .filter(|key| !key.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            }),
+            session_key: var("SESSION_KEY"),
+        }
+    }
+
+    fn merge(self, overrides: Settings) -> Settings {
+        Settings {
+            bind_address: overrides.bind_address.or(self.bind_address),
+            metrics_address: overrides.metrics_address.or(self.metrics_address),
+            public_url: overrides.public_url.or(self.public_url),
+            database_path: overrides.database_path.or(self.database_path),
+            template_dir: overrides.template_dir.or(self.template_dir),
+            log_level: overrides.log_level.or(self.log_level),
+            twitter_api_base_url: overrides.twitter_api_base_url.or(self.twitter_api_base_url),
+            token_store: overrides.token_store.or(self.token_store),
+            worker_count: overrides.worker_count.or(self.worker_count),
+            request_token_ttl_secs: overrides
+                .request_token_ttl_secs
+                .or(self.request_token_ttl_secs),
+            session_ttl_secs: overrides.session_ttl_secs.or(self.session_ttl_secs),
+            token_retention: overrides.token_retention.or(self.token_retention),
+            consumer_key: overrides.consumer_key.or(self.consumer_key),
+            consumer_secret: overrides.consumer_secret.or(self.consumer_secret),
+            master_key: overrides.master_key.or(self.master_key),
+            previous_master_keys: overrides.previous_master_keys.or(self.previous_master_keys),
+            session_key: overrides.session_key.or(self.session_key),
+        }
+    }
+}
+
+fn parse_number<T: FromStr>(name: &str, value: &str) -> std::result::Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} must be a number, got {}", name, value))
+}
+
+fn parse_arg<T: FromStr>(flag: &str, value: String) -> Result<T> {
+    parse_number(flag, &value).map_err(|problem| ErrorKind::ConfigError(problem).into())
+}
+
+/// Everything that's wrong with the config so far.
+#[derive(Default)]
+struct Problems(Vec<String>);
+
+impl Problems {
+    fn add(&mut self, problem: String) {
+        self.0.push(problem);
+    }
+
+    fn check<T>(&mut self, result: std::result::Result<T, String>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(problem) => {
+                self.add(problem);
+                None
+            }
+        }
+    }
+}
+
+/// What was asked for on the command line.
+struct Args {
+    config_file: Option<PathBuf>,
+    settings: Settings,
+    check_config: bool,
+    help: bool,
+}
+
+impl Args {
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Args> {
+        let mut parsed = Args {
+            config_file: None,
+            settings: Settings::default(),
+            check_config: false,
+            help: false,
+        };
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            // Both `--flag value` and `--flag=value` work.
+            let (flag, inline_value) = match arg.find('=') {
+                Some(index) => (arg[..index].to_owned(), Some(arg[index + 1..].to_owned())),
+                None => (arg.clone(), None),
+            };
+            let mut value = || {
+                inline_value.clone().or_else(|| args.next()).ok_or_else(|| {
+                    let kind = ErrorKind::ConfigError(format!("{} needs a value", flag));
+                    Error::from(kind)
+                })
+            };
+
+            let settings = &mut parsed.settings;
+            match flag.as_str() {
+                "--config" => parsed.config_file = Some(PathBuf::from(value()?)),
+                "--bind-address" => settings.bind_address = Some(value()?),
+                "--metrics-address" => settings.metrics_address = Some(value()?),
+                "--public-url" => settings.public_url = Some(value()?),
+                "--database-path" => settings.database_path = Some(PathBuf::from(value()?)),
+                "--template-dir" => settings.template_dir = Some(PathBuf::from(value()?)),
+                "--log-level" => settings.log_level = Some(value()?),
+                "--twitter-api-base-url" => settings.twitter_api_base_url = Some(value()?),
+                "--token-store" => settings.token_store = Some(value()?),
+                "--worker-count" => settings.worker_count = Some(parse_arg(&flag, value()?)?),
+                "--request-token-ttl-secs" => {
+                    settings.request_token_ttl_secs = Some(parse_arg(&flag, value()?)?)
+                }
+                "--session-ttl-secs" => {
+                    settings.session_ttl_secs = Some(parse_arg(&flag, value()?)?)
+                }
+                "--token-retention" => settings.token_retention = Some(value()?),
+                "--check-config" => parsed.check_config = true,
+                "--help" | "-h" => parsed.help = true,
+                other => {
+                    let kind = ErrorKind::ConfigError(format!("unknown argument {}", other));
+                    return Err(kind.into());
+                }
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// Everything that can be configured, checked and with defaults filled in.
+pub struct Config {
+    pub bind_address: SocketAddr,
+    /// Where `/metrics` is served, if anywhere. It's kept off `bind_address` as anyone could read
+    /// it there.
+    pub metrics_address: Option<SocketAddr>,
+    /// Where users reach the server, always ending in a `/`.
+    pub public_url: Url,
+    pub database_path: PathBuf,
+    pub template_dir: PathBuf,
+    pub log_level: LevelFilter,
+    pub api_endpoints: ApiEndpoints,
+    pub token_store: TokenStoreKind,
+    /// How many removal jobs to run at once.
+    pub worker_count: usize,
+    /// How long someone has to finish logging in on twitter before their request token expires.
+    pub request_token_ttl: Duration,
+    /// How long a session lasts after logging in.
+    pub session_ttl: Duration,
+    pub token_retention: TokenRetention,
+    pub consumer_token: KeyPair,
+    master_key: Option<String>,
+    previous_master_keys: Vec<String>,
+    session_key: Option<String>,
+    /// Only check the config, from `--check-config`.
+    pub check_config: bool,
+}
+
+impl Config {
+    /// Load the config from the file, environment and command line, or print what's wrong with it
+    /// and exit.
+    pub fn load_or_exit() -> Config {
+        match Config::load(env::args().skip(1)) {
+            Ok(Some(config)) => config,
+            Ok(None) => {
+                print!("{}", USAGE);
+                std::process::exit(0)
+            }
+            Err(e) => exit_invalid(&e),
+        }
+    }
+
+    /// `None` if only `--help` was asked for.
+    fn load(args: impl IntoIterator<Item = String>) -> Result<Option<Config>> {
+        let args = Args::parse(args)?;
+        if args.help {
+            return Ok(None);
+        }
+
+        let config_file = args
+            .config_file
+            .or_else(|| env::var("CONFIG_FILE").ok().map(PathBuf::from));
+        let file_settings = match config_file {
+            Some(path) => Settings::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Settings::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Settings::default(),
+        };
+        let mut problems = Problems::default();
+        let settings = file_settings
+            .merge(Settings::from_env(&mut problems))
+            .merge(args.settings);
+
+        Config::from_settings(settings, problems, args.check_config).map(Some)
+    }
+
+    /// Check every setting, so that all of the problems can be fixed at once. `problems` are any
+    /// found while reading the settings.
+    fn from_settings(
+        settings: Settings,
+        mut problems: Problems,
+        check_config: bool,
+    ) -> Result<Config> {
+        let bind_address = problems.check(settings.bind_address.as_ref().map_or(
+            Ok(([127, 0, 0, 1], 3000).into()),
+            |address| {
+                address
+                    .parse::<SocketAddr>()
+                    .map_err(|_| format!("bind_address {} is not an ip address and port", address))
+            },
+        ));
+
+        let metrics_address = match settings.metrics_address {
+            Some(ref address) => match address.parse::<SocketAddr>() {
+                Ok(address) if Some(address) == bind_address => {
+                    problems.add(format!(
+                        "metrics_address {} must be different to bind_address",
+                        address
+                    ));
+                    None
+                }
+                Ok(address) => Some(address),
+                Err(_) => {
+                    problems.add(format!(
+                        "metrics_address {} is not an ip address and port",
+                        address
+                    ));
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let public_url = problems.check(
+            settings
+                .public_url
+                .as_ref()
+                .map_or("http://localhost:3000/", String::as_str)
+                .parse::<Url>()
+                .map_err(|e| format!("public_url is not a url: {}", e))
+                .and_then(|mut url| {
+                    if url.scheme() != "http" && url.scheme() != "https" {
+                        return Err(format!("public_url {} must be http or https", url));
+                    }
+                    // Otherwise joining paths onto it would replace the last segment.
+                    if !url.path().ends_with('/') {
+                        let path = format!("{}/", url.path());
+                        url.set_path(&path);
+                    }
+                    Ok(url)
+                }),
+        );
+
+        let database_path = settings
+            .database_path
+            .unwrap_or_else(|| PathBuf::from("de-list.sqlite3"));
+        if let Some(dir) = database_path.parent() {
+            if !dir.as_os_str().is_empty() && !dir.is_dir() {
+                problems.add(format!(
+                    "database_path {} is in a directory that doesn't exist",
+                    database_path.display()
+                ));
+            }
+        }
+
+        let template_dir = settings
+            .template_dir
+            .unwrap_or_else(|| PathBuf::from("templates"));
+        if !template_dir.is_dir() {
+            problems.add(format!(
+                "template_dir {} is not a directory",
+                template_dir.display()
+            ));
+        } else if let Err(problem) = load_templates(&template_dir) {
+            problems.add(problem);
+        }
+
+        let log_level = problems.check(settings.log_level.as_ref().map_or(
+            Ok(LevelFilter::Info),
+            |level| {
+                level.parse().map_err(|_| {
+                    format!(
+                        "log_level must be off, error, warn, info, debug or trace, got {}",
+                        level
+                    )
+                })
+            },
+        ));
+
+        let api_endpoints = problems.check(
+            settings
+                .twitter_api_base_url
+                .as_ref()
+                .map_or(Ok(ApiEndpoints::default()), |base_url| {
+                    ApiEndpoints::new(base_url).map_err(|e| e.kind().to_string())
+                }),
+        );
+
+        let token_store = problems.check(
+            settings
+                .token_store
+                .as_ref()
+                .map_or(Ok(TokenStoreKind::Sqlite), |store| {
+                    store.parse().map_err(|e| format!("token_store: {}", e))
+                }),
+        );
+
+        let worker_count = settings.worker_count.unwrap_or(2);
+        if worker_count == 0 {
+            problems.add("worker_count must be at least 1".to_owned());
+        }
+
+        let request_token_ttl =
+            Duration::from_secs(settings.request_token_ttl_secs.unwrap_or(15 * 60));
+
+        let session_ttl = Duration::from_secs(settings.session_ttl_secs.unwrap_or(24 * 60 * 60));
+
+        let token_retention = problems.check(settings.token_retention.as_ref().map_or(
+            Ok(TokenRetention::AfterJob),
+            |retention| {
+                retention
+                    .parse()
+                    .map_err(|e| format!("token_retention: {}", e))
+            },
+        ));
+
+        let consumer_key = problems.check(
+            settings
+                .consumer_key
+                .ok_or_else(|| "consumer_key (CONSUMER_KEY) is required".to_owned()),
+        );
+        let consumer_secret = problems.check(
+            settings
+                .consumer_secret
+                .ok_or_else(|| "consumer_secret (CONSUMER_SECRET) is required".to_owned()),
+        );
+
+        let master_key = settings.master_key;
+        let previous_master_keys = settings.previous_master_keys.unwrap_or_default();
+        match master_key {
+            Some(ref master_key) => {
+                let previous = previous_master_keys.iter().map(String::as_str);
+                if let Err(e) = Keyring::new(master_key, previous) {
+                    problems.add(format!("master_key or previous_master_keys: {}", e.kind()));
+                }
+            }
+            None if token_store != Some(TokenStoreKind::Memory) => {
+                problems.add("master_key (MASTER_KEY) is required to store tokens".to_owned());
+            }
+            None => {}
+        }
+
+        let session_key = settings.session_key;
+        if let Some(ref session_key) = session_key {
+            if let Err(e) = SigningKey::from_base64(session_key) {
+                problems.add(format!("session_key: {}", e.kind()));
+            }
+        }
+
+        if let (
+            Some(bind_address),
+            Some(public_url),
+            Some(log_level),
+            Some(api_endpoints),
+            Some(token_store),
+            Some(token_retention),
+            Some(consumer_key),
+            Some(consumer_secret),
+        ) = (
+            bind_address,
+            public_url,
+            log_level,
+            api_endpoints,
+            token_store,
+            token_retention,
+            consumer_key,
+            consumer_secret,
+        ) {
+            if problems.0.is_empty() {
+                return Ok(Config {
+                    bind_address,
+                    metrics_address,
+                    public_url,
+                    database_path,
+                    template_dir,
+                    log_level,
+                    api_endpoints,
+                    token_store,
+                    worker_count,
+                    request_token_ttl,
+                    session_ttl,
+                    token_retention,
+                    consumer_token: KeyPair::new(consumer_key, consumer_secret),
+                    master_key,
+                    previous_master_keys,
+                    session_key,
+                    check_config,
+                });
+            }
+        }
+        Err(ErrorKind::ConfigError(problems.0.join("\n")).into())
+    }
+
+    /// Where twitter sends users back to once they've logged in.
+    pub fn callback_url(&self) -> String {
+        self.url("sign-in-with-twitter")
+    }
+
+    /// The url of one of our pages, given its path relative to `public_url`.
+    pub fn url(&self, path: &str) -> String {
+        self.public_url
+            .join(path)
+            .map(Url::into_string)
+            .unwrap_or_else(|_| format!("{}{}", self.public_url, path))
+    }
+
+    pub fn keyring(&self) -> Result<Keyring> {
+        let master_key = self.master_key.as_ref().ok_or_else(|| {
+            let kind = ErrorKind::CryptoError("No master key configured".to_owned());
+            Error::from(kind)
+        })?;
+        Keyring::new(
+            master_key,
+            self.previous_master_keys.iter().map(String::as_str),
+        )
+    }
+
+    /// Sessions only survive a restart if `session_key` is set, otherwise a new key is made up
+    /// each time.
+    pub fn session_signing_key(&self) -> Result<SigningKey> {
+        match self.session_key {
+            Some(ref session_key) => SigningKey::from_base64(session_key),
+            None => {
+                log::warn!("No session_key set, so everyone will be logged out on restart");
+                SigningKey::random()
+            }
+        }
+    }
+
+    /// Whether users reach us over https, so cookies should only be sent that way.
+    pub fn is_https(&self) -> bool {
+        self.public_url.scheme() == "https"
+    }
+
+    pub fn templates(&self) -> Result<Tera> {
+        load_templates(&self.template_dir).map_err(|problem| ErrorKind::ConfigError(problem).into())
+    }
+}
+
+/// For what's built from the config but can still turn out to be wrong once it's used, like a
+/// database that can't be opened or a master key that the stored secrets weren't sealed with.
+/// Prints what's wrong and exits, like an invalid config does.
+pub fn or_exit<T>(result: Result<T>) -> T {
+    result.unwrap_or_else(|e| exit_invalid(&e))
+}
+
+fn exit_invalid(e: &Error) -> ! {
+    match e.kind() {
+        ErrorKind::ConfigError(problems) => eprintln!("Invalid configuration:\n{}", problems),
+        other => eprintln!("Invalid configuration:\n{}", other),
+    }
+    for cause in e.iter_causes() {
+        eprintln!("caused by: {}", cause);
+    }
+    eprintln!("Run with --help to see the options.");
+    std::process::exit(2)
+}
+
+fn load_templates(template_dir: &Path) -> std::result::Result<Tera, String> {
+    let glob = format!("{}/**/*", template_dir.display());
+    let mut tera =
+        Tera::new(&glob).map_err(|e| format!("could not load templates from {}: {}", glob, e))?;
+    tera.autoescape_on(vec!["html"]);
+    Ok(tera)
+}
+
+/// Everything except the secrets, which just say whether they're set.
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "bind_address = {}", self.bind_address)?;
+        match self.metrics_address {
+            Some(address) => writeln!(f, "metrics_address = {}", address)?,
+            None => writeln!(f, "metrics_address = (off)")?,
+        }
+        writeln!(f, "public_url = {}", self.public_url)?;
+        writeln!(f, "callback_url = {}", self.callback_url())?;
+        writeln!(f, "database_path = {}", self.database_path.display())?;
+        writeln!(f, "template_dir = {}", self.template_dir.display())?;
+        writeln!(f, "log_level = {}", self.log_level)?;
+        writeln!(
+            f,
+            "twitter_api_base_url = {}",
+            self.api_endpoints.base_url()
+        )?;
+        writeln!(f, "token_store = {:?}", self.token_store)?;
+        writeln!(f, "worker_count = {}", self.worker_count)?;
+        writeln!(
+            f,
+            "request_token_ttl_secs = {}",
+            self.request_token_ttl.as_secs()
+        )?;
+        writeln!(f, "session_ttl_secs = {}", self.session_ttl.as_secs())?;
+        writeln!(f, "token_retention = {}", self.token_retention)?;
+        writeln!(f, "consumer_key = (set)")?;
+        writeln!(f, "consumer_secret = (set)")?;
+        writeln!(
+            f,
+            "master_key = {}",
+            if self.master_key.is_some() {
+                "(set)"
+            } else {
+                "(not set)"
+            }
+        )?;
+        writeln!(
+            f,
+            "previous_master_keys = ({} set)",
+            self.previous_master_keys.len()
+        )?;
+        writeln!(
+            f,
+            "session_key = {}",
+            if self.session_key.is_some() {
+                "(set)"
+            } else {
+                "(not set)"
+            }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn args(args: &[&str]) -> Result<Args> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn settings() -> Settings {
+        Settings {
+            token_store: Some("memory".to_owned()),
+            consumer_key: Some("key".to_owned()),
+            consumer_secret: Some("secret".to_owned()),
+            ..Settings::default()
+        }
+    }
+
+    fn problems(result: Result<Config>) -> String {
+        match result {
+            Ok(_) => panic!("expected the config to be invalid"),
+            Err(e) => match e.kind() {
+                ErrorKind::ConfigError(problems) => problems.clone(),
+                other => panic!("expected a config error, got {}", other),
+            },
+        }
+    }
+
+    #[test]
+    fn args_take_values_after_a_space_or_an_equals() {
+        let parsed = args(&[
+            "--bind-address",
+            "0.0.0.0:80",
+            "--worker-count=3",
+            "--public-url=https://example.com/?a=b",
+            "--check-config",
+        ])
+        .unwrap();
+        assert_eq!(parsed.settings.bind_address.as_ref().unwrap(), "0.0.0.0:80");
+        assert_eq!(parsed.settings.worker_count, Some(3));
+        assert_eq!(
+            parsed.settings.public_url.as_ref().unwrap(),
+            "https://example.com/?a=b"
+        );
+        assert!(parsed.check_config);
+        assert!(!parsed.help);
+    }
+
+    #[test]
+    fn args_that_are_wrong_are_errors() {
+        assert!(args(&["--public-url"]).is_err());
+        assert!(args(&["--worker-count", "lots"]).is_err());
+        assert!(args(&["--consumer-secret=secret"]).is_err());
+    }
+
+    #[test]
+    fn every_bad_number_in_the_environment_is_a_problem() {
+        let vars: HashMap<_, _> = vec![
+            ("WORKER_COUNT", "lots"),
+            ("SESSION_TTL_SECS", "1h"),
+            ("REQUEST_TOKEN_TTL_SECS", "60"),
+            ("BIND_ADDRESS", ""),
+        ]
+        .into_iter()
+        .collect();
+        let mut problems = Problems::default();
+        let settings =
+            Settings::from_vars(|name| vars.get(name).map(|v| v.to_string()), &mut problems);
+        assert_eq!(settings.request_token_ttl_secs, Some(60));
+        assert_eq!(settings.worker_count, None);
+        assert_eq!(settings.bind_address, None);
+        assert_eq!(
+            problems.0,
+            vec![
+                "WORKER_COUNT must be a number, got lots",
+                "SESSION_TTL_SECS must be a number, got 1h",
+            ]
+        );
+    }
+
+    #[test]
+    fn the_command_line_beats_the_environment_beats_the_file() {
+        let file = Settings {
+            bind_address: Some("file".to_owned()),
+            public_url: Some("file".to_owned()),
+            worker_count: Some(1),
+            ..Settings::default()
+        };
+        let env = Settings {
+            public_url: Some("env".to_owned()),
+            worker_count: Some(2),
+            ..Settings::default()
+        };
+        let command_line = Settings {
+            worker_count: Some(3),
+            ..Settings::default()
+        };
+        let merged = file.merge(env).merge(command_line);
+        assert_eq!(merged.bind_address.as_ref().unwrap(), "file");
+        assert_eq!(merged.public_url.as_ref().unwrap(), "env");
+        assert_eq!(merged.worker_count, Some(3));
+        assert_eq!(merged.log_level, None);
+    }
+
+    #[test]
+    fn missing_settings_get_defaults() {
+        let config = Config::from_settings(settings(), Problems::default(), false).unwrap();
+        assert_eq!(
+            config.bind_address,
+            SocketAddr::from(([127, 0, 0, 1], 3000))
+        );
+        assert_eq!(config.metrics_address, None);
+        assert_eq!(config.public_url.as_str(), "http://localhost:3000/");
+        assert_eq!(config.worker_count, 2);
+        assert_eq!(config.token_retention, TokenRetention::AfterJob);
+        assert_eq!(
+            config.callback_url(),
+            "http://localhost:3000/sign-in-with-twitter"
+        );
+    }
+
+    #[test]
+    fn public_urls_get_a_trailing_slash() {
+        let settings = Settings {
+            public_url: Some("https://example.com/de-list".to_owned()),
+            ..settings()
+        };
+        let config = Config::from_settings(settings, Problems::default(), false).unwrap();
+        assert_eq!(config.url("lists"), "https://example.com/de-list/lists");
+        assert!(config.is_https());
+    }
+
+    #[test]
+    fn metrics_need_their_own_address() {
+        let settings = Settings {
+            bind_address: Some("0.0.0.0:3000".to_owned()),
+            metrics_address: Some("0.0.0.0:3000".to_owned()),
+            ..settings()
+        };
+        let problems = problems(Config::from_settings(settings, Problems::default(), false));
+        assert_eq!(
+            problems,
+            "metrics_address 0.0.0.0:3000 must be different to bind_address"
+        );
+
+        let settings = Settings {
+            metrics_address: Some("127.0.0.1:9000".to_owned()),
+            ..settings()
+        };
+        let config = Config::from_settings(settings, Problems::default(), false).unwrap();
+        assert_eq!(
+            config.metrics_address,
+            Some(SocketAddr::from(([127, 0, 0, 1], 9000)))
+        );
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let settings = Settings {
+            bind_address: Some("localhost".to_owned()),
+            metrics_address: Some("localhost:9000".to_owned()),
+            public_url: Some("ftp://example.com/".to_owned()),
+            token_store: None,
+            worker_count: Some(0),
+            token_retention: Some("forever".to_owned()),
+            consumer_key: None,
+            ..settings()
+        };
+        let mut earlier = Problems::default();
+        earlier.add("WORKER_COUNT must be a number, got lots".to_owned());
+        let problems = problems(Config::from_settings(settings, earlier, false));
+        for problem in &[
+            "WORKER_COUNT must be a number",
+            "bind_address localhost is not an ip address and port",
+            "metrics_address localhost:9000 is not an ip address and port",
+            "public_url ftp://example.com/ must be http or https",
+            "worker_count must be at least 1",
+            "token_retention: expected after_job",
+            "consumer_key (CONSUMER_KEY) is required",
+            "master_key (MASTER_KEY) is required",
+        ] {
+            assert!(
+                problems.contains(problem),
+                "{} not in {}",
+                problem,
+                problems
+            );
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use crate::error::*;
+use failchain::ResultExt;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use sha1::{Digest, Sha1};
+use std::fmt::Write;
+
+const SEALED_PREFIX: &'static str = "v1";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// A 256 bit AES-GCM key, identified by a short fingerprint so that we know which key sealed a
+/// value without having to try them all.
+struct MasterKey {
+    id: String,
+    key: Vec<u8>,
+}
+
+impl MasterKey {
+    fn from_base64(encoded: &str) -> Result<MasterKey> {
+        let key = base64::decode(encoded.trim())
+            .chain_err(|| ErrorKind::CryptoError("master key is not valid base64".to_owned()))?;
+        if key.len() != KEY_LEN {
+            let kind = ErrorKind::CryptoError(format!(
+                "master key must be {} bytes, got {}",
+                KEY_LEN,
+                key.len()
+            ));
+            return Err(kind.into());
+        }
+
+        let mut id = String::new();
+        for byte in Sha1::digest(&key).iter().take(4) {
+            write!(id, "{:02x}", byte).unwrap();
+        }
+        Ok(MasterKey { id, key })
+    }
+}
+
+/// The current master key that everything is sealed with, plus any previous keys that values
+/// might still be sealed with until they're re-encrypted.
+pub struct Keyring {
+    current: MasterKey,
+    previous: Vec<MasterKey>,
+}
+
+impl Keyring {
+    /// Each key is 32 base64 encoded bytes e.g. from `openssl rand -base64 32`. Values sealed with
+    /// any of the `previous` keys can still be opened, but only `current` is used to seal.
+    pub fn new<'a>(current: &str, previous: impl IntoIterator<Item = &'a str>) -> Result<Keyring> {
+        Ok(Keyring {
+            current: MasterKey::from_base64(current)?,
+            previous: previous
+                .into_iter()
+                .map(MasterKey::from_base64)
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Encrypt and authenticate `plaintext`. The `associated_data` isn't stored but must be given
+    /// again to `open`, which stops a sealed value from being copied somewhere it doesn't belong.
+    pub fn seal(&self, plaintext: &str, associated_data: &str) -> Result<String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        openssl::rand::rand_bytes(&mut nonce)
+            .chain_err(|| ErrorKind::CryptoError("generating nonce".to_owned()))?;
+
+        let mut tag = [0u8; TAG_LEN];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.current.key,
+            Some(&nonce),
+            associated_data.as_bytes(),
+            plaintext.as_bytes(),
+            &mut tag,
+        )
+        .chain_err(|| ErrorKind::CryptoError("encrypting".to_owned()))?;
+
+        let mut payload = Vec::with_capacity(NONCE_LEN + TAG_LEN + ciphertext.len());
+        payload.extend_from_slice(&nonce);
+        payload.extend_from_slice(&tag);
+        payload.extend_from_slice(&ciphertext);
+
+        Ok(format!(
+            "{}:{}:{}",
+            SEALED_PREFIX,
+            self.current.id,
+            base64::encode(&payload)
+        ))
+    }
+
+    /// Decrypt a value from `seal`, with whichever key it was sealed with.
+    pub fn open(&self, sealed: &str, associated_data: &str) -> Result<String> {
+        let (key_id, payload) = split_sealed(sealed).ok_or_else(|| -> Error {
+            ErrorKind::CryptoError("value is not sealed".to_owned()).into()
+        })?;
+        let key = self.key(key_id).ok_or_else(|| -> Error {
+            ErrorKind::CryptoError(format!("no master key with id {}", key_id)).into()
+        })?;
+
+        let payload = base64::decode(payload)
+            .chain_err(|| ErrorKind::CryptoError("sealed value is not valid base64".to_owned()))?;
+        if payload.len() < NONCE_LEN + TAG_LEN {
+            return Err(ErrorKind::CryptoError("sealed value is truncated".to_owned()).into());
+        }
+        let (nonce, rest) = payload.split_at(NONCE_LEN);
+        let (tag, ciphertext) = rest.split_at(TAG_LEN);
+
+        let plaintext = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key.key,
+            Some(nonce),
+            associated_data.as_bytes(),
+            ciphertext,
+            tag,
+        )
+        .chain_err(|| ErrorKind::CryptoError("decrypting".to_owned()))?;
+        String::from_utf8(plaintext)
+            .chain_err(|| ErrorKind::CryptoError("decrypted value is not utf8".to_owned()))
+    }
+
+    /// Whether the value should be sealed again with the current key, either because it was
+    /// sealed with a previous key or because it was never sealed at all.
+    pub fn needs_resealing(&self, value: &str) -> bool {
+        match split_sealed(value) {
+            Some((key_id, _)) => key_id != self.current.id,
+            None => true,
+        }
+    }
+
+    /// Seal the value with the current key, opening it first if it was sealed with an old key.
+    /// Values that were never sealed are assumed to be plaintext from before encryption was added.
+    pub fn reseal(&self, value: &str, associated_data: &str) -> Result<String> {
+        let plaintext = if split_sealed(value).is_some() {
+            self.open(value, associated_data)?
+        } else {
+            value.to_owned()
+        };
+        self.seal(&plaintext, associated_data)
+    }
+
+    fn key(&self, key_id: &str) -> Option<&MasterKey> {
+        std::iter::once(&self.current)
+            .chain(self.previous.iter())
+            .find(|key| key.id == key_id)
+    }
+}
+
+fn split_sealed(value: &str) -> Option<(&str, &str)> {
+    let mut parts = value.splitn(3, ':');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(SEALED_PREFIX), Some(key_id), Some(payload)) => Some((key_id, payload)),
+        _ => None,
+    }
+}
+
+/// An HMAC-SHA256 key, for handing out values (like session cookies) that we can later check
+/// haven't been tampered with. Unlike the keyring nothing is encrypted, so the values themselves
+/// mustn't be secret from the person holding them.
+pub struct SigningKey {
+    key: PKey<Private>,
+}
+
+impl SigningKey {
+    /// The key is 32 base64 encoded bytes, like a master key.
+    pub fn from_base64(encoded: &str) -> Result<SigningKey> {
+        let key = base64::decode(encoded.trim())
+            .chain_err(|| ErrorKind::CryptoError("signing key is not valid base64".to_owned()))?;
+        if key.len() != KEY_LEN {
+            let kind = ErrorKind::CryptoError(format!(
+                "signing key must be {} bytes, got {}",
+                KEY_LEN,
+                key.len()
+            ));
+            return Err(kind.into());
+        }
+        SigningKey::new(&key)
+    }
+
+    /// A new key that nothing's been signed with yet.
+    pub fn random() -> Result<SigningKey> {
+        let mut key = [0u8; KEY_LEN];
+        openssl::rand::rand_bytes(&mut key)
+            .chain_err(|| ErrorKind::CryptoError("generating signing key".to_owned()))?;
+        SigningKey::new(&key)
+    }
+
+    fn new(key: &[u8]) -> Result<SigningKey> {
+        let key = PKey::hmac(key)
+            .chain_err(|| ErrorKind::CryptoError("loading signing key".to_owned()))?;
+        Ok(SigningKey { key })
+    }
+
+    /// `value` followed by a `.` and its signature. The value mustn't contain a `.` itself.
+    pub fn sign(&self, value: &str) -> Result<String> {
+        Ok(format!("{}.{}", value, self.signature(value)?))
+    }
+
+    /// The value from `sign`, if its signature is right.
+    pub fn verify<'a>(&self, signed: &'a str) -> Option<&'a str> {
+        let index = signed.rfind('.')?;
+        let (value, signature) = (&signed[..index], &signed[index + 1..]);
+        if self.check_signature(value, signature) {
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    /// Just the signature of `value`, for when whoever checks it already knows the value.
+    pub fn signature(&self, value: &str) -> Result<String> {
+        let mac = self.mac(value)?;
+        Ok(base64::encode_config(&mac, base64::URL_SAFE_NO_PAD))
+    }
+
+    /// Whether `signature` is the signature of `value`.
+    pub fn check_signature(&self, value: &str, signature: &str) -> bool {
+        let signature = match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        let mac = match self.mac(value) {
+            Ok(mac) => mac,
+            Err(_) => return false,
+        };
+        // `eq` panics if the lengths differ, and takes the same time whatever the contents.
+        signature.len() == mac.len() && openssl::memcmp::eq(&signature, &mac)
+    }
+
+    fn mac(&self, value: &str) -> Result<Vec<u8>> {
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key)
+            .chain_err(|| ErrorKind::CryptoError("creating signer".to_owned()))?;
+        signer
+            .update(value.as_bytes())
+            .chain_err(|| ErrorKind::CryptoError("signing".to_owned()))?;
+        signer
+            .sign_to_vec()
+            .chain_err(|| ErrorKind::CryptoError("signing".to_owned()))
+    }
+}
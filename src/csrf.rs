@@ -0,0 +1,66 @@
+use crate::error::*;
+use crate::sessions::SessionStore;
+use failchain::ResultExt;
+use futures::future::FutureObj;
+use http::Method;
+use hyper::Response;
+use tide::middleware::{Middleware, Next};
+use url::form_urlencoded;
+
+/// The form field that the token has to be submitted in.
+pub const FIELD_NAME: &'static str = "csrf_token";
+
+/// The body of a request that's passed the check. The middleware has to read the body to find the
+/// token, so handlers read it from the request's extensions instead.
+pub struct FormBody(pub String);
+
+/// Rejects every request that could change something unless it's from a form that we showed to the
+/// same session, so that other sites can't get logged in users' browsers to submit forms for them.
+pub struct CsrfMiddleware {
+    sessions: &'static SessionStore,
+}
+
+impl CsrfMiddleware {
+    pub fn new(sessions: &'static SessionStore) -> Self {
+        CsrfMiddleware { sessions }
+    }
+
+    fn check(&self, context: &tide::Context<()>, body: &str) -> Result<()> {
+        let session = self
+            .sessions
+            .from_headers(context.request().headers())?
+            .ok_or_else(|| -> Error { ErrorKind::NotLoggedIn.into() })?;
+        let token = form_urlencoded::parse(body.as_bytes())
+            .find(|(key, _)| *key == FIELD_NAME)
+            .map(|(_, value)| value);
+        match token {
+            Some(ref token) if self.sessions.check_csrf_token(&session, token) => Ok(()),
+            _ => Err(ErrorKind::InvalidCsrfToken.into()),
+        }
+    }
+}
+
+impl Middleware<()> for CsrfMiddleware {
+    fn handle<'a>(
+        &'a self,
+        mut context: tide::Context<()>,
+        next: Next<'a, ()>,
+    ) -> FutureObj<'a, Response<http_service::Body>> {
+        FutureObj::new(Box::new(async move {
+            match *context.request().method() {
+                Method::GET | Method::HEAD | Method::OPTIONS => return await!(next.run(context)),
+                _ => (),
+            }
+
+            let body = await!(context.body_string())
+                .chain_err(|| ErrorKind::OtherError("reading form body".to_owned()));
+            match body.and_then(|body| self.check(&context, &body).map(|()| body)) {
+                Ok(body) => {
+                    context.extensions_mut().insert(FormBody(body));
+                    await!(next.run(context))
+                }
+                Err(e) => crate::error_response(&e),
+            }
+        }))
+    }
+}
@@ -0,0 +1,154 @@
+use crate::error::*;
+use failchain::ResultExt;
+use rusqlite::{Connection, NO_PARAMS};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A single sqlite connection shared by everything that needs to persist state.
+pub type Database = Arc<Mutex<Connection>>;
+
+/// Each entry is run once, in order, and `PRAGMA user_version` records how many have been run.
+/// Never edit an entry once it's been released, add a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: token store
+    "CREATE TABLE request_tokens (
+        oauth_token TEXT PRIMARY KEY NOT NULL,
+        oauth_token_secret TEXT NOT NULL
+    );
+    CREATE TABLE access_tokens (
+        user_id INTEGER PRIMARY KEY NOT NULL,
+        oauth_token TEXT NOT NULL,
+        oauth_token_secret TEXT NOT NULL
+    );",
+    // 2: request token expiry, anything from before this will be swept straight away
+    "ALTER TABLE request_tokens ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;",
+    // 3: background removal jobs
+    "CREATE TABLE jobs (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id INTEGER NOT NULL,
+        state TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        finished_at INTEGER
+    );
+    CREATE INDEX jobs_state ON jobs (state);
+    CREATE TABLE job_owners (
+        job_id INTEGER NOT NULL REFERENCES jobs (id),
+        owner_id INTEGER NOT NULL,
+        state TEXT NOT NULL,
+        error TEXT,
+        PRIMARY KEY (job_id, owner_id)
+    );",
+    // 4: following owners again after they've been unblocked
+    "ALTER TABLE job_owners ADD COLUMN refollow TEXT;
+    ALTER TABLE job_owners ADD COLUMN refollow_error TEXT;",
+    // 5: sessions of users who've logged in
+    "CREATE TABLE sessions (
+        id TEXT PRIMARY KEY NOT NULL,
+        user_id INTEGER NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE INDEX sessions_created_at ON sessions (created_at);",
+    // 6: access token retention, anything from before this is treated as long unused
+    "ALTER TABLE access_tokens ADD COLUMN saved_at INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE access_tokens ADD COLUMN keep_until_revoked INTEGER NOT NULL DEFAULT 0;",
+    // 7: recurring removals
+    "CREATE TABLE schedules (
+        user_id INTEGER PRIMARY KEY NOT NULL,
+        frequency TEXT NOT NULL,
+        weekday INTEGER NOT NULL,
+        hour INTEGER NOT NULL,
+        refollow INTEGER NOT NULL,
+        paused INTEGER NOT NULL,
+        next_run_at INTEGER NOT NULL,
+        last_job_id INTEGER,
+        last_error TEXT
+    );
+    CREATE INDEX schedules_next_run_at ON schedules (next_run_at);",
+    // 8: membership snapshots
+    "CREATE TABLE membership_snapshots (
+        id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+        user_id INTEGER NOT NULL,
+        taken_at INTEGER NOT NULL
+    );
+    CREATE INDEX membership_snapshots_user_id ON membership_snapshots (user_id);
+    CREATE TABLE snapshot_lists (
+        snapshot_id INTEGER NOT NULL REFERENCES membership_snapshots (id),
+        list_id INTEGER NOT NULL,
+        owner_id INTEGER NOT NULL,
+        list_name TEXT NOT NULL,
+        PRIMARY KEY (snapshot_id, list_id)
+    );",
+    // 9: pending removals
+    "CREATE TABLE pending_removals (
+        id TEXT PRIMARY KEY NOT NULL,
+        user_id INTEGER NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE INDEX pending_removals_user_id ON pending_removals (user_id);
+    CREATE TABLE pending_removal_owners (
+        removal_id TEXT NOT NULL REFERENCES pending_removals (id),
+        owner_id INTEGER NOT NULL,
+        following INTEGER NOT NULL,
+        PRIMARY KEY (removal_id, owner_id)
+    );",
+    // 10: owners whose lists recurring removals leave the user on
+    "CREATE TABLE schedule_kept_owners (
+        user_id INTEGER NOT NULL REFERENCES schedules (user_id),
+        owner_id INTEGER NOT NULL,
+        PRIMARY KEY (user_id, owner_id)
+    );",
+    // 11: giving up on owners that can't be reconciled
+    "ALTER TABLE job_owners ADD COLUMN reconcile_attempts INTEGER NOT NULL DEFAULT 0;",
+];
+
+/// Open (or create) the database at the given path and bring its schema up to date.
+pub fn open(path: impl AsRef<Path>) -> Result<Database> {
+    let path = path.as_ref();
+    let mut conn = Connection::open(path).chain_err(|| {
+        ErrorKind::DatabaseError(format!("opening database at {}", path.display()))
+    })?;
+    migrate(&mut conn)?;
+    Ok(Arc::new(Mutex::new(conn)))
+}
+
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: i64 = conn
+        .query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))
+        .chain_err(|| ErrorKind::DatabaseError("reading schema version".to_owned()))?;
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let new_version = index + 1;
+        log::info!("Migrating database to version {}", new_version);
+
+        let tx = conn
+            .transaction()
+            .chain_err(|| ErrorKind::DatabaseError("starting migration".to_owned()))?;
+        tx.execute_batch(migration)
+            .and_then(|()| tx.execute_batch(&format!("PRAGMA user_version = {}", new_version)))
+            .chain_err(|| ErrorKind::DatabaseError(format!("migrating to {}", new_version)))?;
+        tx.commit()
+            .chain_err(|| ErrorKind::DatabaseError(format!("committing {}", new_version)))?;
+    }
+    Ok(())
+}
+
+/// Lock the connection, turning a poisoned lock into an error rather than a panic.
+pub fn lock(db: &Database) -> Result<MutexGuard<Connection>> {
+    db.lock().map_err(|_| {
+        let kind = ErrorKind::DatabaseError("Could not get lock for database".to_owned());
+        kind.into()
+    })
+}
+
+/// Times are stored as whole seconds since the unix epoch.
+pub fn to_timestamp(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(dur) => dur.as_secs() as i64,
+        Err(_) => 0,
+    }
+}
+
+pub fn from_timestamp(timestamp: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(timestamp.max(0) as u64)
+}
@@ -6,6 +6,7 @@ use std::result::Result as StdResult;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::error::*;
+use crate::scheduler::ScheduledClient;
 use egg_mode::KeyPair;
 use failchain::ResultExt;
 use futures::compat::Future01CompatExt;
@@ -13,191 +14,771 @@ use futures::Future;
 use futures01::stream::Stream;
 use futures01::Future as Future01;
 use hmac::{Hmac, Mac};
-use hyper::body::Body;
-use hyper::client::{Client, HttpConnector};
-use hyper::header::{HeaderValue, AUTHORIZATION};
+use hyper::body::{Body, Chunk};
+use hyper::header::{HeaderMap, HeaderValue, AUTHORIZATION};
 use hyper::{Method, Request, Uri};
-use hyper_tls::HttpsConnector;
 use rand::distributions::{Alphanumeric, Distribution};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use sha1::Sha1;
-use std::collections::BTreeSet;
-use std::iter::FromIterator;
+use std::collections::{BTreeMap, BTreeSet};
 use url::form_urlencoded;
-use url::percent_encoding::{utf8_percent_encode, EncodeSet};
+use url::percent_encoding::{percent_decode, utf8_percent_encode, EncodeSet};
 
-#[derive(Deserialize)]
-struct TwitterUserId {
-    id: u64,
+/// The owner of a list, as much of them as we show.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TwitterUser {
+    pub id: u64,
+    pub screen_name: String,
+    pub name: String,
+    pub profile_image_url_https: String,
 }
-#[derive(Deserialize)]
-struct TwitterList {
-    user: TwitterUserId,
+
+/// A list that the user is a member of.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TwitterList {
+    pub id: u64,
+    pub name: String,
+    pub slug: String,
+    #[serde(default)]
+    pub description: String,
+    pub member_count: u64,
+    pub subscriber_count: u64,
+    pub user: TwitterUser,
 }
+
 #[derive(Deserialize)]
 struct ListMembership {
     lists: Vec<TwitterList>,
+    next_cursor: i64,
+}
+
+#[derive(Deserialize)]
+struct BlockedIds {
+    ids: Vec<u64>,
+    next_cursor: i64,
+}
+
+/// How the user and someone else follow each other, from `friendships/lookup`.
+#[derive(Clone, Copy, Eq, PartialEq, Default, Debug)]
+pub struct Relationship {
+    /// The user follows them.
+    pub following: bool,
+    /// They follow the user.
+    pub followed_by: bool,
+}
+
+#[derive(Deserialize)]
+struct Friendship {
+    id: u64,
+    connections: Vec<String>,
+}
+
+/// What twitter did when asked to follow someone.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Follow {
+    Following,
+    /// They're protected, so they have to approve a follow request first.
+    Requested,
+}
+
+#[derive(Deserialize)]
+struct FollowedUser {
+    #[serde(default)]
+    protected: bool,
+    #[serde(default)]
+    follow_request_sent: bool,
+}
+
+/// The `x-rate-limit-*` headers from a response.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct RateLimit {
+    pub limit: u32,
+    pub remaining: u32,
+    /// Unix time in seconds when `remaining` goes back up to `limit`.
+    pub reset: u64,
+}
+
+impl RateLimit {
+    pub fn from_headers(headers: &HeaderMap) -> Option<RateLimit> {
+        fn header<T: std::str::FromStr>(headers: &HeaderMap, name: &str) -> Option<T> {
+            headers.get(name)?.to_str().ok()?.parse().ok()
+        }
+
+        Some(RateLimit {
+            limit: header(headers, "x-rate-limit-limit")?,
+            remaining: header(headers, "x-rate-limit-remaining")?,
+            reset: header(headers, "x-rate-limit-reset")?,
+        })
+    }
+}
+
+pub const DEFAULT_API_BASE_URL: &'static str = "https://api.twitter.com";
+
+/// Every twitter endpoint that we use. Rate limits are kept per endpoint, so this is also what
+/// the scheduler's windows are keyed on.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub enum Endpoint {
+    RequestToken,
+    Authenticate,
+    AccessToken,
+    InvalidateToken,
+    Memberships,
+    BlocksCreate,
+    BlocksDestroy,
+    BlocksIds,
+    FriendshipsLookup,
+    FriendshipsCreate,
+}
+
+impl Endpoint {
+    pub fn path(self) -> &'static str {
+        match self {
+            Endpoint::RequestToken => "/oauth/request_token",
+            Endpoint::Authenticate => "/oauth/authenticate",
+            Endpoint::AccessToken => "/oauth/access_token",
+            Endpoint::InvalidateToken => "/1.1/oauth/invalidate_token",
+            Endpoint::Memberships => "/1.1/lists/memberships.json",
+            Endpoint::BlocksCreate => "/1.1/blocks/create.json",
+            Endpoint::BlocksDestroy => "/1.1/blocks/destroy.json",
+            Endpoint::BlocksIds => "/1.1/blocks/ids.json",
+            Endpoint::FriendshipsLookup => "/1.1/friendships/lookup.json",
+            Endpoint::FriendshipsCreate => "/1.1/friendships/create.json",
+        }
+    }
+}
+
+/// Where to find the twitter API, so that we can talk to something other than api.twitter.com.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct ApiEndpoints {
+    base_url: String,
+}
+
+impl ApiEndpoints {
+    /// `base_url` is everything before the endpoint paths, e.g. `https://api.twitter.com`.
+    pub fn new(base_url: &str) -> Result<Self> {
+        let base_url = base_url.trim_end_matches('/');
+        let uri = base_url.parse::<Uri>().chain_err(|| {
+            ErrorKind::OtherError(format!("Invalid twitter API base url {}", base_url))
+        })?;
+        if uri.scheme_part().is_none() || uri.authority_part().is_none() {
+            let kind = ErrorKind::OtherError(format!(
+                "Twitter API base url {} needs a scheme and a host",
+                base_url
+            ));
+            return Err(kind.into());
+        }
+        Ok(ApiEndpoints {
+            base_url: base_url.to_owned(),
+        })
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// The full url of the endpoint, which is also what goes in the signature base string.
+    pub fn url(&self, endpoint: Endpoint) -> String {
+        format!("{}{}", self.base_url, endpoint.path())
+    }
 }
 
-pub const REQUEST_TOKEN: &'static str = "https://api.twitter.com/oauth/request_token";
-pub const AUTHENTICATE: &'static str = "https://api.twitter.com/oauth/authenticate";
-pub const ACCESS_TOKEN: &'static str = "https://api.twitter.com/oauth/access_token";
-pub const MEMBERSHIPS: &'static str = "https://api.twitter.com/1.1/lists/memberships.json";
+impl Default for ApiEndpoints {
+    fn default() -> Self {
+        ApiEndpoints {
+            base_url: DEFAULT_API_BASE_URL.to_owned(),
+        }
+    }
+}
 
 // NOTE THAT egg_mode hasn't been updated for hyper 0.12 yet
 // and that's the only reason that this module exists.
 
-pub fn get_owners_compat(
+/// How many lists to ask for per page of memberships, this is the maximum twitter allows.
+const MEMBERSHIPS_PAGE_SIZE: u32 = 1000;
+
+pub fn get_memberships_compat(
+    user_id: u64,
+    consumer_token: &KeyPair,
+    access_token: &KeyPair,
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
+) -> impl Future<Output = Result<Vec<TwitterList>>> {
+    get_memberships(user_id, consumer_token, access_token, endpoints, client).compat()
+}
+
+/// Every list that the user is a member of, across all pages of memberships.
+pub fn get_memberships(
     user_id: u64,
     consumer_token: &KeyPair,
     access_token: &KeyPair,
-    client: &Client<HttpsConnector<HttpConnector>, Body>,
-) -> impl Future<Output = Result<Vec<u64>>> {
-    get_owners_of_first_75_lists(user_id, consumer_token, access_token, client).compat()
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
+) -> impl Future01<Item = Vec<TwitterList>, Error = Error> {
+    membership_pages(user_id, consumer_token, access_token, endpoints, client)
+        .fold(BTreeMap::new(), |mut lists, page| {
+            // Lists can move between pages if they change while we're paging through them.
+            lists.extend(page.into_iter().map(|list| (list.id, list)));
+            Ok::<_, Error>(lists)
+        })
+        .map(|lists| lists.into_iter().map(|(_, list)| list).collect())
+}
+
+/// Everyone who owns one of the lists, each only once.
+pub fn list_owners(lists: &[TwitterList]) -> BTreeSet<u64> {
+    lists.iter().map(|list| list.user.id).collect()
 }
-pub fn get_owners_of_first_75_lists(
+
+/// A stream of each page of memberships, following `next_cursor` until twitter says there are no
+/// pages left.
+pub fn membership_pages(
     user_id: u64,
     consumer_token: &KeyPair,
     access_token: &KeyPair,
-    client: &Client<HttpsConnector<HttpConnector>, Body>,
-) -> impl Future01<Item = Vec<u64>, Error = Error> {
-    let uri_with_query = format!("{}?cursor=-1&user_id={}&count=200", MEMBERSHIPS, user_id);
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
+) -> impl Stream<Item = Vec<TwitterList>, Error = Error> {
+    let consumer_token = consumer_token.clone();
+    let access_token = access_token.clone();
+    let endpoints = endpoints.clone();
+    let client = client.clone();
+
+    futures01::stream::unfold(Some(-1), move |cursor| {
+        cursor.map(|cursor| {
+            get_memberships_page(
+                user_id,
+                cursor,
+                &consumer_token,
+                &access_token,
+                &endpoints,
+                &client,
+            )
+            .map(|(lists, next_cursor)| {
+                let next_cursor = if next_cursor == 0 {
+                    None
+                } else {
+                    Some(next_cursor)
+                };
+                (lists, next_cursor)
+            })
+        })
+    })
+}
 
+fn get_memberships_page(
+    user_id: u64,
+    cursor: i64,
+    consumer_token: &KeyPair,
+    access_token: &KeyPair,
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
+) -> impl Future01<Item = (Vec<TwitterList>, i64), Error = Error> {
     let mut params = HashMap::new();
-    add_param(&mut params, "cursor", "-1");
+    add_param(&mut params, "cursor", cursor.to_string());
     add_param(&mut params, "user_id", user_id.to_string());
-    add_param(&mut params, "count", "200");
+    add_param(&mut params, "count", MEMBERSHIPS_PAGE_SIZE.to_string());
 
-    let header = get_header(
+    let make_request = signer(
         Method::GET,
-        MEMBERSHIPS,
+        endpoints.url(Endpoint::Memberships),
         consumer_token,
         Some(access_token),
-        None,
-        None,
-        Some(&params),
+        params,
     );
-    let header_value = header.header_value().unwrap();
-    let request = Request::connect::<Uri>(uri_with_query.parse().unwrap())
-        .header(AUTHORIZATION, HeaderValue::from_str(&header_value).unwrap())
-        .method(Method::GET)
-        .body(Body::empty())
-        .unwrap();
 
+    log::debug!("requesting memberships page {}", cursor);
+    send(client, Endpoint::Memberships, access_token, make_request).and_then(parse_lists)
+}
+
+fn parse_lists(body: Chunk) -> Result<(Vec<TwitterList>, i64)> {
+    let body_json: ListMembership = serde_json::from_slice(&body)
+        .chain_err(|| ErrorKind::JsonParseError("Parsing list memberships".to_owned()))?;
+    Ok((body_json.lists, body_json.next_cursor))
+}
+
+pub fn get_blocked_ids_compat(
+    consumer_token: &KeyPair,
+    access_token: &KeyPair,
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
+) -> impl Future<Output = Result<BTreeSet<u64>>> {
+    get_blocked_ids(consumer_token, access_token, endpoints, client).compat()
+}
+
+/// Everyone that the user has blocked, across all pages of up to 5000 ids each.
+pub fn get_blocked_ids(
+    consumer_token: &KeyPair,
+    access_token: &KeyPair,
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
+) -> impl Future01<Item = BTreeSet<u64>, Error = Error> {
+    let consumer_token = consumer_token.clone();
+    let access_token = access_token.clone();
+    let endpoints = endpoints.clone();
+    let client = client.clone();
+
+    futures01::stream::unfold(Some(-1), move |cursor| {
+        cursor.map(|cursor| {
+            get_blocked_ids_page(cursor, &consumer_token, &access_token, &endpoints, &client).map(
+                |(ids, next_cursor)| {
+                    let next_cursor = if next_cursor == 0 {
+                        None
+                    } else {
+                        Some(next_cursor)
+                    };
+                    (ids, next_cursor)
+                },
+            )
+        })
+    })
+    .fold(BTreeSet::new(), |mut blocked, page| {
+        blocked.extend(page);
+        Ok::<_, Error>(blocked)
+    })
+}
+
+fn get_blocked_ids_page(
+    cursor: i64,
+    consumer_token: &KeyPair,
+    access_token: &KeyPair,
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
+) -> impl Future01<Item = (Vec<u64>, i64), Error = Error> {
+    let mut params = HashMap::new();
+    add_param(&mut params, "cursor", cursor.to_string());
+
+    let make_request = signer(
+        Method::GET,
+        endpoints.url(Endpoint::BlocksIds),
+        consumer_token,
+        Some(access_token),
+        params,
+    );
+
+    log::debug!("requesting blocked ids page {}", cursor);
+    send(client, Endpoint::BlocksIds, access_token, make_request).and_then(|body| {
+        let body_json: BlockedIds = serde_json::from_slice(&body)
+            .chain_err(|| ErrorKind::JsonParseError("Parsing blocked ids".to_owned()))?;
+        Ok((body_json.ids, body_json.next_cursor))
+    })
+}
+
+/// How many users `friendships/lookup` will take at once.
+const FRIENDSHIPS_LOOKUP_SIZE: usize = 100;
+
+pub fn get_relationships_compat(
+    user_ids: &BTreeSet<u64>,
+    consumer_token: &KeyPair,
+    access_token: &KeyPair,
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
+) -> impl Future<Output = Result<BTreeMap<u64, Relationship>>> {
+    get_relationships(user_ids, consumer_token, access_token, endpoints, client).compat()
+}
+
+/// How the user and each of the given users follow each other. Anyone twitter doesn't know about
+/// any more is left out.
+pub fn get_relationships(
+    user_ids: &BTreeSet<u64>,
+    consumer_token: &KeyPair,
+    access_token: &KeyPair,
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
+) -> impl Future01<Item = BTreeMap<u64, Relationship>, Error = Error> {
+    let user_ids = user_ids.iter().cloned().collect::<Vec<_>>();
+    let lookups = user_ids
+        .chunks(FRIENDSHIPS_LOOKUP_SIZE)
+        .map(|chunk| lookup_friendships(chunk, consumer_token, access_token, endpoints, client))
+        .collect::<Vec<_>>();
+    futures01::future::join_all(lookups)
+        .map(|chunks| chunks.into_iter().flat_map(|chunk| chunk).collect())
+}
+
+fn lookup_friendships(
+    user_ids: &[u64],
+    consumer_token: &KeyPair,
+    access_token: &KeyPair,
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
+) -> impl Future01<Item = Vec<(u64, Relationship)>, Error = Error> {
+    let user_ids = user_ids
+        .iter()
+        .map(|user_id| user_id.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    let mut params = HashMap::new();
+    add_param(&mut params, "user_id", user_ids);
+
+    let make_request = signer(
+        Method::GET,
+        endpoints.url(Endpoint::FriendshipsLookup),
+        consumer_token,
+        Some(access_token),
+        params,
+    );
+
+    send(
+        client,
+        Endpoint::FriendshipsLookup,
+        access_token,
+        make_request,
+    )
+    .and_then(|body| {
+        let friendships: Vec<Friendship> = serde_json::from_slice(&body)
+            .chain_err(|| ErrorKind::JsonParseError("Parsing friendships".to_owned()))?;
+        Ok(friendships
+            .into_iter()
+            .map(|friendship| {
+                let has = |connection: &str| {
+                    friendship
+                        .connections
+                        .iter()
+                        .any(|c| c.as_str() == connection)
+                };
+                let relationship = Relationship {
+                    following: has("following"),
+                    followed_by: has("followed_by"),
+                };
+                (friendship.id, relationship)
+            })
+            .collect())
+    })
+}
+
+pub fn block_user_compat(
+    user_id: u64,
+    consumer_token: &KeyPair,
+    access_token: &KeyPair,
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
+) -> impl Future<Output = Result<()>> {
+    block_user(user_id, consumer_token, access_token, endpoints, client).compat()
+}
+
+/// Block the given user, which also removes the authenticating user from any of their lists.
+pub fn block_user(
+    user_id: u64,
+    consumer_token: &KeyPair,
+    access_token: &KeyPair,
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
+) -> impl Future01<Item = (), Error = Error> {
+    let endpoint = Endpoint::BlocksCreate;
+    post_for_user_id(
+        endpoint,
+        user_id,
+        consumer_token,
+        access_token,
+        endpoints,
+        client,
+    )
+    .map(|_body| ())
+}
+
+pub fn unblock_user_compat(
+    user_id: u64,
+    consumer_token: &KeyPair,
+    access_token: &KeyPair,
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
+) -> impl Future<Output = Result<()>> {
+    unblock_user(user_id, consumer_token, access_token, endpoints, client).compat()
+}
+
+pub fn unblock_user(
+    user_id: u64,
+    consumer_token: &KeyPair,
+    access_token: &KeyPair,
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
+) -> impl Future01<Item = (), Error = Error> {
+    let endpoint = Endpoint::BlocksDestroy;
+    post_for_user_id(
+        endpoint,
+        user_id,
+        consumer_token,
+        access_token,
+        endpoints,
+        client,
+    )
+    .map(|_body| ())
+}
+
+pub fn follow_user_compat(
+    user_id: u64,
+    consumer_token: &KeyPair,
+    access_token: &KeyPair,
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
+) -> impl Future<Output = Result<Follow>> {
+    follow_user(user_id, consumer_token, access_token, endpoints, client).compat()
+}
+
+/// Follow the given user, or ask to if they're protected.
+pub fn follow_user(
+    user_id: u64,
+    consumer_token: &KeyPair,
+    access_token: &KeyPair,
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
+) -> impl Future01<Item = Follow, Error = Error> {
+    let endpoint = Endpoint::FriendshipsCreate;
+    post_for_user_id(
+        endpoint,
+        user_id,
+        consumer_token,
+        access_token,
+        endpoints,
+        client,
+    )
+    .and_then(|body| {
+        let user: FollowedUser = serde_json::from_slice(&body)
+            .chain_err(|| ErrorKind::JsonParseError("Parsing followed user".to_owned()))?;
+        if user.protected || user.follow_request_sent {
+            Ok(Follow::Requested)
+        } else {
+            Ok(Follow::Following)
+        }
+    })
+}
+
+pub fn invalidate_token_compat(
+    consumer_token: &KeyPair,
+    access_token: &KeyPair,
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
+) -> impl Future<Output = Result<()>> {
+    invalidate_token(consumer_token, access_token, endpoints, client).compat()
+}
+
+/// Revoke the access token, so that it can't be used by anyone again.
+pub fn invalidate_token(
+    consumer_token: &KeyPair,
+    access_token: &KeyPair,
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
+) -> impl Future01<Item = (), Error = Error> {
+    let endpoint = Endpoint::InvalidateToken;
+    let make_request = signer(
+        Method::POST,
+        endpoints.url(endpoint),
+        consumer_token,
+        Some(access_token),
+        HashMap::new(),
+    );
+
+    log::debug!("invalidating access token");
+    send(client, endpoint, access_token, make_request).map(|_body| ())
+}
+
+fn post_for_user_id(
+    endpoint: Endpoint,
+    user_id: u64,
+    consumer_token: &KeyPair,
+    access_token: &KeyPair,
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
+) -> impl Future01<Item = Chunk, Error = Error> {
+    let mut params = HashMap::new();
+    add_param(&mut params, "user_id", user_id.to_string());
+    add_param(&mut params, "include_entities", "false");
+    add_param(&mut params, "skip_status", "true");
+
+    let make_request = signer(
+        Method::POST,
+        endpoints.url(endpoint),
+        consumer_token,
+        Some(access_token),
+        params,
+    );
+
+    send(client, endpoint, access_token, make_request)
+}
+
+/// Send the request once the rate limit for this endpoint and token allows, and read the whole
+/// response body, turning any unsuccessful response into a `TwitterError` with whatever twitter
+/// told us about why.
+fn send(
+    client: &ScheduledClient,
+    endpoint: Endpoint,
+    token: &KeyPair,
+    make_request: impl Fn() -> Result<Request<Body>> + Send + 'static,
+) -> impl Future01<Item = Chunk, Error = Error> {
+    let endpoint = endpoint.path();
     client
-        .request(request)
-        .chain_inspect_err_fut(|_| ErrorKind::OtherError("foo".to_owned()))
-        .and_then(|response| {
-            println!("get_first_75_lists status code: {}", response.status());
-            let (_head, body) = response.into_parts();
+        .request(endpoint, &token.key, make_request)
+        .and_then(move |response| {
+            log::debug!("{} status code: {}", endpoint, response.status());
+            let (head, body) = response.into_parts();
             body.concat2()
-                .chain_inspect_err_fut(|_| ErrorKind::OtherError("foo".to_owned()))
-                .map(|body| parse_list_owners(body).unwrap().take(75).collect())
+                .chain_inspect_err_fut(move |_| {
+                    ErrorKind::TwitterRequestError(format!("reading {} body", endpoint))
+                })
+                .and_then(move |body| {
+                    if head.status.is_success() {
+                        Ok(body)
+                    } else {
+                        let rate_limit = RateLimit::from_headers(&head.headers);
+                        let error = TwitterError::from_response(head.status, rate_limit, &body);
+                        log::warn!("{} failed: {}", endpoint, error);
+                        Err(error.into_kind().into())
+                    }
+                })
         })
 }
 
-fn parse_list_owners(body: impl IntoIterator<Item = u8>) -> Result<impl Iterator<Item = u64>> {
-    let body_bytes: Vec<u8> = body.into_iter().collect();
-    let body_json: ListMembership = serde_json::from_slice(&body_bytes)
-        .chain_err(|| ErrorKind::JsonParseError("Parsing list memberships".to_owned()))?;
-    let list_owners_iter = body_json.lists.into_iter().map(|json| json.user.id);
-    Ok(BTreeSet::from_iter(list_owners_iter).into_iter())
+/// Something that builds a freshly signed request each time it's called, for `send`.
+fn signer(
+    method: Method,
+    uri: String,
+    con_token: &KeyPair,
+    access_token: Option<&KeyPair>,
+    params: ParamList<'static>,
+) -> impl Fn() -> Result<Request<Body>> + Send + 'static {
+    let con_token = con_token.clone();
+    let access_token = access_token.cloned();
+    move || {
+        signed_request(
+            method.clone(),
+            &uri,
+            &con_token,
+            access_token.as_ref(),
+            &params,
+        )
+    }
+}
+
+/// Build a request with the given parameters in the query string and a signed OAuth header.
+fn signed_request(
+    method: Method,
+    uri: &str,
+    con_token: &KeyPair,
+    access_token: Option<&KeyPair>,
+    params: &ParamList,
+) -> Result<Request<Body>> {
+    let header = get_header(
+        method.clone(),
+        uri,
+        con_token,
+        access_token,
+        None,
+        None,
+        Some(params),
+    );
+
+    let mut query = params
+        .iter()
+        .map(|(k, v)| format!("{}={}", percent_encode(k), percent_encode(v)))
+        .collect::<Vec<_>>();
+    query.sort();
+    let uri_with_query = format!("{}?{}", uri, query.join("&"));
+
+    build_request(method, &uri_with_query, &header)
+}
+
+/// A request with no body and the given OAuth header.
+fn build_request(method: Method, uri: &str, header: &TwitterOAuth) -> Result<Request<Body>> {
+    let header_value = header
+        .header_value()
+        .chain_err(|| ErrorKind::OtherError("writing OAuth header".to_owned()))?;
+    let header_value = HeaderValue::from_str(&header_value)
+        .chain_err(|| ErrorKind::OtherError("constructing OAuth header value".to_owned()))?;
+    let uri = uri
+        .parse::<Uri>()
+        .chain_err(|| ErrorKind::OtherError(format!("parsing request uri {}", uri)))?;
+    Request::connect(uri)
+        .header(AUTHORIZATION, header_value)
+        .method(method)
+        .body(Body::empty())
+        .chain_err(|| ErrorKind::OtherError("building request".to_owned()))
 }
 
 pub fn request_token_compat(
     con_token: &KeyPair,
     callback: impl Into<String>,
-    client: &Client<HttpsConnector<HttpConnector>, Body>,
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
 ) -> impl Future<Output = Result<KeyPair>> {
-    request_token(con_token, callback, client).compat()
+    request_token(con_token, callback, endpoints, client).compat()
 }
 
 // FIXME: ensure all tokens are alphanum only also limit length
 pub fn request_token(
     con_token: &KeyPair,
     callback: impl Into<String>,
-    client: &Client<HttpsConnector<HttpConnector>, Body>,
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
 ) -> impl Future01<Item = KeyPair, Error = Error> {
-    let header = get_header(
-        Method::POST,
-        REQUEST_TOKEN,
-        con_token,
-        None,
-        Some(callback.into()),
-        None,
-        None,
-    );
-    let header_value = header.header_value().unwrap();
-    let request = Request::connect::<Uri>(REQUEST_TOKEN.parse().unwrap())
-        .header(AUTHORIZATION, HeaderValue::from_str(&header_value).unwrap())
-        .method(Method::POST)
-        .body(Body::empty())
-        .unwrap();
+    let callback = callback.into();
+    let request_con_token = con_token.clone();
+    let uri = endpoints.url(Endpoint::RequestToken);
+    let make_request = move || {
+        let header = get_header(
+            Method::POST,
+            &uri,
+            &request_con_token,
+            None,
+            Some(callback.clone()),
+            None,
+            None,
+        );
+        build_request(Method::POST, &uri, &header)
+    };
 
-    client
-        .request(request)
-        .chain_inspect_err_fut(|_| ErrorKind::OtherError("foo".to_owned()))
-        .and_then(|response| {
-            println!("request_token status code: {}", response.status());
-            let (_head, body) = response.into_parts();
-            body.concat2()
-                .chain_inspect_err_fut(|_| ErrorKind::OtherError("foo".to_owned()))
-                .map(|body| {
-                    let body_bytes: Vec<u8> = body.into_iter().collect();
-                    // oauth_callback_confirmed: true
-                    parse_oauth_tok(&body_bytes).unwrap()
-                })
-        })
+    // There's no access token yet, so this counts against the app's own rate limit.
+    send(client, Endpoint::RequestToken, con_token, make_request).and_then(|body| {
+        let body_bytes: Vec<u8> = body.into_iter().collect();
+        // oauth_callback_confirmed: true
+        parse_oauth_tok(&body_bytes)
+    })
 }
 
 pub fn access_token_compat(
     con_token: &KeyPair,
     access_token_kp: &KeyPair,
     oauth_verifier: impl Into<String>,
-    client: &Client<HttpsConnector<HttpConnector>, Body>,
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
 ) -> impl Future<Output = Result<(KeyPair, u64)>> {
-    access_token(con_token, access_token_kp, oauth_verifier, client).compat()
+    access_token(
+        con_token,
+        access_token_kp,
+        oauth_verifier,
+        endpoints,
+        client,
+    )
+    .compat()
 }
 
 pub fn access_token(
     con_token: &KeyPair,
     access_token: &KeyPair,
     oauth_verifier: impl Into<String>,
-    client: &Client<HttpsConnector<HttpConnector>, Body>,
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
 ) -> impl Future01<Item = (KeyPair, u64), Error = Error> {
-    let header = get_header(
-        Method::POST,
-        ACCESS_TOKEN,
-        con_token,
-        Some(access_token),
-        None,
-        Some(oauth_verifier.into()),
-        None,
-    );
-    let header_value = header.header_value().unwrap();
-    let request = Request::connect::<Uri>(ACCESS_TOKEN.parse().unwrap())
-        .header(AUTHORIZATION, HeaderValue::from_str(&header_value).unwrap())
-        .method(Method::POST)
-        .body(Body::empty())
-        .unwrap();
-    println!("requesting access token with: {:?}", request);
-    client
-        .request(request)
-        .chain_inspect_err_fut(|_| ErrorKind::OtherError("foo".to_owned()))
-        .and_then(|response| {
-            println!("access_token status code: {}", response.status());
-            let (_head, body) = response.into_parts();
-            body.concat2()
-                .chain_inspect_err_fut(|_| ErrorKind::OtherError("foo".to_owned()))
-                .map(|body| {
-                    let body_bytes: Vec<u8> = body.into_iter().collect();
-                    let str_body = String::from_utf8(body_bytes.clone()).unwrap();
-                    println!("access token response: {}", str_body);
-                    //                    user_id: 111111111
-                    //                    screen_name: foobar
-                    parse_oauth_tok_and_user_id(&body_bytes).unwrap()
-                })
-        })
+    let oauth_verifier = oauth_verifier.into();
+    let request_con_token = con_token.clone();
+    let request_access_token = access_token.clone();
+    let uri = endpoints.url(Endpoint::AccessToken);
+    let make_request = move || {
+        let header = get_header(
+            Method::POST,
+            &uri,
+            &request_con_token,
+            Some(&request_access_token),
+            None,
+            Some(oauth_verifier.clone()),
+            None,
+        );
+        build_request(Method::POST, &uri, &header)
+    };
+
+    send(client, Endpoint::AccessToken, access_token, make_request).and_then(|body| {
+        let body_bytes: Vec<u8> = body.into_iter().collect();
+        //                    user_id: 111111111
+        //                    screen_name: foobar
+        parse_oauth_tok_and_user_id(&body_bytes)
+    })
 }
 
 fn parse_oauth_tok_and_user_id(full_resp: &[u8]) -> Result<(KeyPair, u64)> {
@@ -209,8 +790,13 @@ fn parse_oauth_tok_and_user_id(full_resp: &[u8]) -> Result<(KeyPair, u64)> {
         match key.as_ref() {
             "oauth_token" => parsed_key = Some(value.into_owned()),
             "oauth_token_secret" => parsed_secret = Some(value.into_owned()),
-            "user_id" => parsed_user_id = Some(value.parse::<u64>().unwrap()),
-            other => println!("{}: {}", other, value),
+            "user_id" => {
+                let user_id = value.parse::<u64>().chain_err(|| {
+                    ErrorKind::TwitterRequestError(format!("parsing user_id {}", value))
+                })?;
+                parsed_user_id = Some(user_id);
+            }
+            other => log::debug!("ignoring OAuth response field {}", other),
         }
     }
 
@@ -220,9 +806,8 @@ fn parse_oauth_tok_and_user_id(full_resp: &[u8]) -> Result<(KeyPair, u64)> {
     let secret: Result<String> = parsed_secret.ok_or_else(|| {
         ErrorKind::OtherError("Could not find oauth_token_secret parameter".to_owned()).into()
     });
-    let user_id: Result<u64> = parsed_user_id.ok_or_else(|| {
-        ErrorKind::OtherError("Could not find (or parse) user_id parameter".to_owned()).into()
-    });
+    let user_id: Result<u64> = parsed_user_id
+        .ok_or_else(|| ErrorKind::OtherError("Could not find user_id parameter".to_owned()).into());
 
     Ok((KeyPair::new(key?, secret?), user_id?))
 }
@@ -235,7 +820,7 @@ fn parse_oauth_tok(full_resp: &[u8]) -> Result<KeyPair> {
         match key.as_ref() {
             "oauth_token" => parsed_key = Some(value.into_owned()),
             "oauth_token_secret" => parsed_secret = Some(value.into_owned()),
-            other => println!("{}: {}", other, value),
+            other => log::debug!("ignoring OAuth response field {}", other),
         }
     }
 
@@ -379,8 +964,29 @@ fn sign(
     }
 }
 
+/// Check that a request we received was signed by someone holding the given tokens, using the
+/// same signing code as our own requests. This is for the fake twitter server, so that it rejects
+/// anything that real twitter would.
+///
+/// `uri` is the url without any query string, and `params` are the query parameters.
+pub fn verify(
+    header: &TwitterOAuth,
+    method: Method,
+    uri: &str,
+    params: &ParamList,
+    con_token: &KeyPair,
+    access_token: Option<&KeyPair>,
+) -> bool {
+    let unsigned = TwitterOAuth {
+        signature: None,
+        ..header.clone()
+    };
+    let expected = sign(unsigned, method, uri, Some(params), con_token, access_token);
+    header.signature.is_some() && header.signature == expected.signature
+}
+
 #[derive(Clone, Debug)]
-struct TwitterOAuth {
+pub struct TwitterOAuth {
     consumer_key: String,
     nonce: String,
     signature: Option<String>,
@@ -435,7 +1041,59 @@ impl std::str::FromStr for TwitterOAuth {
     }
 }
 
+/// Reading headers back is only needed by the fake twitter server.
 impl TwitterOAuth {
+    /// Parse the value of an `Authorization` header, as written by `header_value`.
+    pub fn from_header_value(value: &str) -> StdResult<Self, String> {
+        // Values are quoted and percent encoded, so once the quotes are gone this is what
+        // `from_str` expects, apart from the fields that are always the same.
+        let fields = value
+            .trim()
+            .trim_start_matches("OAuth ")
+            .split(',')
+            .map(|field| field.trim().replace('"', ""))
+            .filter(|field| {
+                !field.starts_with("oauth_signature_method=")
+                    && !field.starts_with("oauth_version=")
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let encoded: TwitterOAuth = fields.parse()?;
+
+        fn decode(value: String) -> StdResult<String, String> {
+            percent_decode(value.as_bytes())
+                .decode_utf8()
+                .map(|value| value.into_owned())
+                .map_err(|e| e.to_string())
+        }
+
+        Ok(TwitterOAuth {
+            consumer_key: decode(encoded.consumer_key)?,
+            nonce: decode(encoded.nonce)?,
+            signature: encoded.signature.map(decode).transpose()?,
+            timestamp: encoded.timestamp,
+            token: encoded.token.map(decode).transpose()?,
+            callback: encoded.callback.map(decode).transpose()?,
+            verifier: encoded.verifier.map(decode).transpose()?,
+        })
+    }
+
+    pub fn consumer_key(&self) -> &str {
+        &self.consumer_key
+    }
+
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_ref().map(String::as_str)
+    }
+
+    pub fn callback(&self) -> Option<&str> {
+        self.callback.as_ref().map(String::as_str)
+    }
+
+    pub fn verifier(&self) -> Option<&str> {
+        self.verifier.as_ref().map(String::as_str)
+    }
+
     fn header_value(&self) -> StdResult<String, std::fmt::Error> {
         let mut ret = String::new();
         write!(ret, "OAuth ")?;
@@ -1,15 +1,71 @@
+use crate::egg_mode_2::RateLimit;
 use failchain::{BoxedError, ChainErrorKind};
 use failure::Fail;
+use http::StatusCode;
+use serde::Deserialize;
+use std::fmt;
 use std::result::Result as StdResult;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub type Error = BoxedError<ErrorKind>;
 pub type Result<T> = StdResult<T, Error>;
 
 #[derive(Clone, Eq, PartialEq, Debug, Fail)]
 pub enum ErrorKind {
+    #[fail(display = "Missing Query Parameters: {}", 0)]
+    MissingQueryParams(String),
+
+    #[fail(display = "Unknown Or Expired Token")]
+    UnknownOrExpiredToken,
+
+    #[fail(display = "Not Found: {}", 0)]
+    NotFound(String),
+
+    /// The page needs a session, and the request didn't have a valid one.
+    #[fail(display = "Not Logged In")]
+    NotLoggedIn,
+
+    /// A form was submitted without the CSRF token for the session, so it may have come from
+    /// another site.
+    #[fail(display = "Invalid CSRF Token")]
+    InvalidCsrfToken,
+
+    /// The user tried to log out while one of their removals still needs their access token.
+    #[fail(display = "Job In Progress")]
+    JobInProgress,
+
+    /// The removal form was for lists we no longer have, as it's already been submitted or the
+    /// session it was shown in has expired.
+    #[fail(display = "Removal Expired")]
+    RemovalExpired,
+
+    /// The user submitted the removal form without choosing any lists.
+    #[fail(display = "Nothing Selected")]
+    NothingSelected,
+
+    /// We couldn't talk to twitter at all.
+    #[fail(display = "Twitter Request Error: {}", 0)]
+    TwitterRequestError(String),
+
+    /// Twitter answered, but with an error.
+    #[fail(display = "Twitter API Error: {}", 0)]
+    TwitterApiError(TwitterError),
+
+    #[fail(display = "Rate Limited: {}", 0)]
+    RateLimited(TwitterError),
+
     #[fail(display = "Json Parse Error: {}", 0)]
     JsonParseError(String),
 
+    #[fail(display = "Database Error: {}", 0)]
+    DatabaseError(String),
+
+    #[fail(display = "Crypto Error: {}", 0)]
+    CryptoError(String),
+
+    #[fail(display = "Config Error: {}", 0)]
+    ConfigError(String),
+
     #[fail(display = "Other Error: {}", 0)]
     OtherError(String),
 }
@@ -18,6 +74,194 @@ impl ChainErrorKind for ErrorKind {
     type Error = Error;
 }
 
+impl ErrorKind {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ErrorKind::MissingQueryParams(_) => StatusCode::BAD_REQUEST,
+            ErrorKind::UnknownOrExpiredToken => StatusCode::BAD_REQUEST,
+            ErrorKind::NotFound(_) => StatusCode::NOT_FOUND,
+            ErrorKind::NotLoggedIn => StatusCode::UNAUTHORIZED,
+            ErrorKind::InvalidCsrfToken => StatusCode::FORBIDDEN,
+            ErrorKind::JobInProgress => StatusCode::CONFLICT,
+            ErrorKind::RemovalExpired => StatusCode::GONE,
+            ErrorKind::NothingSelected => StatusCode::BAD_REQUEST,
+            ErrorKind::TwitterRequestError(_) => StatusCode::BAD_GATEWAY,
+            ErrorKind::TwitterApiError(e) if e.is_invalid_token() => StatusCode::UNAUTHORIZED,
+            ErrorKind::TwitterApiError(e) if e.is_suspended() => StatusCode::FORBIDDEN,
+            ErrorKind::TwitterApiError(_) => StatusCode::BAD_GATEWAY,
+            ErrorKind::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            ErrorKind::JsonParseError(_)
+            | ErrorKind::DatabaseError(_)
+            | ErrorKind::CryptoError(_)
+            | ErrorKind::ConfigError(_)
+            | ErrorKind::OtherError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// A short explanation of what went wrong that's safe to show to the user.
+    pub fn title(&self) -> &'static str {
+        match self {
+            ErrorKind::MissingQueryParams(_) => "That link is incomplete",
+            ErrorKind::UnknownOrExpiredToken => "That login has expired",
+            ErrorKind::NotFound(_) => "We couldn't find that",
+            ErrorKind::NotLoggedIn => "You're not logged in",
+            ErrorKind::InvalidCsrfToken => "That form has expired",
+            ErrorKind::JobInProgress => "You have a removal in progress",
+            ErrorKind::RemovalExpired => "Those lists are out of date",
+            ErrorKind::NothingSelected => "You didn't choose any lists",
+            ErrorKind::TwitterApiError(e) if e.is_invalid_token() => {
+                "Twitter no longer recognises your login"
+            }
+            ErrorKind::TwitterApiError(e) if e.is_suspended() => "Your account is suspended",
+            ErrorKind::TwitterRequestError(_) | ErrorKind::TwitterApiError(_) => {
+                "Twitter returned an error"
+            }
+            ErrorKind::RateLimited(_) => "Twitter is rate limiting us",
+            _ => "Something went wrong",
+        }
+    }
+
+    /// What the user should do next.
+    pub fn advice(&self) -> String {
+        match self {
+            ErrorKind::MissingQueryParams(_) | ErrorKind::UnknownOrExpiredToken => {
+                "Logins can only be used once and only for a short while. Please log in again."
+                    .to_owned()
+            }
+            ErrorKind::NotFound(_) => {
+                "Please check the link, or start again from the home page.".to_owned()
+            }
+            ErrorKind::NotLoggedIn => {
+                "Your login may have expired, or been from another browser. Please log in again."
+                    .to_owned()
+            }
+            ErrorKind::InvalidCsrfToken => {
+                "Forms only work for the login they were shown to. Please reload the page and try \
+                 again."
+                    .to_owned()
+            }
+            ErrorKind::JobInProgress => {
+                "Logging out now would stop it part way through, which could leave people \
+                 blocked. Please wait for it to finish and try again."
+                    .to_owned()
+            }
+            ErrorKind::RemovalExpired => {
+                "Each page of lists can only be used once, and only for as long as you stay \
+                 logged in. Please reload your lists and choose again."
+                    .to_owned()
+            }
+            ErrorKind::NothingSelected => {
+                "Go back and tick the lists you want to be removed from.".to_owned()
+            }
+            ErrorKind::TwitterApiError(e) if e.is_invalid_token() => {
+                "You may have revoked our access, or the login took too long. Please log in again."
+                    .to_owned()
+            }
+            ErrorKind::TwitterApiError(e) if e.is_suspended() => {
+                "Twitter won't let suspended accounts do this, so there's nothing we can do."
+                    .to_owned()
+            }
+            ErrorKind::TwitterRequestError(_) | ErrorKind::TwitterApiError(_) => {
+                "This is usually temporary, please try again in a few minutes.".to_owned()
+            }
+            ErrorKind::RateLimited(e) => match e.rate_limit {
+                Some(rate_limit) => {
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|dur| dur.as_secs())
+                        .unwrap_or(0);
+                    let minutes = (rate_limit.reset.saturating_sub(now) + 59) / 60;
+                    format!("Please try again in {} minutes.", minutes.max(1))
+                }
+                None => "Please try again in 15 minutes.".to_owned(),
+            },
+            _ => "This is our fault, not yours. Please try again later.".to_owned(),
+        }
+    }
+}
+
+/// One entry of the `errors` array that twitter sends back with an unsuccessful response.
+/// See https://developer.twitter.com/en/docs/basics/response-codes
+#[derive(Clone, Eq, PartialEq, Debug, Deserialize)]
+pub struct TwitterErrorCode {
+    pub code: i32,
+    pub message: String,
+}
+
+#[derive(Deserialize)]
+struct TwitterErrorBody {
+    errors: Vec<TwitterErrorCode>,
+}
+
+/// An unsuccessful response from twitter.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct TwitterError {
+    pub status: u16,
+    pub errors: Vec<TwitterErrorCode>,
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl TwitterError {
+    /// The oauth endpoints don't send json, so `errors` will be empty for them.
+    pub fn from_response(status: StatusCode, rate_limit: Option<RateLimit>, body: &[u8]) -> Self {
+        let errors = serde_json::from_slice::<TwitterErrorBody>(body)
+            .map(|body| body.errors)
+            .unwrap_or_default();
+        TwitterError {
+            status: status.as_u16(),
+            errors,
+            rate_limit,
+        }
+    }
+
+    pub fn has_code(&self, code: i32) -> bool {
+        self.errors.iter().any(|error| error.code == code)
+    }
+
+    /// 89: Invalid or expired token.
+    pub fn is_invalid_token(&self) -> bool {
+        self.status == StatusCode::UNAUTHORIZED.as_u16() || self.has_code(89)
+    }
+
+    /// 88: Rate limit exceeded.
+    pub fn is_rate_limited(&self) -> bool {
+        self.status == StatusCode::TOO_MANY_REQUESTS.as_u16() || self.has_code(88)
+    }
+
+    /// 50: User not found, 63: User has been suspended. Either way there's nothing to do to them.
+    pub fn is_user_gone(&self) -> bool {
+        self.has_code(50) || self.has_code(63)
+    }
+
+    /// 64: Your account is suspended, 326: your account is temporarily locked.
+    pub fn is_suspended(&self) -> bool {
+        self.has_code(64) || self.has_code(326)
+    }
+
+    /// 130: Over capacity, 131: Internal error, or any 5xx. Worth trying again later.
+    pub fn is_transient(&self) -> bool {
+        self.status >= 500 || self.has_code(130) || self.has_code(131)
+    }
+
+    pub fn into_kind(self) -> ErrorKind {
+        if self.is_rate_limited() {
+            ErrorKind::RateLimited(self)
+        } else {
+            ErrorKind::TwitterApiError(self)
+        }
+    }
+}
+
+impl fmt::Display for TwitterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "status {}", self.status)?;
+        for error in &self.errors {
+            write!(f, ", code {}: {}", error.code, error.message)?;
+        }
+        Ok(())
+    }
+}
+
 pub trait Future01Ext<S, E: Fail>: Sized + futures01::Future<Item = S, Error = E> {
     fn chain_inspect_err_fut<ErrorKindT: ChainErrorKind>(
         self,
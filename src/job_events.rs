@@ -0,0 +1,100 @@
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use serde::{Serialize, Serializer};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Something that happened while running a job, streamed to anyone watching its progress page.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobEvent {
+    Blocked {
+        #[serde(serialize_with = "serialize_id")]
+        owner_id: u64,
+    },
+    Unblocked {
+        #[serde(serialize_with = "serialize_id")]
+        owner_id: u64,
+    },
+    /// The user had already blocked them, so they weren't unblocked.
+    KeptBlocked {
+        #[serde(serialize_with = "serialize_id")]
+        owner_id: u64,
+    },
+    Skipped {
+        #[serde(serialize_with = "serialize_id")]
+        owner_id: u64,
+        reason: String,
+    },
+    Failed {
+        #[serde(serialize_with = "serialize_id")]
+        owner_id: u64,
+        error: String,
+    },
+    /// Blocking them made the user unfollow them, and they've been followed again.
+    FollowRestored {
+        #[serde(serialize_with = "serialize_id")]
+        owner_id: u64,
+    },
+    FollowNotRestored {
+        #[serde(serialize_with = "serialize_id")]
+        owner_id: u64,
+        reason: String,
+    },
+    /// Unix time in seconds that the job will carry on at.
+    WaitingForRateLimit {
+        endpoint: String,
+        until: u64,
+    },
+    Progress {
+        done: usize,
+        total: usize,
+        eta_seconds: Option<u64>,
+    },
+    Finished {
+        succeeded: bool,
+    },
+}
+
+/// Twitter ids are too big for javascript numbers, so send them as strings like twitter does.
+fn serialize_id<S: Serializer>(id: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&id.to_string())
+}
+
+/// Where a running job sends its events.
+pub type Reporter = Arc<dyn Fn(JobEvent) + Send + Sync>;
+
+/// Hands out the events of each job to everyone subscribed to it. Events are only for people
+/// watching right now, the job's state in the database is what's authoritative.
+#[derive(Default)]
+pub struct JobEventBus {
+    subscribers: Mutex<HashMap<i64, Vec<UnboundedSender<JobEvent>>>>,
+}
+
+impl JobEventBus {
+    pub fn subscribe(&self, job_id: i64) -> UnboundedReceiver<JobEvent> {
+        let (sender, receiver) = mpsc::unbounded();
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.entry(job_id).or_default().push(sender);
+        }
+        receiver
+    }
+
+    pub fn publish(&self, job_id: i64, event: JobEvent) {
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            if let Some(senders) = subscribers.get_mut(&job_id) {
+                senders.retain(|sender| sender.unbounded_send(event.clone()).is_ok());
+            }
+        }
+    }
+
+    /// Ends the stream of every subscriber to the job.
+    pub fn close(&self, job_id: i64) {
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.remove(&job_id);
+        }
+    }
+
+    pub fn reporter(self: Arc<Self>, job_id: i64) -> Reporter {
+        Arc::new(move |event| self.publish(job_id, event))
+    }
+}
@@ -0,0 +1,1514 @@
+use crate::db::{self, Database};
+use crate::egg_mode_2::{self, ApiEndpoints, Follow};
+use crate::error::*;
+use crate::job_events::{JobEvent, JobEventBus, Reporter};
+use crate::removal::{self, Journal, Outcome, OwnerRemoval, Step};
+use crate::scheduler::{ScheduledClient, WaitListener};
+use crate::token_store::TokenStore;
+use egg_mode::KeyPair;
+use failchain::ResultExt;
+use rand::distributions::{Alphanumeric, Distribution};
+use rusqlite::{params, OptionalExtension};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How often idle workers look for new jobs.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How often to retry unblocking owners that we couldn't unblock at the time.
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// About an hour of retries, after which the owner is failed so that the user can see what went
+/// wrong, and can log out.
+const MAX_RECONCILE_ATTEMPTS: u32 = 12;
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum JobState {
+    Queued,
+    Running,
+    Finished,
+    Failed,
+}
+
+impl JobState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Finished => "finished",
+            JobState::Failed => "failed",
+        }
+    }
+
+    pub fn is_done(self) -> bool {
+        self == JobState::Finished || self == JobState::Failed
+    }
+
+    fn parse(state: &str) -> Result<JobState> {
+        match state {
+            "queued" => Ok(JobState::Queued),
+            "running" => Ok(JobState::Running),
+            "finished" => Ok(JobState::Finished),
+            "failed" => Ok(JobState::Failed),
+            other => {
+                let kind = ErrorKind::DatabaseError(format!("unknown job state {}", other));
+                Err(kind.into())
+            }
+        }
+    }
+}
+
+/// What's happened so far to a single owner in a job. Each step of a removal is recorded as it's
+/// reached, so that `Workers::reconcile` can finish off anything a crash interrupted.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OwnerState {
+    Pending,
+    /// We might have blocked them.
+    Blocking,
+    /// We've blocked them, and they're still blocked.
+    Blocked,
+    /// We might have unblocked them.
+    Unblocking,
+    Removed,
+    /// Removed from their lists, but left blocked as the user had already blocked them.
+    KeptBlocked,
+    /// We didn't try, e.g. because it's the user's own list.
+    Skipped,
+    Failed,
+}
+
+impl OwnerState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OwnerState::Pending => "pending",
+            OwnerState::Blocking => "blocking",
+            OwnerState::Blocked => "blocked",
+            OwnerState::Unblocking => "unblocking",
+            OwnerState::Removed => "removed",
+            OwnerState::KeptBlocked => "kept_blocked",
+            OwnerState::Skipped => "skipped",
+            OwnerState::Failed => "failed",
+        }
+    }
+
+    fn parse(state: &str) -> Result<OwnerState> {
+        match state {
+            "pending" => Ok(OwnerState::Pending),
+            "blocking" => Ok(OwnerState::Blocking),
+            "blocked" => Ok(OwnerState::Blocked),
+            "unblocking" => Ok(OwnerState::Unblocking),
+            "removed" => Ok(OwnerState::Removed),
+            "kept_blocked" => Ok(OwnerState::KeptBlocked),
+            "skipped" => Ok(OwnerState::Skipped),
+            "failed" => Ok(OwnerState::Failed),
+            other => {
+                let kind = ErrorKind::DatabaseError(format!("unknown owner state {}", other));
+                Err(kind.into())
+            }
+        }
+    }
+}
+
+/// Whether we're following an owner again after unblocking them, for users who followed them.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RefollowState {
+    Wanted,
+    /// We might have followed them.
+    Following,
+    Restored,
+    /// Following them failed, or needs them to approve it, kept in `refollow_error`.
+    NotRestored,
+}
+
+impl RefollowState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RefollowState::Wanted => "wanted",
+            RefollowState::Following => "following",
+            RefollowState::Restored => "restored",
+            RefollowState::NotRestored => "not_restored",
+        }
+    }
+
+    fn parse(state: &str) -> Result<RefollowState> {
+        match state {
+            "wanted" => Ok(RefollowState::Wanted),
+            "following" => Ok(RefollowState::Following),
+            "restored" => Ok(RefollowState::Restored),
+            "not_restored" => Ok(RefollowState::NotRestored),
+            other => {
+                let kind = ErrorKind::DatabaseError(format!("unknown refollow state {}", other));
+                Err(kind.into())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Job {
+    pub id: i64,
+    pub user_id: u64,
+    pub state: JobState,
+}
+
+/// Where a single owner in a job has got to, and why it failed if it did.
+#[derive(Clone, Debug, Serialize)]
+pub struct JobOwner {
+    pub owner_id: u64,
+    pub state: OwnerState,
+    pub error: Option<String>,
+    /// `None` if the user didn't follow them, or didn't want them followed again.
+    pub refollow: Option<RefollowState>,
+    pub refollow_error: Option<String>,
+}
+
+impl JobOwner {
+    /// The events that would have got the owner into its current state, for people who start
+    /// watching a job part way through.
+    pub fn to_events(&self) -> Vec<JobEvent> {
+        let owner_id = self.owner_id;
+        let state_event = match self.state {
+            OwnerState::Pending | OwnerState::Blocking => None,
+            // Unblocking them failed, see `JobStore::record_removal`.
+            OwnerState::Blocked if self.error.is_some() => Some(JobEvent::Failed {
+                owner_id,
+                error: self.error.clone().unwrap_or_default(),
+            }),
+            OwnerState::Blocked | OwnerState::Unblocking => Some(JobEvent::Blocked { owner_id }),
+            OwnerState::Removed => Some(JobEvent::Unblocked { owner_id }),
+            OwnerState::KeptBlocked => Some(JobEvent::KeptBlocked { owner_id }),
+            OwnerState::Skipped => Some(JobEvent::Skipped {
+                owner_id,
+                reason: self.error.clone().unwrap_or_default(),
+            }),
+            OwnerState::Failed => Some(JobEvent::Failed {
+                owner_id,
+                error: self.error.clone().unwrap_or_default(),
+            }),
+        };
+        let refollow_event = match self.refollow {
+            None | Some(RefollowState::Wanted) | Some(RefollowState::Following) => None,
+            Some(RefollowState::Restored) => Some(JobEvent::FollowRestored { owner_id }),
+            Some(RefollowState::NotRestored) => Some(JobEvent::FollowNotRestored {
+                owner_id,
+                reason: self.refollow_error.clone().unwrap_or_default(),
+            }),
+        };
+        state_event.into_iter().chain(refollow_event).collect()
+    }
+}
+
+/// Everything needed to run a removal once the user has confirmed it on the logged in page.
+#[derive(Clone, Debug)]
+pub struct PendingRemoval {
+    pub user_id: u64,
+    pub owners: BTreeSet<u64>,
+    /// The owners that the user follows, to follow again afterwards if they want.
+    pub following: BTreeSet<u64>,
+}
+
+/// Removal jobs and the state of each owner in them, persisted so that they survive a restart.
+#[derive(Clone)]
+pub struct JobStore {
+    db: Database,
+}
+
+impl JobStore {
+    pub fn new(db: Database) -> Self {
+        JobStore { db }
+    }
+
+    /// Queue up a job to remove the user from the lists of all of the given owners, following the
+    /// ones in `refollow` again afterwards.
+    pub fn create_job(
+        &self,
+        user_id: u64,
+        owners: impl IntoIterator<Item = u64>,
+        refollow: &BTreeSet<u64>,
+    ) -> Result<i64> {
+        let mut conn = db::lock(&self.db)?;
+        let tx = conn
+            .transaction()
+            .chain_err(|| ErrorKind::DatabaseError("starting creating job".to_owned()))?;
+        tx.execute(
+            "INSERT INTO jobs (user_id, state, created_at) VALUES (?1, ?2, ?3)",
+            params![
+                user_id as i64,
+                JobState::Queued.as_str(),
+                db::to_timestamp(SystemTime::now())
+            ],
+        )
+        .chain_err(|| ErrorKind::DatabaseError("creating job".to_owned()))?;
+        let job_id = tx.last_insert_rowid();
+
+        for owner_id in owners {
+            let refollow_state = if refollow.contains(&owner_id) {
+                Some(RefollowState::Wanted.as_str())
+            } else {
+                None
+            };
+            tx.execute(
+                "INSERT INTO job_owners (job_id, owner_id, state, refollow)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    job_id,
+                    owner_id as i64,
+                    OwnerState::Pending.as_str(),
+                    refollow_state
+                ],
+            )
+            .chain_err(|| ErrorKind::DatabaseError("adding owner to job".to_owned()))?;
+        }
+
+        tx.commit()
+            .chain_err(|| ErrorKind::DatabaseError("committing job".to_owned()))?;
+        Ok(job_id)
+    }
+
+    /// Keep the removal that the user was just shown until they confirm it, returning the id that
+    /// their form has to give back.
+    pub fn save_pending_removal(&self, pending_removal: &PendingRemoval) -> Result<String> {
+        let removal_id = Alphanumeric
+            .sample_iter(&mut rand::thread_rng())
+            .take(32)
+            .collect::<String>();
+        let mut conn = db::lock(&self.db)?;
+        let tx = conn
+            .transaction()
+            .chain_err(|| ErrorKind::DatabaseError("starting saving removal".to_owned()))?;
+        tx.execute(
+            "INSERT INTO pending_removals (id, user_id, created_at) VALUES (?1, ?2, ?3)",
+            params![
+                removal_id,
+                pending_removal.user_id as i64,
+                db::to_timestamp(SystemTime::now())
+            ],
+        )
+        .chain_err(|| ErrorKind::DatabaseError("saving pending removal".to_owned()))?;
+        for &owner_id in &pending_removal.owners {
+            tx.execute(
+                "INSERT INTO pending_removal_owners (removal_id, owner_id, following)
+                 VALUES (?1, ?2, ?3)",
+                params![
+                    removal_id,
+                    owner_id as i64,
+                    pending_removal.following.contains(&owner_id)
+                ],
+            )
+            .chain_err(|| ErrorKind::DatabaseError("adding owner to removal".to_owned()))?;
+        }
+        tx.commit()
+            .chain_err(|| ErrorKind::DatabaseError("committing pending removal".to_owned()))?;
+        Ok(removal_id)
+    }
+
+    /// Take the pending removal so that it can only be started once. Only the user that it was
+    /// for can take it, and only if it was saved after `not_before`.
+    pub fn take_pending_removal(
+        &self,
+        removal_id: &str,
+        user_id: u64,
+        not_before: SystemTime,
+    ) -> Result<Option<PendingRemoval>> {
+        let mut conn = db::lock(&self.db)?;
+        let tx = conn
+            .transaction()
+            .chain_err(|| ErrorKind::DatabaseError("starting taking removal".to_owned()))?;
+        let created_at: Option<i64> = tx
+            .query_row(
+                "SELECT created_at FROM pending_removals WHERE id = ?1 AND user_id = ?2",
+                params![removal_id, user_id as i64],
+                |row| row.get(0),
+            )
+            .optional()
+            .chain_err(|| ErrorKind::DatabaseError("loading pending removal".to_owned()))?;
+        let created_at = match created_at {
+            Some(created_at) => created_at,
+            None => return Ok(None),
+        };
+
+        let owners: Vec<(i64, bool)> = {
+            let mut statement = tx
+                .prepare(
+                    "SELECT owner_id, following FROM pending_removal_owners
+                     WHERE removal_id = ?1",
+                )
+                .chain_err(|| ErrorKind::DatabaseError("loading removal owners".to_owned()))?;
+            let owners = statement
+                .query_map(params![removal_id], |row| (row.get(0), row.get(1)))
+                .and_then(|rows| rows.collect())
+                .chain_err(|| ErrorKind::DatabaseError("loading removal owners".to_owned()))?;
+            owners
+        };
+        delete_pending_removals(&tx, "id = ?1", params![removal_id])?;
+        tx.commit()
+            .chain_err(|| ErrorKind::DatabaseError("committing taking removal".to_owned()))?;
+
+        if created_at < db::to_timestamp(not_before) {
+            return Ok(None);
+        }
+        Ok(Some(PendingRemoval {
+            user_id,
+            owners: owners
+                .iter()
+                .map(|&(owner_id, _)| owner_id as u64)
+                .collect(),
+            following: owners
+                .iter()
+                .filter(|&&(_, following)| following)
+                .map(|&(owner_id, _)| owner_id as u64)
+                .collect(),
+        }))
+    }
+
+    /// Throw away any removals the user was shown but didn't start.
+    pub fn delete_user_pending_removals(&self, user_id: u64) -> Result<usize> {
+        let mut conn = db::lock(&self.db)?;
+        let tx = conn
+            .transaction()
+            .chain_err(|| ErrorKind::DatabaseError("starting deleting removals".to_owned()))?;
+        let deleted = delete_pending_removals(&tx, "user_id = ?1", params![user_id as i64])?;
+        tx.commit()
+            .chain_err(|| ErrorKind::DatabaseError("committing deleting removals".to_owned()))?;
+        Ok(deleted)
+    }
+
+    /// Throw away removals that nobody started before `cutoff`, returning how many there were.
+    pub fn delete_pending_removals_before(&self, cutoff: SystemTime) -> Result<usize> {
+        let mut conn = db::lock(&self.db)?;
+        let tx = conn
+            .transaction()
+            .chain_err(|| ErrorKind::DatabaseError("starting deleting removals".to_owned()))?;
+        let deleted =
+            delete_pending_removals(&tx, "created_at < ?1", params![db::to_timestamp(cutoff)])?;
+        tx.commit()
+            .chain_err(|| ErrorKind::DatabaseError("committing deleting removals".to_owned()))?;
+        Ok(deleted)
+    }
+
+    pub fn get_job(&self, job_id: i64) -> Result<Option<Job>> {
+        let conn = db::lock(&self.db)?;
+        let row: Option<(i64, String)> = conn
+            .query_row(
+                "SELECT user_id, state FROM jobs WHERE id = ?1",
+                params![job_id],
+                |row| (row.get(0), row.get(1)),
+            )
+            .optional()
+            .chain_err(|| ErrorKind::DatabaseError("loading job".to_owned()))?;
+        match row {
+            Some((user_id, state)) => Ok(Some(Job {
+                id: job_id,
+                user_id: user_id as u64,
+                state: JobState::parse(&state)?,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    pub fn job_owners(&self, job_id: i64) -> Result<Vec<JobOwner>> {
+        let conn = db::lock(&self.db)?;
+        let mut statement = conn
+            .prepare(
+                "SELECT owner_id, state, error, refollow, refollow_error FROM job_owners
+                 WHERE job_id = ?1 ORDER BY owner_id",
+            )
+            .chain_err(|| ErrorKind::DatabaseError("loading job owners".to_owned()))?;
+        let rows: Vec<(i64, String, Option<String>, Option<String>, Option<String>)> = statement
+            .query_map(params![job_id], |row| {
+                (row.get(0), row.get(1), row.get(2), row.get(3), row.get(4))
+            })
+            .and_then(|rows| rows.collect())
+            .chain_err(|| ErrorKind::DatabaseError("loading job owners".to_owned()))?;
+        rows.into_iter()
+            .map(|(owner_id, state, error, refollow, refollow_error)| {
+                Ok(JobOwner {
+                    owner_id: owner_id as u64,
+                    state: OwnerState::parse(&state)?,
+                    error,
+                    refollow: match refollow {
+                        Some(refollow) => Some(RefollowState::parse(&refollow)?),
+                        None => None,
+                    },
+                    refollow_error,
+                })
+            })
+            .collect()
+    }
+
+    /// How many of the job's owners have been dealt with, out of how many in total. Anyone part
+    /// way through, or left blocked, hasn't been dealt with yet.
+    pub fn progress(&self, job_id: i64) -> Result<(usize, usize)> {
+        let conn = db::lock(&self.db)?;
+        let (done, total): (i64, i64) = conn
+            .query_row(
+                "SELECT COUNT(CASE WHEN state IN (?1, ?2, ?3, ?4) THEN 1 END), COUNT(*)
+                 FROM job_owners WHERE job_id = ?5",
+                params![
+                    OwnerState::Removed.as_str(),
+                    OwnerState::KeptBlocked.as_str(),
+                    OwnerState::Skipped.as_str(),
+                    OwnerState::Failed.as_str(),
+                    job_id
+                ],
+                |row| (row.get(0), row.get(1)),
+            )
+            .chain_err(|| ErrorKind::DatabaseError("counting job progress".to_owned()))?;
+        Ok((done as usize, total as usize))
+    }
+
+    /// Whether any of the user's jobs still need their access token, either to carry on or to
+    /// finish off an owner that a crash interrupted.
+    pub fn has_unfinished_job(&self, user_id: u64) -> Result<bool> {
+        let conn = db::lock(&self.db)?;
+        let unfinished: bool = conn
+            .query_row(
+                "SELECT EXISTS (
+                     SELECT 1 FROM jobs WHERE user_id = ?1 AND (
+                         state IN (?2, ?3) OR id IN (
+                             SELECT job_id FROM job_owners WHERE state IN (?4, ?5, ?6)
+                         )
+                     )
+                 )",
+                params![
+                    user_id as i64,
+                    JobState::Queued.as_str(),
+                    JobState::Running.as_str(),
+                    OwnerState::Blocking.as_str(),
+                    OwnerState::Blocked.as_str(),
+                    OwnerState::Unblocking.as_str()
+                ],
+                |row| row.get(0),
+            )
+            .chain_err(|| ErrorKind::DatabaseError("checking for unfinished jobs".to_owned()))?;
+        Ok(unfinished)
+    }
+
+    /// When the user's most recent job finished, if any of them have.
+    pub fn last_finished_at(&self, user_id: u64) -> Result<Option<SystemTime>> {
+        let conn = db::lock(&self.db)?;
+        let finished_at: Option<i64> = conn
+            .query_row(
+                "SELECT MAX(finished_at) FROM jobs WHERE user_id = ?1",
+                params![user_id as i64],
+                |row| row.get(0),
+            )
+            .chain_err(|| ErrorKind::DatabaseError("loading last finished job".to_owned()))?;
+        Ok(finished_at.map(db::from_timestamp))
+    }
+
+    /// Forget every job the user has started, returning how many there were.
+    pub fn delete_user_jobs(&self, user_id: u64) -> Result<usize> {
+        let mut conn = db::lock(&self.db)?;
+        let tx = conn
+            .transaction()
+            .chain_err(|| ErrorKind::DatabaseError("starting deleting jobs".to_owned()))?;
+        tx.execute(
+            "DELETE FROM job_owners WHERE job_id IN (SELECT id FROM jobs WHERE user_id = ?1)",
+            params![user_id as i64],
+        )
+        .chain_err(|| ErrorKind::DatabaseError("deleting job owners".to_owned()))?;
+        let deleted = tx
+            .execute(
+                "DELETE FROM jobs WHERE user_id = ?1",
+                params![user_id as i64],
+            )
+            .chain_err(|| ErrorKind::DatabaseError("deleting jobs".to_owned()))?;
+        tx.commit()
+            .chain_err(|| ErrorKind::DatabaseError("committing deleting jobs".to_owned()))?;
+        Ok(deleted)
+    }
+
+    /// Jobs that were running when the server stopped get picked up again from where they were.
+    pub fn requeue_interrupted(&self) -> Result<usize> {
+        let conn = db::lock(&self.db)?;
+        let requeued = conn
+            .execute(
+                "UPDATE jobs SET state = ?1 WHERE state = ?2",
+                params![JobState::Queued.as_str(), JobState::Running.as_str()],
+            )
+            .chain_err(|| ErrorKind::DatabaseError("requeueing jobs".to_owned()))?;
+        Ok(requeued)
+    }
+
+    /// Take the oldest queued job, marking it as running so that no other worker takes it too.
+    fn claim_next_job(&self) -> Result<Option<Job>> {
+        let mut conn = db::lock(&self.db)?;
+        let tx = conn
+            .transaction()
+            .chain_err(|| ErrorKind::DatabaseError("starting claiming job".to_owned()))?;
+        let row: Option<(i64, i64)> = tx
+            .query_row(
+                "SELECT id, user_id FROM jobs WHERE state = ?1 ORDER BY id LIMIT 1",
+                params![JobState::Queued.as_str()],
+                |row| (row.get(0), row.get(1)),
+            )
+            .optional()
+            .chain_err(|| ErrorKind::DatabaseError("finding queued job".to_owned()))?;
+        let job = match row {
+            Some((id, user_id)) => {
+                tx.execute(
+                    "UPDATE jobs SET state = ?1 WHERE id = ?2",
+                    params![JobState::Running.as_str(), id],
+                )
+                .chain_err(|| ErrorKind::DatabaseError("claiming job".to_owned()))?;
+                Some(Job {
+                    id,
+                    user_id: user_id as u64,
+                    state: JobState::Running,
+                })
+            }
+            None => None,
+        };
+        tx.commit()
+            .chain_err(|| ErrorKind::DatabaseError("committing claiming job".to_owned()))?;
+        Ok(job)
+    }
+
+    fn pending_owners(&self, job_id: i64, limit: usize) -> Result<Vec<u64>> {
+        let conn = db::lock(&self.db)?;
+        let mut statement = conn
+            .prepare(
+                "SELECT owner_id FROM job_owners WHERE job_id = ?1 AND state = ?2
+                 ORDER BY owner_id LIMIT ?3",
+            )
+            .chain_err(|| ErrorKind::DatabaseError("loading pending owners".to_owned()))?;
+        let owners = statement
+            .query_map(
+                params![job_id, OwnerState::Pending.as_str(), limit as i64],
+                |row| row.get::<_, i64>(0) as u64,
+            )
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<u64>>>())
+            .chain_err(|| ErrorKind::DatabaseError("loading pending owners".to_owned()))?;
+        Ok(owners)
+    }
+
+    /// The owners that still need following again once they've been unblocked.
+    fn refollow_owners(&self, job_id: i64) -> Result<BTreeSet<u64>> {
+        let conn = db::lock(&self.db)?;
+        let mut statement = conn
+            .prepare("SELECT owner_id FROM job_owners WHERE job_id = ?1 AND refollow = ?2")
+            .chain_err(|| ErrorKind::DatabaseError("loading refollow owners".to_owned()))?;
+        let owners = statement
+            .query_map(params![job_id, RefollowState::Wanted.as_str()], |row| {
+                row.get::<_, i64>(0) as u64
+            })
+            .and_then(|rows| rows.collect::<rusqlite::Result<BTreeSet<u64>>>())
+            .chain_err(|| ErrorKind::DatabaseError("loading refollow owners".to_owned()))?;
+        Ok(owners)
+    }
+
+    fn record_removal(&self, job_id: i64, removal: &OwnerRemoval) -> Result<()> {
+        let (state, error) = match removal.result {
+            Ok(Outcome::Unblocked) => (OwnerState::Removed, None),
+            Ok(Outcome::KeptBlocked) => (OwnerState::KeptBlocked, None),
+            // Left for `Workers::reconcile` to unblock next time it runs.
+            Err(ref e) if removal.still_blocked => (OwnerState::Blocked, Some(e.to_string())),
+            Err(ref e) => (OwnerState::Failed, Some(e.to_string())),
+        };
+        self.set_owner_state(
+            job_id,
+            removal.owner_id,
+            state,
+            error.as_ref().map(String::as_str),
+        )?;
+
+        match removal.refollow {
+            Some(ref follow) => self.record_refollow(job_id, removal.owner_id, follow),
+            None => Ok(()),
+        }
+    }
+
+    fn record_refollow(&self, job_id: i64, owner_id: u64, follow: &Result<Follow>) -> Result<()> {
+        let (refollow, refollow_error) = match follow {
+            Ok(Follow::Following) => (RefollowState::Restored, None),
+            Ok(Follow::Requested) => (
+                RefollowState::NotRestored,
+                Some(removal::REQUESTED_REASON.to_owned()),
+            ),
+            Err(e) => (RefollowState::NotRestored, Some(e.to_string())),
+        };
+        let refollow_error = refollow_error.as_ref().map(String::as_str);
+        self.set_refollow_state(job_id, owner_id, refollow, refollow_error)
+    }
+
+    fn set_refollow_state(
+        &self,
+        job_id: i64,
+        owner_id: u64,
+        refollow: RefollowState,
+        refollow_error: Option<&str>,
+    ) -> Result<()> {
+        let conn = db::lock(&self.db)?;
+        conn.execute(
+            "UPDATE job_owners SET refollow = ?1, refollow_error = ?2
+             WHERE job_id = ?3 AND owner_id = ?4",
+            params![refollow.as_str(), refollow_error, job_id, owner_id as i64],
+        )
+        .chain_err(|| ErrorKind::DatabaseError("recording refollow".to_owned()))?;
+        Ok(())
+    }
+
+    /// Record that the owner has reached `step`, see `removal::Journal`.
+    fn record_step(&self, job_id: i64, owner_id: u64, step: Step) -> Result<()> {
+        match step {
+            Step::Blocking => self.set_owner_state(job_id, owner_id, OwnerState::Blocking, None),
+            Step::Blocked => self.set_owner_state(job_id, owner_id, OwnerState::Blocked, None),
+            Step::Unblocking => {
+                self.set_owner_state(job_id, owner_id, OwnerState::Unblocking, None)
+            }
+            Step::Following => {
+                self.set_refollow_state(job_id, owner_id, RefollowState::Following, None)
+            }
+        }
+    }
+
+    /// Owners that a crash or a failed unblock left part way through being removed or followed
+    /// again. Owners of running jobs are left to their worker.
+    fn interrupted_owners(&self) -> Result<Vec<InterruptedOwner>> {
+        let conn = db::lock(&self.db)?;
+        let mut statement = conn
+            .prepare(
+                "SELECT job_owners.job_id, jobs.user_id, job_owners.owner_id, job_owners.state,
+                        job_owners.refollow, job_owners.reconcile_attempts
+                 FROM job_owners JOIN jobs ON jobs.id = job_owners.job_id
+                 WHERE jobs.state != ?7 AND (
+                     job_owners.state IN (?1, ?2, ?3)
+                     OR (job_owners.state = ?4 AND job_owners.refollow IN (?5, ?6))
+                 )
+                 ORDER BY job_owners.job_id, job_owners.owner_id",
+            )
+            .chain_err(|| ErrorKind::DatabaseError("loading interrupted owners".to_owned()))?;
+        let rows: Vec<(i64, i64, i64, String, Option<String>, i64)> = statement
+            .query_map(
+                params![
+                    OwnerState::Blocking.as_str(),
+                    OwnerState::Blocked.as_str(),
+                    OwnerState::Unblocking.as_str(),
+                    OwnerState::Removed.as_str(),
+                    RefollowState::Wanted.as_str(),
+                    RefollowState::Following.as_str(),
+                    JobState::Running.as_str()
+                ],
+                |row| {
+                    (
+                        row.get(0),
+                        row.get(1),
+                        row.get(2),
+                        row.get(3),
+                        row.get(4),
+                        row.get(5),
+                    )
+                },
+            )
+            .and_then(|rows| rows.collect())
+            .chain_err(|| ErrorKind::DatabaseError("loading interrupted owners".to_owned()))?;
+        rows.into_iter()
+            .map(|(job_id, user_id, owner_id, state, refollow, attempts)| {
+                Ok(InterruptedOwner {
+                    job_id,
+                    user_id: user_id as u64,
+                    owner_id: owner_id as u64,
+                    state: OwnerState::parse(&state)?,
+                    refollow: match refollow {
+                        Some(refollow) => Some(RefollowState::parse(&refollow)?),
+                        None => None,
+                    },
+                    attempts: attempts as u32,
+                })
+            })
+            .collect()
+    }
+
+    /// Count a failed attempt to reconcile the owner. After `MAX_RECONCILE_ATTEMPTS` we give up,
+    /// and the owner or their refollow is failed with `error`.
+    fn record_reconcile_failure(&self, owner: &InterruptedOwner, error: &str) -> Result<()> {
+        if owner.attempts + 1 < MAX_RECONCILE_ATTEMPTS {
+            let conn = db::lock(&self.db)?;
+            conn.execute(
+                "UPDATE job_owners SET reconcile_attempts = reconcile_attempts + 1
+                 WHERE job_id = ?1 AND owner_id = ?2",
+                params![owner.job_id, owner.owner_id as i64],
+            )
+            .chain_err(|| ErrorKind::DatabaseError("counting reconcile attempt".to_owned()))?;
+            return Ok(());
+        }
+
+        log::warn!(
+            "Giving up on reconciling owner {} of job {}",
+            owner.owner_id,
+            owner.job_id
+        );
+        if owner.state == OwnerState::Removed {
+            let refollow = RefollowState::NotRestored;
+            self.set_refollow_state(owner.job_id, owner.owner_id, refollow, Some(error))
+        } else {
+            let error = format!(
+                "Could not unblock them, so they may still be blocked: {}",
+                error
+            );
+            self.set_owner_state(
+                owner.job_id,
+                owner.owner_id,
+                OwnerState::Failed,
+                Some(&error),
+            )
+        }
+    }
+
+    /// Queue any finished job with owners that have gone back to pending again, so that they're
+    /// not left on those owners' lists. Returns how many there were.
+    fn requeue_jobs_with_pending_owners(&self) -> Result<usize> {
+        let conn = db::lock(&self.db)?;
+        let requeued = conn
+            .execute(
+                "UPDATE jobs SET state = ?1, finished_at = NULL
+                 WHERE state IN (?2, ?3)
+                   AND id IN (SELECT job_id FROM job_owners WHERE state = ?4)",
+                params![
+                    JobState::Queued.as_str(),
+                    JobState::Finished.as_str(),
+                    JobState::Failed.as_str(),
+                    OwnerState::Pending.as_str()
+                ],
+            )
+            .chain_err(|| ErrorKind::DatabaseError("requeueing jobs".to_owned()))?;
+        Ok(requeued)
+    }
+
+    /// `reason` is kept in the `error` column, as it's why nothing was done.
+    fn skip_owner(&self, job_id: i64, owner_id: u64, reason: &str) -> Result<()> {
+        self.set_owner_state(job_id, owner_id, OwnerState::Skipped, Some(reason))
+    }
+
+    fn set_owner_state(
+        &self,
+        job_id: i64,
+        owner_id: u64,
+        state: OwnerState,
+        error: Option<&str>,
+    ) -> Result<()> {
+        let conn = db::lock(&self.db)?;
+        conn.execute(
+            "UPDATE job_owners SET state = ?1, error = ?2 WHERE job_id = ?3 AND owner_id = ?4",
+            params![state.as_str(), error, job_id, owner_id as i64],
+        )
+        .chain_err(|| ErrorKind::DatabaseError("recording owner state".to_owned()))?;
+        Ok(())
+    }
+
+    /// Give up on every owner that hasn't been done yet, e.g. because we no longer have access.
+    fn fail_pending_owners(&self, job_id: i64, error: &str) -> Result<()> {
+        let conn = db::lock(&self.db)?;
+        conn.execute(
+            "UPDATE job_owners SET state = ?1, error = ?2 WHERE job_id = ?3 AND state = ?4",
+            params![
+                OwnerState::Failed.as_str(),
+                error,
+                job_id,
+                OwnerState::Pending.as_str()
+            ],
+        )
+        .chain_err(|| ErrorKind::DatabaseError("failing pending owners".to_owned()))?;
+        Ok(())
+    }
+
+    fn finish_job(&self, job_id: i64, state: JobState) -> Result<()> {
+        let conn = db::lock(&self.db)?;
+        conn.execute(
+            "UPDATE jobs SET state = ?1, finished_at = ?2 WHERE id = ?3",
+            params![state.as_str(), db::to_timestamp(SystemTime::now()), job_id],
+        )
+        .chain_err(|| ErrorKind::DatabaseError("finishing job".to_owned()))?;
+        Ok(())
+    }
+}
+
+/// Delete the pending removals matching `condition`, along with their owners.
+fn delete_pending_removals(
+    tx: &rusqlite::Transaction,
+    condition: &str,
+    params: &[&dyn rusqlite::types::ToSql],
+) -> Result<usize> {
+    tx.execute(
+        &format!(
+            "DELETE FROM pending_removal_owners WHERE removal_id IN
+                 (SELECT id FROM pending_removals WHERE {})",
+            condition
+        ),
+        params,
+    )
+    .chain_err(|| ErrorKind::DatabaseError("deleting removal owners".to_owned()))?;
+    let deleted = tx
+        .execute(
+            &format!("DELETE FROM pending_removals WHERE {}", condition),
+            params,
+        )
+        .chain_err(|| ErrorKind::DatabaseError("deleting pending removals".to_owned()))?;
+    Ok(deleted)
+}
+
+/// An owner that a crash left part way through, along with whose job it was.
+struct InterruptedOwner {
+    job_id: i64,
+    user_id: u64,
+    owner_id: u64,
+    state: OwnerState,
+    refollow: Option<RefollowState>,
+    /// How many times reconciling them has failed so far.
+    attempts: u32,
+}
+
+/// Everything a worker needs to run jobs.
+#[derive(Clone)]
+pub struct Workers {
+    pub jobs: JobStore,
+    pub token_store: &'static dyn TokenStore,
+    pub consumer_token: &'static KeyPair,
+    pub endpoints: &'static ApiEndpoints,
+    pub events: Arc<JobEventBus>,
+    /// This should wait for rate limits however long they take, as nobody's waiting on a worker.
+    pub client: ScheduledClient,
+}
+
+impl Workers {
+    /// Finish off anything that a crash left part way through, and then start `count` worker
+    /// threads. This happens on its own thread so that the server can start straight away, but
+    /// no jobs are run until it's done. Any running jobs have to have been requeued first.
+    /// After that the same thread retries any unblocks that failed every `RECONCILE_INTERVAL`.
+    pub fn start(self, count: usize) -> std::io::Result<()> {
+        thread::Builder::new()
+            .name("job-reconciler".to_owned())
+            .spawn(move || {
+                self.log_reconcile();
+                if let Err(e) = self.clone().spawn(count) {
+                    log::error!("Could not start job workers: {:?}", e);
+                }
+                loop {
+                    thread::sleep(RECONCILE_INTERVAL);
+                    self.log_reconcile();
+                }
+            })?;
+        Ok(())
+    }
+
+    /// Start `count` worker threads, each of which runs one job at a time.
+    fn spawn(self, count: usize) -> std::io::Result<()> {
+        for index in 0..count {
+            let workers = self.clone();
+            thread::Builder::new()
+                .name(format!("job-worker-{}", index))
+                .spawn(move || workers.run())?;
+        }
+        Ok(())
+    }
+
+    fn log_reconcile(&self) {
+        match self.reconcile() {
+            Ok(0) => {}
+            Ok(reconciled) => log::info!("Reconciled {} interrupted owners", reconciled),
+            Err(e) => log::error!("Could not reconcile interrupted owners: {:?}", e),
+        }
+    }
+
+    /// Unblock everyone who might still be blocked because of a crash or a failed unblock, and
+    /// follow anyone who should have been followed again. Anyone we might not have got round to
+    /// blocking goes back to pending, and their job is queued again if it had finished, so that
+    /// it does them again. Anything that fails is left for next time, up to
+    /// `MAX_RECONCILE_ATTEMPTS`.
+    fn reconcile(&self) -> Result<usize> {
+        let mut runtime = tokio::runtime::current_thread::Runtime::new()
+            .chain_err(|| ErrorKind::OtherError("starting reconciler runtime".to_owned()))?;
+
+        let mut reconciled = 0;
+        for owner in self.jobs.interrupted_owners()? {
+            match self.reconcile_owner(&mut runtime, &owner) {
+                Ok(()) => reconciled += 1,
+                Err(e) => {
+                    log::error!(
+                        "Could not reconcile owner {} of job {}: {:?}",
+                        owner.owner_id,
+                        owner.job_id,
+                        e
+                    );
+                    if let Err(e) = self.jobs.record_reconcile_failure(&owner, &e.to_string()) {
+                        log::error!("Could not record failing to reconcile: {:?}", e);
+                    }
+                }
+            }
+        }
+
+        let requeued = self.jobs.requeue_jobs_with_pending_owners()?;
+        if requeued > 0 {
+            log::info!("Requeued {} jobs with owners to do again", requeued);
+        }
+        Ok(reconciled)
+    }
+
+    fn reconcile_owner(
+        &self,
+        runtime: &mut tokio::runtime::current_thread::Runtime,
+        owner: &InterruptedOwner,
+    ) -> Result<()> {
+        let access_token = self
+            .token_store
+            .get_access_token(owner.user_id)?
+            .ok_or_else(|| -> Error {
+                ErrorKind::OtherError("No access token for job's user".to_owned()).into()
+            })?;
+
+        let mut state = owner.state;
+        if state != OwnerState::Removed {
+            // Unblocking someone who isn't blocked does nothing, so this is safe even if the
+            // block never happened.
+            let unblocked = runtime.block_on(egg_mode_2::unblock_user(
+                owner.owner_id,
+                self.consumer_token,
+                &access_token,
+                self.endpoints,
+                &self.client,
+            ));
+            match unblocked {
+                Ok(()) => {}
+                // Nobody can be blocked by a deleted or suspended account.
+                Err(ref e) if is_user_gone(e) => {
+                    log::info!("Owner {} of job {} is gone", owner.owner_id, owner.job_id)
+                }
+                Err(e) => return Err(e),
+            }
+            // If we don't know that they were blocked then we don't know that the user is off
+            // their lists.
+            state = if state == OwnerState::Blocking {
+                OwnerState::Pending
+            } else {
+                OwnerState::Removed
+            };
+            self.jobs
+                .set_owner_state(owner.job_id, owner.owner_id, state, None)?;
+            log::info!(
+                "Unblocked owner {} of job {} after a crash",
+                owner.owner_id,
+                owner.job_id
+            );
+        }
+
+        let wants_refollow = match owner.refollow {
+            Some(RefollowState::Wanted) | Some(RefollowState::Following) => true,
+            _ => false,
+        };
+        if state == OwnerState::Removed && wants_refollow {
+            self.jobs
+                .record_step(owner.job_id, owner.owner_id, Step::Following)?;
+            let follow = runtime.block_on(egg_mode_2::follow_user(
+                owner.owner_id,
+                self.consumer_token,
+                &access_token,
+                self.endpoints,
+                &self.client,
+            ));
+            self.jobs
+                .record_refollow(owner.job_id, owner.owner_id, &follow)?;
+        }
+        Ok(())
+    }
+
+    /// Records each step of the job's removals, so that `reconcile` can pick up after a crash.
+    fn journal(&self, job_id: i64) -> Journal {
+        let jobs = self.jobs.clone();
+        Arc::new(move |owner_id, step| jobs.record_step(job_id, owner_id, step))
+    }
+
+    fn run(self) {
+        let mut runtime = match tokio::runtime::current_thread::Runtime::new() {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                log::error!("Could not start job worker runtime: {:?}", e);
+                return;
+            }
+        };
+
+        loop {
+            match self.jobs.claim_next_job() {
+                Ok(Some(job)) => {
+                    log::info!("Starting job {}", job.id);
+                    let state = match self.run_job(&mut runtime, &job) {
+                        Ok(()) => JobState::Finished,
+                        Err(e) => {
+                            log::error!("Job {} failed: {:?}", job.id, e);
+                            let _ = self.jobs.fail_pending_owners(job.id, &e.to_string());
+                            JobState::Failed
+                        }
+                    };
+                    if let Err(e) = self.jobs.finish_job(job.id, state) {
+                        log::error!("Could not finish job {}: {:?}", job.id, e);
+                    }
+                    self.events.publish(
+                        job.id,
+                        JobEvent::Finished {
+                            succeeded: state == JobState::Finished,
+                        },
+                    );
+                    self.events.close(job.id);
+                }
+                Ok(None) => thread::sleep(POLL_INTERVAL),
+                Err(e) => {
+                    log::error!("Could not claim a job: {:?}", e);
+                    thread::sleep(POLL_INTERVAL);
+                }
+            }
+        }
+    }
+
+    /// Work through the job's pending owners a batch at a time, recording each result as soon as
+    /// its batch is done so a restart only has to redo the batch that was in progress.
+    /// Progress is reported after every batch, with an ETA based on how fast this run has gone.
+    fn run_job(
+        &self,
+        runtime: &mut tokio::runtime::current_thread::Runtime,
+        job: &Job,
+    ) -> Result<()> {
+        let access_token =
+            self.token_store
+                .get_access_token(job.user_id)?
+                .ok_or_else(|| -> Error {
+                    ErrorKind::OtherError("No access token for job's user".to_owned()).into()
+                })?;
+        let report = self.events.clone().reporter(job.id);
+        let journal = self.journal(job.id);
+        let client = self
+            .client
+            .with_wait_listener(wait_reporter(report.clone()));
+
+        // Look this up now rather than trusting what we showed the user, as they might have
+        // blocked someone since. If we can't find out then we can't safely unblock anyone.
+        let already_blocked = runtime.block_on(egg_mode_2::get_blocked_ids(
+            self.consumer_token,
+            &access_token,
+            self.endpoints,
+            &client,
+        ))?;
+        let refollow = self.jobs.refollow_owners(job.id)?;
+
+        let started = Instant::now();
+        let (done_before, _) = self.jobs.progress(job.id)?;
+        loop {
+            let mut batch = self.jobs.pending_owners(job.id, removal::BATCH_SIZE)?;
+            if batch.is_empty() {
+                return Ok(());
+            }
+
+            // You can be on your own lists, but twitter won't let you block yourself.
+            if let Some(index) = batch.iter().position(|&owner_id| owner_id == job.user_id) {
+                let owner_id = batch.remove(index);
+                let reason = "This is one of your own lists";
+                self.jobs.skip_owner(job.id, owner_id, reason)?;
+                report(JobEvent::Skipped {
+                    owner_id,
+                    reason: reason.to_owned(),
+                });
+            }
+
+            let removals = runtime.block_on(removal::remove_batch(
+                batch,
+                &already_blocked,
+                &refollow,
+                self.consumer_token,
+                &access_token,
+                self.endpoints,
+                &client,
+                &report,
+                &journal,
+            ))?;
+            for removal in &removals {
+                self.jobs.record_removal(job.id, removal)?;
+            }
+
+            let (done, total) = self.jobs.progress(job.id)?;
+            let eta_seconds =
+                estimate_remaining(started.elapsed(), done - done_before, total - done);
+            report(JobEvent::Progress {
+                done,
+                total,
+                eta_seconds,
+            });
+
+            // If twitter won't accept the token any more then there's no point carrying on.
+            let invalid_token = removals.iter().find_map(|removal| match removal.result {
+                Err(ref e) => match e.kind() {
+                    ErrorKind::TwitterApiError(twitter_error)
+                        if twitter_error.is_invalid_token() =>
+                    {
+                        Some(e.to_string())
+                    }
+                    _ => None,
+                },
+                Ok(_) => None,
+            });
+            if let Some(error) = invalid_token {
+                return Err(ErrorKind::OtherError(error).into());
+            }
+        }
+    }
+}
+
+fn is_user_gone(e: &Error) -> bool {
+    match e.kind() {
+        ErrorKind::TwitterApiError(twitter_error) => twitter_error.is_user_gone(),
+        _ => false,
+    }
+}
+
+fn wait_reporter(report: Reporter) -> WaitListener {
+    Arc::new(move |endpoint, until| {
+        report(JobEvent::WaitingForRateLimit {
+            endpoint: endpoint.to_owned(),
+            until: until
+                .duration_since(UNIX_EPOCH)
+                .map(|dur| dur.as_secs())
+                .unwrap_or(0),
+        })
+    })
+}
+
+/// Assume the rest will go as fast as the owners done so far, which includes any time spent
+/// waiting for rate limits.
+fn estimate_remaining(elapsed: Duration, done: usize, remaining: usize) -> Option<u64> {
+    if done == 0 {
+        return None;
+    }
+    Some(elapsed.as_secs() * remaining as u64 / done as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::egg_mode_2::Endpoint;
+    use crate::scheduler::SystemClock;
+    use crate::token_store::MemoryTokenStore;
+    use futures01::Future as Future01;
+    use hyper::client::Client;
+    use hyper::service::service_fn_ok;
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use hyper_tls::HttpsConnector;
+    use std::sync::Mutex;
+    use url::form_urlencoded;
+
+    const USER: u64 = 1;
+    /// Followed by the user, so they should be followed again once they're unblocked.
+    const OWNER: u64 = 10;
+    /// Blocked by the user before their job started.
+    const BLOCKED_OWNER: u64 = 20;
+
+    /// The bits of twitter that a removal touches.
+    #[derive(Default)]
+    struct Twitter {
+        blocked: BTreeSet<u64>,
+        /// Everyone who's been blocked or unblocked, in order.
+        blocks: Vec<u64>,
+        unblocks: Vec<u64>,
+        following: BTreeSet<u64>,
+        /// Stands in for our server having crashed, as nothing gets through while it's set.
+        down: bool,
+        down_after_blocking: Option<u64>,
+        /// Deleted accounts, which twitter says it can't find.
+        missing: BTreeSet<u64>,
+        /// Accounts that can't be unblocked, however many times we try.
+        unblock_fails: BTreeSet<u64>,
+    }
+
+    type SharedTwitter = Arc<Mutex<Twitter>>;
+
+    fn respond(twitter: &SharedTwitter, request: Request<Body>) -> Response<Body> {
+        let mut twitter = twitter.lock().unwrap();
+        let user_id = request.uri().query().and_then(|query| {
+            form_urlencoded::parse(query.as_bytes())
+                .find(|(key, _)| key == "user_id")
+                .and_then(|(_, value)| value.parse::<u64>().ok())
+        });
+        let status = match user_id {
+            _ if twitter.down => StatusCode::SERVICE_UNAVAILABLE,
+            Some(user_id) if twitter.missing.contains(&user_id) => {
+                return Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::from(
+                        r#"{"errors":[{"code":50,"message":"User not found."}]}"#,
+                    ))
+                    .unwrap();
+            }
+            Some(user_id) if request.uri().path() == Endpoint::BlocksCreate.path() => {
+                twitter.blocked.insert(user_id);
+                twitter.blocks.push(user_id);
+                twitter.following.remove(&user_id);
+                twitter.down = twitter.down_after_blocking == Some(user_id);
+                StatusCode::OK
+            }
+            Some(user_id) if request.uri().path() == Endpoint::BlocksDestroy.path() => {
+                if twitter.unblock_fails.contains(&user_id) {
+                    return Response::builder()
+                        .status(StatusCode::FORBIDDEN)
+                        .body(Body::from("{}"))
+                        .unwrap();
+                }
+                twitter.blocked.remove(&user_id);
+                twitter.unblocks.push(user_id);
+                StatusCode::OK
+            }
+            Some(user_id) if request.uri().path() == Endpoint::FriendshipsCreate.path() => {
+                twitter.following.insert(user_id);
+                StatusCode::OK
+            }
+            _ => StatusCode::NOT_FOUND,
+        };
+        Response::builder()
+            .status(status)
+            .body(Body::from("{}"))
+            .unwrap()
+    }
+
+    fn start_twitter(
+        runtime: &mut tokio::runtime::Runtime,
+        twitter: SharedTwitter,
+    ) -> ApiEndpoints {
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(move || {
+            let twitter = twitter.clone();
+            service_fn_ok(move |request| respond(&twitter, request))
+        });
+        let endpoints = ApiEndpoints::new(&format!("http://{}", server.local_addr())).unwrap();
+        runtime.spawn(server.map_err(|e| eprintln!("stub twitter failed: {:?}", e)));
+        endpoints
+    }
+
+    fn access_token() -> KeyPair {
+        KeyPair::new("access", "access secret")
+    }
+
+    /// Twitter, where the user follows `OWNER` and has blocked `BLOCKED_OWNER`, and workers that
+    /// talk to it.
+    fn start_workers(runtime: &mut tokio::runtime::Runtime) -> (SharedTwitter, Workers) {
+        let twitter = SharedTwitter::default();
+        twitter.lock().unwrap().blocked.insert(BLOCKED_OWNER);
+        twitter.lock().unwrap().following.insert(OWNER);
+        let endpoints = start_twitter(runtime, twitter.clone());
+        let jobs = JobStore::new(db::open(":memory:").unwrap());
+        (twitter, workers(jobs, endpoints))
+    }
+
+    fn workers(jobs: JobStore, endpoints: ApiEndpoints) -> Workers {
+        let token_store = MemoryTokenStore::default();
+        token_store
+            .save_access_token(USER, &access_token(), SystemTime::now())
+            .unwrap();
+        let https = HttpsConnector::new(1).unwrap();
+        Workers {
+            jobs,
+            token_store: Box::leak(Box::new(token_store)),
+            consumer_token: Box::leak(Box::new(KeyPair::new("consumer", "consumer secret"))),
+            endpoints: Box::leak(Box::new(endpoints)),
+            events: Arc::new(JobEventBus::default()),
+            client: ScheduledClient::new(
+                Client::builder().build::<_, Body>(https),
+                Arc::new(SystemClock),
+            ),
+        }
+    }
+
+    /// Remove the user from `OWNER`'s and `BLOCKED_OWNER`'s lists, crashing once `OWNER` reaches
+    /// `crash_at`, and then reconcile as if the server had restarted. Returns where `OWNER` got
+    /// to afterwards, once it's been checked that nobody is left blocked who shouldn't be.
+    fn crash_and_reconcile(crash_at: Step) -> (JobOwner, SharedTwitter) {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let (twitter, workers) = start_workers(&mut runtime);
+        let jobs = workers.jobs.clone();
+
+        let already_blocked: BTreeSet<u64> = [BLOCKED_OWNER].iter().cloned().collect();
+        let refollow: BTreeSet<u64> = [OWNER].iter().cloned().collect();
+        let job_id = jobs
+            .create_job(USER, vec![OWNER, BLOCKED_OWNER], &refollow)
+            .unwrap();
+
+        // Nothing more gets recorded once we've crashed.
+        let journal = workers.journal(job_id);
+        let crashing_twitter = twitter.clone();
+        let crashing_journal: Journal = Arc::new(move |owner_id, step| {
+            let mut twitter = crashing_twitter.lock().unwrap();
+            if twitter.down {
+                return Err(ErrorKind::OtherError("crashed".to_owned()).into());
+            }
+            journal(owner_id, step)?;
+            if owner_id == OWNER && step == crash_at {
+                match step {
+                    // The worst case is that the block went through just before the crash.
+                    Step::Blocking => twitter.down_after_blocking = Some(OWNER),
+                    _ => twitter.down = true,
+                }
+            }
+            Ok(())
+        });
+        let report: Reporter = Arc::new(|_| {});
+        // The results are never recorded, as that would happen after the crash.
+        let _ = runtime.block_on(removal::remove_batch(
+            vec![OWNER, BLOCKED_OWNER],
+            &already_blocked,
+            &refollow,
+            workers.consumer_token,
+            &access_token(),
+            workers.endpoints,
+            &workers.client,
+            &report,
+            &crashing_journal,
+        ));
+        assert!(twitter.lock().unwrap().down);
+
+        twitter.lock().unwrap().down = false;
+        let blocks_before = twitter.lock().unwrap().blocks.len();
+        assert_eq!(workers.reconcile().unwrap(), 1);
+        assert_eq!(workers.reconcile().unwrap(), 0);
+        {
+            let twitter = twitter.lock().unwrap();
+            assert_eq!(twitter.blocked, already_blocked);
+            assert_eq!(twitter.blocks.len(), blocks_before);
+            assert!(!twitter.unblocks.contains(&BLOCKED_OWNER));
+        }
+
+        (job_owner(&jobs, job_id, OWNER), twitter)
+    }
+
+    fn job_owner(jobs: &JobStore, job_id: i64, owner_id: u64) -> JobOwner {
+        jobs.job_owners(job_id)
+            .unwrap()
+            .into_iter()
+            .find(|owner| owner.owner_id == owner_id)
+            .unwrap()
+    }
+
+    /// Once they've been unblocked the user is off their lists, and can follow them again.
+    fn assert_removed_and_followed(owner: &JobOwner, twitter: &SharedTwitter) {
+        assert_eq!(owner.state, OwnerState::Removed);
+        assert_eq!(owner.refollow, Some(RefollowState::Restored));
+        assert!(twitter.lock().unwrap().following.contains(&OWNER));
+    }
+
+    #[test]
+    fn reconciling_a_crash_while_blocking_does_the_owner_again() {
+        let (owner, _) = crash_and_reconcile(Step::Blocking);
+        assert_eq!(owner.state, OwnerState::Pending);
+        assert_eq!(owner.refollow, Some(RefollowState::Wanted));
+    }
+
+    #[test]
+    fn reconciling_a_crash_once_blocked_unblocks_the_owner() {
+        let (owner, twitter) = crash_and_reconcile(Step::Blocked);
+        assert_removed_and_followed(&owner, &twitter);
+    }
+
+    #[test]
+    fn reconciling_a_crash_while_unblocking_unblocks_the_owner() {
+        let (owner, twitter) = crash_and_reconcile(Step::Unblocking);
+        assert_removed_and_followed(&owner, &twitter);
+    }
+
+    #[test]
+    fn reconciling_a_crash_while_following_follows_the_owner() {
+        let (owner, twitter) = crash_and_reconcile(Step::Following);
+        assert_removed_and_followed(&owner, &twitter);
+    }
+
+    #[test]
+    fn reconciling_retries_failed_unblocks_once_the_job_is_done() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let (twitter, workers) = start_workers(&mut runtime);
+        let jobs = workers.jobs.clone();
+        let job_id = jobs
+            .create_job(USER, vec![OWNER], &BTreeSet::new())
+            .unwrap();
+        jobs.claim_next_job().unwrap().unwrap();
+        twitter.lock().unwrap().blocked.insert(OWNER);
+        jobs.set_owner_state(
+            job_id,
+            OWNER,
+            OwnerState::Blocked,
+            Some("unblocking failed"),
+        )
+        .unwrap();
+
+        // Its worker might still be going.
+        assert_eq!(workers.reconcile().unwrap(), 0);
+        assert!(twitter.lock().unwrap().blocked.contains(&OWNER));
+
+        jobs.finish_job(job_id, JobState::Finished).unwrap();
+        assert_eq!(workers.reconcile().unwrap(), 1);
+        assert!(!twitter.lock().unwrap().blocked.contains(&OWNER));
+        let owner = job_owner(&jobs, job_id, OWNER);
+        assert_eq!(owner.state, OwnerState::Removed);
+        assert_eq!(owner.error, None);
+        assert!(!jobs.has_unfinished_job(USER).unwrap());
+    }
+
+    /// An owner of a finished job, left in `state` by a crash or a failed unblock.
+    fn finished_job_with_owner(workers: &Workers, state: OwnerState) -> i64 {
+        let jobs = &workers.jobs;
+        let job_id = jobs
+            .create_job(USER, vec![OWNER], &BTreeSet::new())
+            .unwrap();
+        jobs.claim_next_job().unwrap().unwrap();
+        jobs.set_owner_state(job_id, OWNER, state, None).unwrap();
+        jobs.finish_job(job_id, JobState::Finished).unwrap();
+        job_id
+    }
+
+    #[test]
+    fn reconciling_a_crash_while_blocking_requeues_a_finished_job() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let (_, workers) = start_workers(&mut runtime);
+        let job_id = finished_job_with_owner(&workers, OwnerState::Blocking);
+
+        assert_eq!(workers.reconcile().unwrap(), 1);
+        let owner = job_owner(&workers.jobs, job_id, OWNER);
+        assert_eq!(owner.state, OwnerState::Pending);
+        let job = workers.jobs.get_job(job_id).unwrap().unwrap();
+        assert_eq!(job.state, JobState::Queued);
+    }
+
+    #[test]
+    fn reconciling_a_missing_owner_counts_as_unblocking_them() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let (twitter, workers) = start_workers(&mut runtime);
+        twitter.lock().unwrap().missing.insert(OWNER);
+        let job_id = finished_job_with_owner(&workers, OwnerState::Blocked);
+
+        assert_eq!(workers.reconcile().unwrap(), 1);
+        let owner = job_owner(&workers.jobs, job_id, OWNER);
+        assert_eq!(owner.state, OwnerState::Removed);
+        assert!(!workers.jobs.has_unfinished_job(USER).unwrap());
+    }
+
+    #[test]
+    fn reconciling_gives_up_on_owners_that_keep_failing() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let (twitter, workers) = start_workers(&mut runtime);
+        twitter.lock().unwrap().blocked.insert(OWNER);
+        twitter.lock().unwrap().unblock_fails.insert(OWNER);
+        let job_id = finished_job_with_owner(&workers, OwnerState::Blocked);
+
+        for _ in 1..MAX_RECONCILE_ATTEMPTS {
+            assert_eq!(workers.reconcile().unwrap(), 0);
+        }
+        assert_eq!(
+            job_owner(&workers.jobs, job_id, OWNER).state,
+            OwnerState::Blocked
+        );
+        assert!(workers.jobs.has_unfinished_job(USER).unwrap());
+
+        assert_eq!(workers.reconcile().unwrap(), 0);
+        let owner = job_owner(&workers.jobs, job_id, OWNER);
+        assert_eq!(owner.state, OwnerState::Failed);
+        assert!(owner.error.unwrap().contains("may still be blocked"));
+        assert!(!workers.jobs.has_unfinished_job(USER).unwrap());
+        assert!(workers.jobs.interrupted_owners().unwrap().is_empty());
+    }
+}
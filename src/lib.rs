@@ -0,0 +1,10 @@
+#![feature(futures_api)]
+
+//! Talking to twitter: signing requests, sending them within its rate limits and taking a user off
+//! an owner's lists. Shared by the server, `fake_twitter` and the tests.
+
+pub mod egg_mode_2;
+pub mod error;
+pub mod job_events;
+pub mod removal;
+pub mod scheduler;
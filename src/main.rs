@@ -1,56 +1,137 @@
 #![feature(futures_api, async_await, await_macro)]
 
+use bytes::Bytes;
 use egg_mode;
 use egg_mode::KeyPair;
 use failchain::ResultExt;
 use futures::compat::Future01CompatExt;
 use futures::future::{FutureExt, TryFutureExt};
+use futures::stream::StreamExt;
 use futures::Future;
+use futures01::Future as Future01;
 use http::Uri;
-use hyper::client::{Client, HttpConnector};
+use hyper::client::Client;
 use hyper::{Request, Response, StatusCode};
 use hyper_tls::HttpsConnector;
 use lazy_static::lazy_static;
-use std::collections::HashMap;
-use std::env;
-use std::sync::Mutex;
-use tera::{compile_templates, Context, Tera, Value};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tera::{Context, Tera, Value};
 use url::form_urlencoded;
 
-mod egg_mode_2;
-mod error;
+mod config;
+mod crypto;
+mod csrf;
+mod db;
+mod jobs;
+mod metrics;
+mod retention;
+mod schedules;
+mod sessions;
+mod snapshots;
+mod token_store;
+
+use de_list_server::{egg_mode_2, error, job_events, removal, scheduler};
+use job_events::{JobEvent, JobEventBus};
+use scheduler::ScheduledClient;
+use token_store::TokenStore;
 
 lazy_static! {
-    pub static ref TERA: Tera = {
-        let mut tera = compile_templates!("templates/**/*");
-        tera.autoescape_on(vec!["html"]);
-        tera
-    };
+    /// Loaded from the config file, environment and command line, run with `--help` for details.
+    pub static ref CONFIG: config::Config = config::Config::load_or_exit();
 
-    pub static ref CLIENT_POOL: Client<HttpsConnector<HttpConnector>> = {
-        let https = HttpsConnector::new(4).unwrap();
+    pub static ref TERA: Tera = config::or_exit(CONFIG.templates());
+
+    /// For requests made while someone's waiting on a response, so won't wait long for a rate
+    /// limit window to reset.
+    pub static ref CLIENT_POOL: ScheduledClient = {
+        let https = config::or_exit(HttpsConnector::new(4).chain_err(|| {
+            error::ErrorKind::OtherError("setting up tls".to_owned())
+        }));
         let client = Client::builder()
             .build::<_, hyper::Body>(https);
-        client
+        ScheduledClient::new(client, Arc::new(scheduler::SystemClock))
+            .with_max_wait(Some(MAX_REQUEST_RATE_LIMIT_WAIT))
     };
 
-    pub static ref CONSUMER_TOKEN: KeyPair = {
-        let consumer_key = env::var("CONSUMER_KEY").unwrap();
-        let consumer_secret = env::var("CONSUMER_SECRET").unwrap();
-        KeyPair::new(consumer_key, consumer_secret)
-    };
+    pub static ref DB: db::Database = config::or_exit(db::open(&CONFIG.database_path));
 
-    // FIXME: just in memory for now
-    // probably shouldn't also have a single lock too
-    pub static ref OAUTH_TOKENS: Mutex<HashMap<String, KeyPair>> = {
-        Mutex::new(HashMap::new())
+    pub static ref KEYRING: Arc<crypto::Keyring> = Arc::new(config::or_exit(CONFIG.keyring()));
+
+    pub static ref TOKEN_STORE: Box<dyn TokenStore> = {
+        match CONFIG.token_store {
+            config::TokenStoreKind::Memory => Box::new(token_store::MemoryTokenStore::default()),
+            config::TokenStoreKind::Sqlite => {
+                let store = token_store::SqliteTokenStore::new(DB.clone(), KEYRING.clone());
+                let resealed = config::or_exit(store.reseal_secrets());
+                if resealed > 0 {
+                    log::info!("Resealed {} token secrets with the current master key", resealed);
+                }
+                Box::new(store)
+            }
+        }
     };
+
+    pub static ref SESSIONS: sessions::SessionStore = sessions::SessionStore::new(
+        DB.clone(),
+        config::or_exit(CONFIG.session_signing_key()),
+        CONFIG.session_ttl,
+        CONFIG.is_https(),
+    );
+
+    pub static ref JOBS: jobs::JobStore = jobs::JobStore::new(DB.clone());
+
+    pub static ref SCHEDULES: schedules::ScheduleStore = schedules::ScheduleStore::new(DB.clone());
+
+    pub static ref SNAPSHOTS: snapshots::SnapshotStore = snapshots::SnapshotStore::new(DB.clone());
+
+    pub static ref JOB_EVENTS: Arc<JobEventBus> = Arc::new(JobEventBus::default());
+}
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_REQUEST_RATE_LIMIT_WAIT: Duration = Duration::from_secs(30);
+
+/// Pending removals can be started for as long as the session that was shown them lasts.
+fn pending_removal_cutoff() -> SystemTime {
+    SystemTime::now() - CONFIG.session_ttl
+}
+
+fn save_oauth_token(oauth_token: &KeyPair) -> error::Result<()> {
+    TOKEN_STORE.save_request_token(oauth_token, SystemTime::now())?;
+    metrics::REQUEST_TOKENS_ISSUED.increment();
+    Ok(())
 }
-const CALLBACK_URL: &'static str = "http://localhost:3000/sign-in-with-twitter";
 
-fn save_oauth_token(oauth_token: &KeyPair) {
-    let mut map = OAUTH_TOKENS.lock().unwrap();
-    map.insert(oauth_token.key.clone().into_owned(), oauth_token.clone());
+/// Periodically throw away request tokens for logins that were started but never finished,
+/// sessions that have expired, and removals that were shown but never started.
+fn spawn_sweeper() -> std::io::Result<thread::JoinHandle<()>> {
+    let ttl = CONFIG.request_token_ttl;
+    thread::Builder::new()
+        .name("sweeper".to_owned())
+        .spawn(move || loop {
+            thread::sleep(SWEEP_INTERVAL);
+            match TOKEN_STORE.delete_request_tokens_before(SystemTime::now() - ttl) {
+                Ok(0) => (),
+                Ok(expired) => {
+                    log::info!("Swept {} expired request tokens", expired);
+                    metrics::REQUEST_TOKENS_EXPIRED.increment_by(expired);
+                }
+                Err(e) => log::error!("Could not sweep expired request tokens: {:?}", e),
+            }
+            match SESSIONS.delete_expired() {
+                Ok(0) => (),
+                Ok(expired) => log::info!("Swept {} expired sessions", expired),
+                Err(e) => log::error!("Could not sweep expired sessions: {:?}", e),
+            }
+            match JOBS.delete_pending_removals_before(pending_removal_cutoff()) {
+                Ok(0) => (),
+                Ok(expired) => log::info!("Swept {} expired pending removals", expired),
+                Err(e) => log::error!("Could not sweep expired pending removals: {:?}", e),
+            }
+        })
 }
 
 fn redirect_response(redirect_url: &str) -> Result<Response<http_service::Body>, error::Error> {
@@ -69,16 +150,21 @@ fn redirect_response(redirect_url: &str) -> Result<Response<http_service::Body>,
 fn redirect_to_twitter_authenticate(
     _context: tide::Context<()>,
 ) -> impl futures::Future<Output = Result<Response<http_service::Body>, error::Error>> {
-    let key_pair_future =
-        egg_mode_2::request_token(&CONSUMER_TOKEN, CALLBACK_URL, &CLIENT_POOL).compat();
+    let key_pair_future = egg_mode_2::request_token(
+        &CONFIG.consumer_token,
+        CONFIG.callback_url(),
+        &CONFIG.api_endpoints,
+        &CLIENT_POOL,
+    )
+    .compat();
 
     key_pair_future.map(|try_oauth_token| {
         try_oauth_token.and_then(|oauth_token| {
-            save_oauth_token(&oauth_token);
+            save_oauth_token(&oauth_token)?;
 
             let redirect_url = format!(
                 "{}?oauth_token={}",
-                egg_mode_2::AUTHENTICATE,
+                CONFIG.api_endpoints.url(egg_mode_2::Endpoint::Authenticate),
                 oauth_token.key
             );
 
@@ -87,51 +173,165 @@ fn redirect_to_twitter_authenticate(
     })
 }
 
-fn parse_oauth_token_and_verifier(uri: &Uri) -> Result<(String, String), error::Error> {
+/// Twitter sends the user back to us either having logged in, or with `denied` if they clicked
+/// cancel instead.
+enum Callback {
+    Authorized {
+        oauth_token: String,
+        oauth_verifier: String,
+    },
+    Denied {
+        oauth_token: String,
+    },
+}
+
+fn parse_callback(uri: &Uri) -> Result<Callback, error::Error> {
     let mut oauth_token_option = None;
     let mut oauth_verifier_option = None;
+    let mut denied_option = None;
 
     for query in uri.query() {
         for (key, value) in form_urlencoded::parse(query.as_bytes()) {
             match key.as_ref() {
                 "oauth_token" => oauth_token_option = Some(value.into_owned()),
                 "oauth_verifier" => oauth_verifier_option = Some(value.into_owned()),
+                "denied" => denied_option = Some(value.into_owned()),
                 _ => (),
             }
         }
     }
 
-    let oauth_token =
-        oauth_token_option.ok_or_else(|| error::ErrorKind::OtherError("".to_owned().into()));
-    let oauth_verifier =
-        oauth_verifier_option.ok_or_else(|| error::ErrorKind::OtherError("".to_owned().into()));
-    Ok((oauth_token?, oauth_verifier?))
+    if let Some(oauth_token) = denied_option {
+        return Ok(Callback::Denied { oauth_token });
+    }
+
+    match (oauth_token_option, oauth_verifier_option) {
+        (Some(oauth_token), Some(oauth_verifier)) => Ok(Callback::Authorized {
+            oauth_token,
+            oauth_verifier,
+        }),
+        (oauth_token, oauth_verifier) => {
+            let missing = [
+                ("oauth_token", oauth_token),
+                ("oauth_verifier", oauth_verifier),
+            ]
+            .iter()
+            .filter(|(_, value)| value.is_none())
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>();
+            let kind = error::ErrorKind::MissingQueryParams(missing.join(", "));
+            Err(kind.into())
+        }
+    }
 }
 
+/// Consumes the request token, so a callback url can't be used more than once.
 fn get_oauth_keypair(oauth_token: &str) -> error::Result<KeyPair> {
-    let map = OAUTH_TOKENS.lock().map_err(|e| -> error::Error {
-        let kind = error::ErrorKind::OtherError("Could not get lock for OAUTH_TOKENS".to_owned());
-        kind.into()
-    })?;
-    let keypair = map.get(oauth_token).ok_or_else(|| -> error::Error {
-        let kind =
-            error::ErrorKind::OtherError("Did not find oauth token in tokens map".to_owned());
-        kind.into()
-    })?;
-    Ok(keypair.clone())
+    let pending = TOKEN_STORE.take_request_token(oauth_token)?;
+    let pending = pending
+        .ok_or_else(|| -> error::Error { error::ErrorKind::UnknownOrExpiredToken.into() })?;
+
+    let age = pending.created_at.elapsed().unwrap_or_default();
+    if age > CONFIG.request_token_ttl {
+        metrics::REQUEST_TOKENS_EXPIRED.increment();
+        return Err(error::ErrorKind::UnknownOrExpiredToken.into());
+    }
+
+    metrics::REQUEST_TOKENS_CONSUMED.increment();
+    Ok(pending.keypair)
+}
+
+/// A list on the logged in page, along with whether its owner will stay blocked afterwards.
+#[derive(Serialize)]
+struct PreviewList {
+    #[serde(flatten)]
+    list: egg_mode_2::TwitterList,
+    already_blocked: bool,
+    following: bool,
+    followed_by: bool,
 }
 
+/// Shows every list that the user will be removed from, grouped by owner, so that they can check
+/// before starting. Owners that the user has already blocked are marked, as they won't be
+/// unblocked afterwards, and so are owners that they follow or are followed by, as blocking
+/// breaks follows both ways. `relationships` is `None` if we couldn't find out who they follow.
 fn logged_in_response(
-    list_count: usize,
-    removal_url: &str,
+    lists: Vec<egg_mode_2::TwitterList>,
+    already_blocked: &BTreeSet<u64>,
+    relationships: Option<&BTreeMap<u64, egg_mode_2::Relationship>>,
+    owner_count: usize,
+    removal_id: &str,
+    session: &sessions::Session,
 ) -> error::Result<Response<http_service::Body>> {
+    let relationship = |owner_id| {
+        relationships
+            .and_then(|relationships| relationships.get(&owner_id).cloned())
+            .unwrap_or_default()
+    };
+    let mut lists = lists
+        .into_iter()
+        .map(|list| {
+            let relationship = relationship(list.user.id);
+            PreviewList {
+                already_blocked: already_blocked.contains(&list.user.id),
+                following: relationship.following,
+                followed_by: relationship.followed_by,
+                list,
+            }
+        })
+        .collect::<Vec<_>>();
+    lists.sort_by(|a, b| {
+        let a_owner = a.list.user.screen_name.to_lowercase();
+        let b_owner = b.list.user.screen_name.to_lowercase();
+        (a_owner, &a.list.name).cmp(&(b_owner, &b.list.name))
+    });
+
     let mut context = Context::new();
-    context.insert("list_count", &Value::String(list_count.to_string()));
-    context.insert("removal_url", &Value::String(removal_url.to_owned()));
-    let response = Response::new(http_service::Body::from(
-        TERA.render("logged_in.html", &context).unwrap(),
-    ));
-    Ok(response)
+    context.insert("list_count", &lists.len());
+    context.insert("owner_count", &owner_count);
+    context.insert("relationships_known", &relationships.is_some());
+    let (following_count, followed_by_count) = relationships.map_or((0, 0), |relationships| {
+        let following = relationships.values().filter(|r| r.following).count();
+        let followed_by = relationships.values().filter(|r| r.followed_by).count();
+        (following, followed_by)
+    });
+    context.insert("following_count", &following_count);
+    context.insert("followed_by_count", &followed_by_count);
+    context.insert("lists", &lists);
+    context.insert("removal_id", &Value::String(removal_id.to_owned()));
+    insert_account_context(&mut context, session)?;
+    let body = TERA
+        .render("logged_in.html", &context)
+        .chain_err(|| error::ErrorKind::OtherError("rendering logged_in.html".to_owned()))?;
+    Ok(Response::new(http_service::Body::from(body)))
+}
+
+/// What `account.html` needs: the CSRF token for its forms, and how long we'll keep the user's
+/// access token so that they know what we hold.
+fn insert_account_context(context: &mut Context, session: &sessions::Session) -> error::Result<()> {
+    context.insert("csrf_token", &SESSIONS.csrf_token(session)?);
+    let keep_until_revoked = TOKEN_STORE
+        .stored_access_token(session.user_id)?
+        .map_or(false, |stored| stored.keep_until_revoked);
+    context.insert("keep_until_revoked", &keep_until_revoked);
+    let retention_days = match CONFIG.token_retention {
+        config::TokenRetention::AfterJob => 0,
+        config::TokenRetention::Days(days) => days,
+    };
+    context.insert("retention_days", &retention_days);
+    Ok(())
+}
+
+/// The user clicked cancel on twitter, so forget about their login and reassure them.
+fn cancelled_response(oauth_token: &str) -> error::Result<Response<http_service::Body>> {
+    if TOKEN_STORE.take_request_token(oauth_token)?.is_some() {
+        metrics::LOGINS_CANCELLED.increment();
+    }
+
+    let body = TERA
+        .render("cancelled.html", &Context::new())
+        .chain_err(|| error::ErrorKind::OtherError("rendering cancelled.html".to_owned()))?;
+    Ok(Response::new(http_service::Body::from(body)))
 }
 
 fn accept_twitter_authentication_3(
@@ -139,60 +339,715 @@ fn accept_twitter_authentication_3(
 ) -> impl futures::Future<Output = Result<Response<http_service::Body>, error::Error>> {
     log::trace!("accept_twitter_authentication");
 
-    let (oauth_token, oauth_verifier) = match parse_oauth_token_and_verifier(context.uri()) {
-        Ok(x) => x,
-        Err(e) => panic!(),
-        // return futures01::future::Either::A(futures01::failed(e)),
-    };
-
-    let oauth_keypair = match get_oauth_keypair(&oauth_token) {
-        Ok(x) => x,
-        Err(e) => panic!(),
-        // return futures01::future::Either::A(futures01::failed(e)),
+    let (oauth_keypair, oauth_verifier) = match parse_callback(context.uri()) {
+        Ok(Callback::Authorized {
+            oauth_token,
+            oauth_verifier,
+        }) => match get_oauth_keypair(&oauth_token) {
+            Ok(oauth_keypair) => (oauth_keypair, oauth_verifier),
+            Err(e) => return futures::future::Either::Left(futures::future::err(e)),
+        },
+        Ok(Callback::Denied { oauth_token }) => {
+            let response = cancelled_response(&oauth_token);
+            return futures::future::Either::Left(futures::future::ready(response));
+        }
+        Err(e) => return futures::future::Either::Left(futures::future::err(e)),
     };
 
     let fut = egg_mode_2::access_token(
-        &CONSUMER_TOKEN,
+        &CONFIG.consumer_token,
         &oauth_keypair,
         oauth_verifier,
+        &CONFIG.api_endpoints,
         &CLIENT_POOL,
     )
     .compat()
     .and_then(|(access_token, user_id)| {
-        egg_mode_2::get_owners_of_first_75_lists(
-            user_id,
-            &CONSUMER_TOKEN,
-            &access_token,
+        futures::future::ready(logged_in_redirect(user_id, &access_token))
+    });
+    futures::future::Either::Right(fut)
+}
+
+/// Remember the user's access token and give them a session, then send them on to their lists.
+fn logged_in_redirect(
+    user_id: u64,
+    access_token: &KeyPair,
+) -> error::Result<Response<http_service::Body>> {
+    TOKEN_STORE.save_access_token(user_id, access_token, SystemTime::now())?;
+    let session = SESSIONS.create(user_id)?;
+    let mut response = redirect_response(&CONFIG.url("lists"))?;
+    response
+        .headers_mut()
+        .insert(hyper::header::SET_COOKIE, SESSIONS.set_cookie(&session)?);
+    Ok(response)
+}
+
+/// The session from the request's cookie, for pages that only make sense once logged in.
+fn current_session(context: &tide::Context<()>) -> error::Result<sessions::Session> {
+    SESSIONS
+        .from_headers(context.request().headers())?
+        .ok_or_else(|| error::ErrorKind::NotLoggedIn.into())
+}
+
+/// Logged in users go straight to their lists, everyone else has to log in with twitter first.
+fn home(
+    context: tide::Context<()>,
+) -> impl futures::Future<Output = Result<Response<http_service::Body>, error::Error>> {
+    match SESSIONS.from_headers(context.request().headers()) {
+        Ok(Some(_)) => {
+            let response = redirect_response(&CONFIG.url("lists"));
+            futures::future::Either::Left(futures::future::ready(response))
+        }
+        Ok(None) => futures::future::Either::Right(redirect_to_twitter_authenticate(context)),
+        Err(e) => futures::future::Either::Left(futures::future::err(e)),
+    }
+}
+
+/// Every list that the logged in user is on, for them to choose which to be removed from.
+async fn lists_page(context: tide::Context<()>) -> error::Result<Response<http_service::Body>> {
+    let session = current_session(&context)?;
+    let user_id = session.user_id;
+    let access_token = TOKEN_STORE
+        .get_access_token(user_id)?
+        .ok_or_else(|| -> error::Error { error::ErrorKind::NotLoggedIn.into() })?;
+
+    let memberships = egg_mode_2::get_memberships(
+        user_id,
+        &CONFIG.consumer_token,
+        &access_token,
+        &CONFIG.api_endpoints,
+        &CLIENT_POOL,
+    );
+    let blocked_ids = egg_mode_2::get_blocked_ids(
+        &CONFIG.consumer_token,
+        &access_token,
+        &CONFIG.api_endpoints,
+        &CLIENT_POOL,
+    );
+    // Not being able to look up follows shouldn't stop anyone removing themselves, it just
+    // means we can't warn them or follow anyone again afterwards.
+    let lookup_token = access_token.clone();
+    let memberships = memberships.and_then(move |lists| {
+        let owners = egg_mode_2::list_owners(&lists);
+        egg_mode_2::get_relationships(
+            &owners,
+            &CONFIG.consumer_token,
+            &lookup_token,
+            &CONFIG.api_endpoints,
             &CLIENT_POOL,
         )
-        .compat()
-        .map_ok(|lists| logged_in_response(lists.len(), "foo.com").unwrap())
+        .then(move |relationships| {
+            let relationships = relationships
+                .map_err(|e| log::warn!("Could not look up friendships: {:?}", e))
+                .ok();
+            Ok::<_, error::Error>((lists, relationships))
+        })
+    });
+    let ((lists, relationships), already_blocked) = await!(memberships.join(blocked_ids).compat())?;
+    if let Err(e) = SNAPSHOTS.save(user_id, &lists, SystemTime::now()) {
+        log::warn!("Could not save snapshot of {}: {:?}", user_id, e);
+    }
+
+    let owners = egg_mode_2::list_owners(&lists);
+    let owner_count = owners.len();
+    let following = relationships
+        .iter()
+        .flat_map(|relationships| relationships.iter())
+        .filter(|(_, relationship)| relationship.following)
+        .map(|(&owner_id, _)| owner_id)
+        .collect();
+    let removal_id = JOBS.save_pending_removal(&jobs::PendingRemoval {
+        user_id,
+        owners,
+        following,
+    })?;
+    logged_in_response(
+        lists,
+        &already_blocked,
+        relationships.as_ref(),
+        owner_count,
+        &removal_id,
+        &session,
+    )
+}
+
+/// What the user chose on the logged in page.
+struct RemovalForm {
+    removal_id: String,
+    owners: BTreeSet<u64>,
+    /// Whether to follow the owners they follow again afterwards.
+    refollow: bool,
+}
+
+fn parse_removal_form(body: &str) -> error::Result<RemovalForm> {
+    let mut removal_id = None;
+    let mut owners = BTreeSet::new();
+    let mut refollow = false;
+    for (key, value) in form_urlencoded::parse(body.as_bytes()) {
+        match key.as_ref() {
+            "removal_id" => removal_id = Some(value.into_owned()),
+            "refollow" => refollow = true,
+            "owner_id" => {
+                let owner_id = value.parse().chain_err(|| {
+                    error::ErrorKind::OtherError(format!("Invalid owner_id {}", value))
+                })?;
+                owners.insert(owner_id);
+            }
+            _ => {}
+        }
+    }
+
+    let removal_id = removal_id.ok_or_else(|| -> error::Error {
+        let kind = error::ErrorKind::OtherError("No removal_id in form body".to_owned());
+        kind.into()
+    })?;
+    if owners.is_empty() {
+        return Err(error::ErrorKind::NothingSelected.into());
+    }
+    Ok(RemovalForm {
+        removal_id,
+        owners,
+        refollow,
+    })
+}
+
+fn job_started_response(job_id: i64) -> error::Result<Response<http_service::Body>> {
+    let mut context = Context::new();
+    context.insert("job_id", &job_id);
+    let body = TERA
+        .render("job_started.html", &context)
+        .chain_err(|| error::ErrorKind::OtherError("rendering job_started.html".to_owned()))?;
+    Ok(Response::new(http_service::Body::from(body)))
+}
+
+/// The body of a form that `CsrfMiddleware` has checked.
+fn form_body(context: &mut tide::Context<()>) -> error::Result<String> {
+    let csrf::FormBody(body) = context
+        .extensions_mut()
+        .remove::<csrf::FormBody>()
+        .ok_or_else(|| -> error::Error { error::ErrorKind::InvalidCsrfToken.into() })?;
+    Ok(body)
+}
+
+async fn remove_from_lists(
+    mut context: tide::Context<()>,
+) -> error::Result<Response<http_service::Body>> {
+    log::trace!("remove_from_lists");
+
+    let session = current_session(&context)?;
+    let body = form_body(&mut context)?;
+    // Check the form before taking the pending removal, so that the user can go back and fix it.
+    let form = parse_removal_form(&body)?;
+    let pending_removal = JOBS
+        .take_pending_removal(&form.removal_id, session.user_id, pending_removal_cutoff())?
+        .ok_or_else(|| -> error::Error { error::ErrorKind::RemovalExpired.into() })?;
+
+    // Only ever remove owners that we showed the user, whatever the form says.
+    let owners = pending_removal.owners.intersection(&form.owners).cloned();
+    let refollow = if form.refollow {
+        pending_removal.following
+    } else {
+        BTreeSet::new()
+    };
+    let job_id = JOBS.create_job(pending_removal.user_id, owners, &refollow)?;
+    log::info!("Queued job {}", job_id);
+    job_started_response(job_id)
+}
+
+fn job_id_param(context: &tide::Context<()>) -> error::Result<i64> {
+    context.param("id").map_err(|_| {
+        let kind = error::ErrorKind::NotFound("job id is not a number".to_owned());
+        kind.into()
+    })
+}
+
+fn load_job(job_id: i64) -> error::Result<jobs::Job> {
+    JOBS.get_job(job_id)?.ok_or_else(|| {
+        let kind = error::ErrorKind::NotFound(format!("job {}", job_id));
+        kind.into()
+    })
+}
+
+/// The job in the url, as long as it's the logged in user's. Anyone else's is treated as not
+/// existing, so that nobody can find out about other people's jobs.
+fn load_users_job(context: &tide::Context<()>) -> error::Result<jobs::Job> {
+    let session = current_session(context)?;
+    let job = load_job(job_id_param(context)?)?;
+    if job.user_id != session.user_id {
+        let kind = error::ErrorKind::NotFound(format!("job {} of another user", job.id));
+        return Err(kind.into());
+    }
+    Ok(job)
+}
+
+async fn job_page(context: tide::Context<()>) -> error::Result<Response<http_service::Body>> {
+    let session = current_session(&context)?;
+    let job = load_users_job(&context)?;
+    let (done, total) = JOBS.progress(job.id)?;
+
+    let mut tera_context = Context::new();
+    tera_context.insert("job_id", &job.id);
+    tera_context.insert("state", job.state.as_str());
+    tera_context.insert("done", &done);
+    tera_context.insert("total", &total);
+    tera_context.insert("owners", &JOBS.job_owners(job.id)?);
+    insert_account_context(&mut tera_context, &session)?;
+    let body = TERA
+        .render("job.html", &tera_context)
+        .chain_err(|| error::ErrorKind::OtherError("rendering job.html".to_owned()))?;
+    Ok(Response::new(http_service::Body::from(body)))
+}
+
+/// Log the user out everywhere and revoke their access token, so that we can't do anything on
+/// their account any more. If they want to be forgotten, their job history goes too.
+async fn log_out(
+    context: tide::Context<()>,
+    forget: bool,
+) -> error::Result<Response<http_service::Body>> {
+    let user_id = current_session(&context)?.user_id;
+    if JOBS.has_unfinished_job(user_id)? {
+        return Err(error::ErrorKind::JobInProgress.into());
+    }
+
+    // Even if twitter won't revoke the token we still delete our copy, but tell the user so that
+    // they can revoke it themselves.
+    let revoked = match TOKEN_STORE.get_access_token(user_id)? {
+        Some(access_token) => {
+            let invalidated = await!(egg_mode_2::invalidate_token_compat(
+                &CONFIG.consumer_token,
+                &access_token,
+                &CONFIG.api_endpoints,
+                &CLIENT_POOL,
+            ));
+            let already_revoked = |e: &error::Error| match e.kind() {
+                error::ErrorKind::TwitterApiError(e) => e.is_invalid_token(),
+                _ => false,
+            };
+            match invalidated {
+                Ok(()) => true,
+                // They've already revoked it themselves.
+                Err(ref e) if already_revoked(e) => true,
+                Err(e) => {
+                    log::warn!("Could not invalidate access token of {}: {:?}", user_id, e);
+                    false
+                }
+            }
+        }
+        None => true,
+    };
+    TOKEN_STORE.delete_access_token(user_id)?;
+    JOBS.delete_user_pending_removals(user_id)?;
+    SESSIONS.delete_user_sessions(user_id)?;
+    SCHEDULES.delete(user_id)?;
+    if forget {
+        let jobs = JOBS.delete_user_jobs(user_id)?;
+        let snapshots = SNAPSHOTS.delete_user_snapshots(user_id)?;
+        log::info!(
+            "Forgot user {} with {} jobs and {} snapshots",
+            user_id,
+            jobs,
+            snapshots
+        );
+    } else {
+        log::info!("Logged out user {}", user_id);
+    }
+
+    let mut tera_context = Context::new();
+    tera_context.insert("revoked", &revoked);
+    tera_context.insert("forgotten", &forget);
+    let body = TERA
+        .render("logged_out.html", &tera_context)
+        .chain_err(|| error::ErrorKind::OtherError("rendering logged_out.html".to_owned()))?;
+    let mut response = Response::new(http_service::Body::from(body));
+    response
+        .headers_mut()
+        .insert(hyper::header::SET_COOKIE, SESSIONS.clear_cookie()?);
+    Ok(response)
+}
+
+/// A list from the user's latest snapshot, along with whether their recurring removals leave them
+/// on it.
+#[derive(Serialize)]
+struct ScheduleList {
+    #[serde(flatten)]
+    list: snapshots::SnapshotList,
+    kept: bool,
+}
+
+/// Where the user sets up, pauses or cancels their recurring removal, and chooses whose lists it
+/// leaves them on from the lists they were on when we last looked.
+async fn settings_page(context: tide::Context<()>) -> error::Result<Response<http_service::Body>> {
+    let session = current_session(&context)?;
+    let schedule = SCHEDULES.get(session.user_id)?;
+    let mut kept_owners = SCHEDULES.kept_owners(session.user_id)?;
+    let lists = SNAPSHOTS
+        .latest_lists(session.user_id)?
+        .into_iter()
+        .map(|list| ScheduleList {
+            kept: kept_owners.contains(&list.owner_id),
+            list,
+        })
+        .collect::<Vec<_>>();
+    // Anyone they chose to stay on the lists of who isn't shown is kept as they are.
+    for list in &lists {
+        kept_owners.remove(&list.list.owner_id);
+    }
+
+    let mut tera_context = Context::new();
+    let hours_until_next_run = schedule.as_ref().map(|schedule| {
+        schedule
+            .next_run_at
+            .duration_since(SystemTime::now())
+            .map(|until| (until.as_secs() + 59 * 60) / (60 * 60))
+            .unwrap_or(0)
     });
-    fut
+    tera_context.insert("hours_until_next_run", &hours_until_next_run);
+    // What the form starts with, a weekly run is what most people want.
+    let when = schedule.as_ref().map_or(
+        schedules::When {
+            frequency: schedules::Frequency::Weekly,
+            weekday: 0,
+            hour: 9,
+        },
+        |schedule| schedule.when,
+    );
+    tera_context.insert("when", &when);
+    tera_context.insert(
+        "refollow",
+        &schedule.as_ref().map_or(true, |schedule| schedule.refollow),
+    );
+    tera_context.insert("schedule", &schedule);
+    tera_context.insert("lists", &lists);
+    tera_context.insert("other_kept_owners", &kept_owners);
+    tera_context.insert("weekdays", &schedules::WEEKDAYS);
+    tera_context.insert("hours", &(0..24).collect::<Vec<u8>>());
+    insert_account_context(&mut tera_context, &session)?;
+    let body = TERA
+        .render("settings.html", &tera_context)
+        .chain_err(|| error::ErrorKind::OtherError("rendering settings.html".to_owned()))?;
+    Ok(Response::new(http_service::Body::from(body)))
 }
 
-fn or_internal_service_error<T>(
+/// Which lists the user was added to or left between the last two times we looked them up.
+async fn changes_page(context: tide::Context<()>) -> error::Result<Response<http_service::Body>> {
+    let session = current_session(&context)?;
+    let changes = SNAPSHOTS.changes(session.user_id)?;
+
+    let mut tera_context = Context::new();
+    tera_context.insert("compared", &changes.is_some());
+    if let Some(changes) = changes {
+        let hours = |duration: Duration| duration.as_secs() / (60 * 60);
+        let hours_between = changes
+            .taken_at
+            .duration_since(changes.since)
+            .unwrap_or_default();
+        tera_context.insert("hours_between", &hours(hours_between));
+        tera_context.insert(
+            "hours_ago",
+            &hours(changes.taken_at.elapsed().unwrap_or_default()),
+        );
+        tera_context.insert("added", &changes.added);
+        tera_context.insert("removed", &changes.removed);
+    }
+    insert_account_context(&mut tera_context, &session)?;
+    let body = TERA
+        .render("changes.html", &tera_context)
+        .chain_err(|| error::ErrorKind::OtherError("rendering changes.html".to_owned()))?;
+    Ok(Response::new(http_service::Body::from(body)))
+}
+
+/// What the user chose for their recurring removal on the settings page.
+struct ScheduleForm {
+    when: schedules::When,
+    refollow: bool,
+    /// Owners whose lists to leave the user on.
+    kept_owners: BTreeSet<u64>,
+}
+
+fn parse_schedule_form(body: &str) -> error::Result<ScheduleForm> {
+    let mut frequency = None;
+    let mut weekday = None;
+    let mut hour = None;
+    let mut refollow = false;
+    let mut kept_owners = BTreeSet::new();
+    for (key, value) in form_urlencoded::parse(body.as_bytes()) {
+        match key.as_ref() {
+            "frequency" => frequency = Some(value.into_owned()),
+            "weekday" => weekday = Some(value.into_owned()),
+            "hour" => hour = Some(value.into_owned()),
+            "refollow" => refollow = true,
+            "keep_owner_id" => {
+                let owner_id = value.parse().chain_err(|| {
+                    error::ErrorKind::OtherError(format!("Invalid keep_owner_id {}", value))
+                })?;
+                kept_owners.insert(owner_id);
+            }
+            _ => {}
+        }
+    }
+
+    let number = |name: &str, value: Option<String>| -> Result<u8, String> {
+        let value = value.ok_or_else(|| format!("no {}", name))?;
+        value
+            .parse()
+            .map_err(|_| format!("{} {} is not a number", name, value))
+    };
+    let when = frequency
+        .ok_or_else(|| "no frequency".to_owned())
+        .and_then(|frequency| frequency.parse::<schedules::Frequency>())
+        .and_then(|frequency| {
+            schedules::When::new(
+                frequency,
+                number("weekday", weekday)?,
+                number("hour", hour)?,
+            )
+        })
+        .map_err(|problem| -> error::Error {
+            let kind = error::ErrorKind::OtherError(format!("Invalid schedule: {}", problem));
+            kind.into()
+        })?;
+    Ok(ScheduleForm {
+        when,
+        refollow,
+        kept_owners,
+    })
+}
+
+#[derive(Clone, Copy)]
+enum ScheduleAction {
+    Save,
+    Pause,
+    Resume,
+    Cancel,
+}
+
+/// Change the user's recurring removal, then go back to the settings page. Recurring runs need
+/// the user's access token, so it's kept until they cancel whatever the retention policy says.
+async fn update_schedule(
+    mut context: tide::Context<()>,
+    action: ScheduleAction,
+) -> error::Result<Response<http_service::Body>> {
+    let user_id = current_session(&context)?.user_id;
+    match action {
+        ScheduleAction::Save => {
+            let form = parse_schedule_form(&form_body(&mut context)?)?;
+            if TOKEN_STORE.stored_access_token(user_id)?.is_none() {
+                return Err(error::ErrorKind::NotLoggedIn.into());
+            }
+            TOKEN_STORE.set_keep_until_revoked(user_id, true)?;
+            SCHEDULES.save(user_id, form.when, form.refollow, &form.kept_owners)?;
+            log::info!("Saved schedule of {}", user_id);
+        }
+        ScheduleAction::Pause => SCHEDULES.pause(user_id)?,
+        ScheduleAction::Resume => SCHEDULES.resume(user_id)?,
+        ScheduleAction::Cancel => {
+            SCHEDULES.delete(user_id)?;
+            TOKEN_STORE.set_keep_until_revoked(user_id, false)?;
+            log::info!("Cancelled schedule of {}", user_id);
+        }
+    }
+    redirect_response(&CONFIG.url("settings"))
+}
+
+fn server_sent_event(event: &JobEvent) -> Bytes {
+    let data = serde_json::to_string(event).unwrap_or_else(|e| {
+        log::error!("Could not serialise {:?}: {:?}", event, e);
+        "{}".to_owned()
+    });
+    Bytes::from(format!("data: {}\n\n", data))
+}
+
+/// A stream of the job's events, starting with everything that's happened so far. The stream ends
+/// once the job does.
+async fn job_events(context: tide::Context<()>) -> error::Result<Response<http_service::Body>> {
+    let job_id = load_users_job(&context)?.id;
+
+    // Subscribe before looking at where the job's got to, so that nothing can happen in between
+    // that we'd miss. At worst some owners are sent twice.
+    let live = JOB_EVENTS.subscribe(job_id);
+    let job = load_job(job_id)?;
+    let (done, total) = JOBS.progress(job_id)?;
+
+    let mut so_far: Vec<JobEvent> = JOBS
+        .job_owners(job_id)?
+        .iter()
+        .flat_map(jobs::JobOwner::to_events)
+        .collect();
+    so_far.push(JobEvent::Progress {
+        done,
+        total,
+        eta_seconds: None,
+    });
+    // A finished job won't send anything else, so don't wait on it.
+    let live = if job.state.is_done() {
+        so_far.push(JobEvent::Finished {
+            succeeded: job.state == jobs::JobState::Finished,
+        });
+        None
+    } else {
+        Some(live)
+    };
+
+    let events = futures::stream::iter(so_far)
+        .chain(futures::stream::iter(live).flatten())
+        .map(|event| Ok::<_, std::io::Error>(server_sent_event(&event)));
+    let mut response = Response::new(http_service::Body::from_stream(events));
+    let headers = response.headers_mut();
+    headers.insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("text/event-stream"),
+    );
+    headers.insert(
+        hyper::header::CACHE_CONTROL,
+        hyper::header::HeaderValue::from_static("no-cache"),
+    );
+    Ok(response)
+}
+
+fn metrics_response(request: Request<hyper::Body>) -> Response<hyper::Body> {
+    if request.uri().path() == "/metrics" {
+        Response::new(hyper::Body::from(metrics::render()))
+    } else {
+        let mut response = Response::new(hyper::Body::empty());
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        response
+    }
+}
+
+/// Serve `/metrics` on its own address, so that it can be kept off the public one.
+fn spawn_metrics_server(address: SocketAddr) -> std::io::Result<thread::JoinHandle<()>> {
+    let server = hyper::Server::try_bind(&address)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+        .serve(|| hyper::service::service_fn_ok(metrics_response))
+        .map_err(|e| log::error!("Metrics server failed: {}", e));
+    log::info!("Serving metrics on {}", address);
+    thread::Builder::new()
+        .name("metrics".to_owned())
+        .spawn(move || hyper::rt::run(server))
+}
+
+fn error_response(e: &error::Error) -> Response<http_service::Body> {
+    let kind = e.kind();
+    let status = kind.status_code();
+    if status.is_server_error() {
+        log::error!("Unhandled error: {:?}", e);
+    } else {
+        log::info!("Request failed: {:?}", e);
+    }
+
+    let mut context = Context::new();
+    context.insert("title", kind.title());
+    context.insert("advice", &kind.advice());
+    let body = TERA
+        .render("error.html", &context)
+        .unwrap_or_else(|render_error| {
+            log::error!("Could not render error page: {:?}", render_error);
+            format!("{}. {}", kind.title(), kind.advice())
+        });
+
+    let mut response = Response::new(http_service::Body::from(body));
+    *response.status_mut() = status;
+    response
+}
+
+fn or_error_page<T>(
     fut: impl Future<Output = Result<T, error::Error>>,
 ) -> impl Future<Output = Result<T, Response<http_service::Body>>> {
-    fut.map_err(|e| {
-        log::error!("Unhandled error: {:?}", e);
-        let mut response = Response::new(http_service::Body::from(
-            "Internal Server Error: unhandled exception",
-        ));
-        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-        response
-    })
+    fut.map_err(|e| error_response(&e))
 }
 
 fn main() -> std::io::Result<()> {
-    env_logger::init();
+    // Exits with everything that's wrong with the config if it isn't valid.
+    lazy_static::initialize(&CONFIG);
+    if CONFIG.check_config {
+        print!("{}", *CONFIG);
+        println!("Configuration is valid");
+        return Ok(());
+    }
+
+    env_logger::Builder::new()
+        .filter_level(CONFIG.log_level)
+        .init();
+
+    // Load the templates, open the database, run any migrations and reseal any secrets that were
+    // sealed with a previous master key now rather than on the first request, exiting with what's
+    // wrong if any of them can't be done.
+    lazy_static::initialize(&CLIENT_POOL);
+    lazy_static::initialize(&TERA);
+    lazy_static::initialize(&DB);
+    lazy_static::initialize(&TOKEN_STORE);
+    lazy_static::initialize(&SESSIONS);
+
+    spawn_sweeper()?;
+    if let Some(metrics_address) = CONFIG.metrics_address {
+        spawn_metrics_server(metrics_address)?;
+    }
+
+    let requeued = config::or_exit(JOBS.requeue_interrupted());
+    if requeued > 0 {
+        log::info!("Requeued {} jobs that were interrupted", requeued);
+    }
+    jobs::Workers {
+        jobs: JOBS.clone(),
+        token_store: &**TOKEN_STORE,
+        consumer_token: &CONFIG.consumer_token,
+        endpoints: &CONFIG.api_endpoints,
+        events: JOB_EVENTS.clone(),
+        client: CLIENT_POOL.with_max_wait(None),
+    }
+    .start(CONFIG.worker_count)?;
+    retention::TokenSweeper {
+        token_store: &**TOKEN_STORE,
+        sessions: &SESSIONS,
+        jobs: JOBS.clone(),
+        retention: CONFIG.token_retention,
+        session_ttl: CONFIG.session_ttl,
+        consumer_token: &CONFIG.consumer_token,
+        endpoints: &CONFIG.api_endpoints,
+        client: CLIENT_POOL.with_max_wait(None),
+    }
+    .start()?;
+    schedules::ScheduleRunner {
+        schedules: SCHEDULES.clone(),
+        jobs: JOBS.clone(),
+        snapshots: SNAPSHOTS.clone(),
+        token_store: &**TOKEN_STORE,
+        consumer_token: &CONFIG.consumer_token,
+        endpoints: &CONFIG.api_endpoints,
+        client: CLIENT_POOL.with_max_wait(None),
+    }
+    .start()?;
 
     let mut app = tide::App::new(());
+    app.middleware(csrf::CsrfMiddleware::new(&SESSIONS));
 
-    app.at("/")
-        .get(|c| or_internal_service_error(redirect_to_twitter_authenticate(c)));
+    app.at("/").get(|c| or_error_page(home(c)));
     app.at("/sign-in-with-twitter")
-        .get(|c| or_internal_service_error(accept_twitter_authentication_3(c)));
+        .get(|c| or_error_page(accept_twitter_authentication_3(c)));
+    app.at("/lists").get(|c| or_error_page(lists_page(c)));
+    app.at("/remove")
+        .post(|c| or_error_page(remove_from_lists(c)));
+    app.at("/logout").post(|c| or_error_page(log_out(c, false)));
+    app.at("/forget-me")
+        .post(|c| or_error_page(log_out(c, true)));
+    app.at("/changes").get(|c| or_error_page(changes_page(c)));
+    app.at("/settings").get(|c| or_error_page(settings_page(c)));
+    app.at("/settings/schedule")
+        .post(|c| or_error_page(update_schedule(c, ScheduleAction::Save)));
+    app.at("/settings/schedule/pause")
+        .post(|c| or_error_page(update_schedule(c, ScheduleAction::Pause)));
+    app.at("/settings/schedule/resume")
+        .post(|c| or_error_page(update_schedule(c, ScheduleAction::Resume)));
+    app.at("/settings/schedule/cancel")
+        .post(|c| or_error_page(update_schedule(c, ScheduleAction::Cancel)));
+    app.at("/jobs/:id").get(|c| or_error_page(job_page(c)));
+    app.at("/jobs/:id/events")
+        .get(|c| or_error_page(job_events(c)));
 
-    app.serve("127.0.0.1:3000")
+    log::info!(
+        "Listening on {}, reachable at {}",
+        CONFIG.bind_address,
+        CONFIG.public_url
+    );
+    app.serve(CONFIG.bind_address)
 }
@@ -0,0 +1,68 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A counter that only ever goes up, exposed on `/metrics`.
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicUsize,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str) -> Counter {
+        Counter {
+            name,
+            help,
+            value: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn increment_by(&self, amount: usize) {
+        self.value.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub fn increment(&self) {
+        self.increment_by(1);
+    }
+}
+
+pub static REQUEST_TOKENS_ISSUED: Counter = Counter::new(
+    "delist_request_tokens_issued_total",
+    "Request tokens handed out to start a login",
+);
+pub static REQUEST_TOKENS_CONSUMED: Counter = Counter::new(
+    "delist_request_tokens_consumed_total",
+    "Request tokens used by a login callback",
+);
+pub static REQUEST_TOKENS_EXPIRED: Counter = Counter::new(
+    "delist_request_tokens_expired_total",
+    "Request tokens that expired without being used",
+);
+pub static LOGINS_CANCELLED: Counter = Counter::new(
+    "delist_logins_cancelled_total",
+    "Logins where the user clicked cancel on twitter",
+);
+
+static COUNTERS: &[&Counter] = &[
+    &REQUEST_TOKENS_ISSUED,
+    &REQUEST_TOKENS_CONSUMED,
+    &REQUEST_TOKENS_EXPIRED,
+    &LOGINS_CANCELLED,
+];
+
+/// All the counters in the prometheus text format.
+pub fn render() -> String {
+    let mut out = String::new();
+    for counter in COUNTERS {
+        writeln!(out, "# HELP {} {}", counter.name, counter.help).unwrap();
+        writeln!(out, "# TYPE {} counter", counter.name).unwrap();
+        writeln!(
+            out,
+            "{} {}",
+            counter.name,
+            counter.value.load(Ordering::Relaxed)
+        )
+        .unwrap();
+    }
+    out
+}
@@ -0,0 +1,248 @@
+use crate::egg_mode_2::{self, ApiEndpoints, Follow};
+use crate::error::*;
+use crate::job_events::{JobEvent, Reporter};
+use crate::scheduler::ScheduledClient;
+use egg_mode::KeyPair;
+use futures01::future::{self, Either};
+use futures01::Future as Future01;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+/// How many owners to work on at once.
+pub const BATCH_SIZE: usize = 15;
+
+/// Why a follow of a protected account isn't restored straight away.
+pub const REQUESTED_REASON: &'static str =
+    "They're protected, so they'll need to approve a follow request";
+
+/// How we got off a single owner's lists.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Outcome {
+    /// Blocked and then unblocked, so nothing else has changed.
+    Unblocked,
+    /// The user had already blocked them, so they've been left blocked.
+    KeptBlocked,
+}
+
+/// The points in getting off an owner's lists that are recorded as they're reached, so that after
+/// a crash we know what might need finishing off.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Step {
+    /// About to block them.
+    Blocking,
+    /// They're blocked, and need unblocking.
+    Blocked,
+    /// About to unblock them.
+    Unblocking,
+    /// About to follow them again.
+    Following,
+}
+
+impl Step {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Step::Blocking => "blocking",
+            Step::Blocked => "blocked",
+            Step::Unblocking => "unblocking",
+            Step::Following => "following",
+        }
+    }
+}
+
+/// Where each owner's steps are recorded. Nothing is done if recording that we're about to block
+/// someone fails, as we'd have no way of knowing to unblock them.
+pub type Journal = Arc<dyn Fn(u64, Step) -> Result<()> + Send + Sync>;
+
+/// What happened when we tried to get off a single owner's lists.
+#[derive(Debug)]
+pub struct OwnerRemoval {
+    pub owner_id: u64,
+    pub result: Result<Outcome>,
+    /// Unblocking them failed, so they need unblocking later.
+    pub still_blocked: bool,
+    /// How following them again went, if we tried.
+    pub refollow: Option<Result<Follow>>,
+}
+
+/// For every owner in the batch, block and then immediately unblock them. Blocking someone removes
+/// you from all of their lists, and unblocking them straight away means that's the only lasting
+/// effect.
+///
+/// Owners in `already_blocked` are never unblocked, as the user blocked them for a reason. They're
+/// still blocked again, which is harmless and makes sure the user is off their lists.
+///
+/// Blocking someone also breaks any follows between them and the user, so owners in `refollow`
+/// are followed again once they've been unblocked. Only the user's side can be restored, as we
+/// can't make them follow the user again.
+///
+/// Every owner in the batch is done concurrently. A failure for one owner doesn't stop the others
+/// from being processed, it's just reported in that owner's `OwnerRemoval`. Each step is also
+/// reported to `report` as soon as it happens, and recorded in `journal` as it's reached.
+pub fn remove_batch(
+    owners: impl IntoIterator<Item = u64>,
+    already_blocked: &BTreeSet<u64>,
+    refollow: &BTreeSet<u64>,
+    consumer_token: &KeyPair,
+    access_token: &KeyPair,
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
+    report: &Reporter,
+    journal: &Journal,
+) -> impl Future01<Item = Vec<OwnerRemoval>, Error = Error> {
+    let removals = owners.into_iter().map(|owner_id| {
+        remove_from_owners_lists(
+            owner_id,
+            already_blocked.contains(&owner_id),
+            refollow.contains(&owner_id),
+            consumer_token,
+            access_token,
+            endpoints,
+            client,
+            report.clone(),
+            journal.clone(),
+        )
+    });
+    future::join_all(removals.collect::<Vec<_>>())
+}
+
+/// Recording a step after it's been taken can't undo it, so a failure is only logged.
+fn record_after(journal: &Journal, owner_id: u64, step: Step) {
+    if let Err(e) = journal(owner_id, step) {
+        log::error!(
+            "Could not record {} for {}: {:?}",
+            step.as_str(),
+            owner_id,
+            e
+        );
+    }
+}
+
+fn remove_from_owners_lists(
+    owner_id: u64,
+    already_blocked: bool,
+    refollow: bool,
+    consumer_token: &KeyPair,
+    access_token: &KeyPair,
+    endpoints: &ApiEndpoints,
+    client: &ScheduledClient,
+    report: Reporter,
+    journal: Journal,
+) -> impl Future01<Item = OwnerRemoval, Error = Error> {
+    let block_consumer_token = consumer_token.clone();
+    let block_access_token = access_token.clone();
+    let block_endpoints = endpoints.clone();
+    let block_client = client.clone();
+    let unblock_consumer_token = consumer_token.clone();
+    let unblock_access_token = access_token.clone();
+    let unblock_endpoints = endpoints.clone();
+    let unblock_client = client.clone();
+    let follow_consumer_token = consumer_token.clone();
+    let follow_access_token = access_token.clone();
+    let follow_endpoints = endpoints.clone();
+    let follow_client = client.clone();
+    let report_blocked = report.clone();
+    let report_follow = report.clone();
+    let block_journal = journal.clone();
+    let unblock_journal = journal.clone();
+
+    // Someone who's already blocked stays blocked whatever happens, so there's nothing to record.
+    future::lazy(move || {
+        if already_blocked {
+            Ok(())
+        } else {
+            block_journal(owner_id, Step::Blocking)
+        }
+    })
+    .and_then(move |()| {
+        egg_mode_2::block_user(
+            owner_id,
+            &block_consumer_token,
+            &block_access_token,
+            &block_endpoints,
+            &block_client,
+        )
+    })
+    .then(move |blocked| {
+        if let Err(e) = blocked {
+            return Either::A(future::ok((Err(e), false)));
+        }
+        report_blocked(JobEvent::Blocked { owner_id });
+        if already_blocked {
+            return Either::A(future::ok((Ok(Outcome::KeptBlocked), false)));
+        }
+
+        record_after(&unblock_journal, owner_id, Step::Blocked);
+        record_after(&unblock_journal, owner_id, Step::Unblocking);
+        let unblock = egg_mode_2::unblock_user(
+            owner_id,
+            &unblock_consumer_token,
+            &unblock_access_token,
+            &unblock_endpoints,
+            &unblock_client,
+        );
+        Either::B(unblock.then(|unblocked| match unblocked {
+            Ok(()) => Ok::<_, Error>((Ok(Outcome::Unblocked), false)),
+            Err(e) => Ok((Err(e), true)),
+        }))
+    })
+    .then(move |removed: Result<(Result<Outcome>, bool)>| {
+        let (result, still_blocked) = removed?;
+        match result {
+            Ok(Outcome::Unblocked) => report(JobEvent::Unblocked { owner_id }),
+            Ok(Outcome::KeptBlocked) => report(JobEvent::KeptBlocked { owner_id }),
+            Err(ref e) => {
+                log::warn!("Could not remove from lists of {}: {:?}", owner_id, e);
+                report(JobEvent::Failed {
+                    owner_id,
+                    error: e.to_string(),
+                });
+            }
+        }
+        Ok::<_, Error>((result, still_blocked))
+    })
+    .and_then(move |(result, still_blocked)| {
+        let unblocked = match result {
+            Ok(Outcome::Unblocked) => true,
+            _ => false,
+        };
+        if !(refollow && unblocked) {
+            return Either::A(future::ok(OwnerRemoval {
+                owner_id,
+                result,
+                still_blocked,
+                refollow: None,
+            }));
+        }
+
+        record_after(&journal, owner_id, Step::Following);
+        let follow = egg_mode_2::follow_user(
+            owner_id,
+            &follow_consumer_token,
+            &follow_access_token,
+            &follow_endpoints,
+            &follow_client,
+        );
+        Either::B(follow.then(move |follow| {
+            match follow {
+                Ok(Follow::Following) => report_follow(JobEvent::FollowRestored { owner_id }),
+                Ok(Follow::Requested) => report_follow(JobEvent::FollowNotRestored {
+                    owner_id,
+                    reason: REQUESTED_REASON.to_owned(),
+                }),
+                Err(ref e) => {
+                    log::warn!("Could not follow {} again: {:?}", owner_id, e);
+                    report_follow(JobEvent::FollowNotRestored {
+                        owner_id,
+                        reason: e.to_string(),
+                    });
+                }
+            }
+            Ok(OwnerRemoval {
+                owner_id,
+                result,
+                still_blocked,
+                refollow: Some(follow),
+            })
+        }))
+    })
+}
@@ -0,0 +1,116 @@
+use crate::config::TokenRetention;
+use crate::egg_mode_2::{self, ApiEndpoints};
+use crate::error::*;
+use crate::jobs::JobStore;
+use crate::scheduler::ScheduledClient;
+use crate::sessions::SessionStore;
+use crate::token_store::{StoredAccessToken, TokenStore};
+use egg_mode::KeyPair;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// How often to look for access tokens that we no longer need.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Discards access tokens once the retention policy says we no longer need them. They're revoked
+/// with twitter first, so that they're useless even if a copy of the database survives somewhere.
+/// The user is logged out at the same time, as nothing they're logged in for works without it.
+pub struct TokenSweeper {
+    pub token_store: &'static dyn TokenStore,
+    pub sessions: &'static SessionStore,
+    pub jobs: JobStore,
+    pub retention: TokenRetention,
+    /// Someone who's logged in but hasn't finished a removal yet needs their token for as long as
+    /// they stay logged in.
+    pub session_ttl: Duration,
+    pub consumer_token: &'static KeyPair,
+    pub endpoints: &'static ApiEndpoints,
+    pub client: ScheduledClient,
+}
+
+impl TokenSweeper {
+    pub fn start(self) -> std::io::Result<()> {
+        thread::Builder::new()
+            .name("token-sweeper".to_owned())
+            .spawn(move || {
+                let mut runtime = match tokio::runtime::current_thread::Runtime::new() {
+                    Ok(runtime) => runtime,
+                    Err(e) => {
+                        log::error!("Could not start token sweeper runtime: {:?}", e);
+                        return;
+                    }
+                };
+                loop {
+                    match self.sweep(&mut runtime) {
+                        Ok(0) => (),
+                        Ok(discarded) => log::info!("Discarded {} access tokens", discarded),
+                        Err(e) => log::error!("Could not sweep access tokens: {:?}", e),
+                    }
+                    thread::sleep(SWEEP_INTERVAL);
+                }
+            })?;
+        Ok(())
+    }
+
+    /// Discard every token that's been idle for longer than the policy allows, returning how many
+    /// there were.
+    fn sweep(&self, runtime: &mut tokio::runtime::current_thread::Runtime) -> Result<usize> {
+        let now = SystemTime::now();
+        let mut discarded = 0;
+        for token in self.token_store.stored_access_tokens()? {
+            let idle_since = match self.idle_since(&token)? {
+                Some(idle_since) => idle_since,
+                None => continue,
+            };
+            if idle_since + self.retention.duration() > now {
+                continue;
+            }
+            match self.discard(runtime, token.user_id) {
+                Ok(()) => discarded += 1,
+                Err(e) => log::error!(
+                    "Could not discard access token of {}: {:?}",
+                    token.user_id,
+                    e
+                ),
+            }
+        }
+        Ok(discarded)
+    }
+
+    /// When we stopped needing the token, or `None` if we still need it.
+    fn idle_since(&self, token: &StoredAccessToken) -> Result<Option<SystemTime>> {
+        if token.keep_until_revoked || self.jobs.has_unfinished_job(token.user_id)? {
+            return Ok(None);
+        }
+        let idle_since = match self.jobs.last_finished_at(token.user_id)? {
+            Some(finished_at) if finished_at >= token.saved_at => finished_at,
+            // They've logged in since their last job, so might still start another.
+            _ => token.saved_at + self.session_ttl,
+        };
+        Ok(Some(idle_since))
+    }
+
+    /// The token is deleted even if twitter won't revoke it, as we don't need it either way.
+    fn discard(
+        &self,
+        runtime: &mut tokio::runtime::current_thread::Runtime,
+        user_id: u64,
+    ) -> Result<()> {
+        if let Some(access_token) = self.token_store.get_access_token(user_id)? {
+            let invalidated = runtime.block_on(egg_mode_2::invalidate_token(
+                self.consumer_token,
+                &access_token,
+                self.endpoints,
+                &self.client,
+            ));
+            if let Err(e) = invalidated {
+                log::warn!("Could not invalidate access token of {}: {:?}", user_id, e);
+            }
+        }
+        // Logging them out first means that a failure here is retried on the next sweep.
+        self.sessions.delete_user_sessions(user_id)?;
+        self.token_store.delete_access_token(user_id)?;
+        log::info!("Discarded access token of {}", user_id);
+        Ok(())
+    }
+}
@@ -0,0 +1,449 @@
+use crate::egg_mode_2::RateLimit;
+use crate::error::*;
+use futures01::future::{self, Loop};
+use futures01::Future as Future01;
+use hyper::body::Body;
+use hyper::client::{Client, HttpConnector};
+use hyper::{Request, Response, StatusCode};
+use hyper_tls::HttpsConnector;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How many times to retry a request that twitter rejected with a 429.
+const MAX_RATE_LIMIT_RETRIES: u32 = 3;
+
+/// Twitter's rate limit windows are 15 minutes, so if a 429 doesn't say when the window resets
+/// then wait that long.
+const DEFAULT_WINDOW: Duration = Duration::from_secs(15 * 60);
+
+/// Where the scheduler gets the time from, so that tests don't have to wait for real windows.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    fn delay(&self, duration: Duration) -> Box<dyn Future01<Item = (), Error = Error> + Send>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn delay(&self, duration: Duration) -> Box<dyn Future01<Item = (), Error = Error> + Send> {
+        Box::new(
+            tokio::timer::Delay::new(Instant::now() + duration)
+                .chain_inspect_err_fut(|_| ErrorKind::OtherError("waiting for delay".to_owned())),
+        )
+    }
+}
+
+/// A clock that only moves when something waits on it, at which point it jumps straight to the
+/// end of the wait.
+#[cfg(test)]
+pub struct ManualClock {
+    now: Mutex<SystemTime>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new(now: SystemTime) -> Self {
+        ManualClock {
+            now: Mutex::new(now),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+
+    fn delay(&self, duration: Duration) -> Box<dyn Future01<Item = (), Error = Error> + Send> {
+        self.advance(duration);
+        Box::new(future::ok(()))
+    }
+}
+
+/// Rate limits are per endpoint and per access token.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+struct WindowKey {
+    endpoint: &'static str,
+    token: String,
+}
+
+/// Told which endpoint a request is waiting on and when it will carry on.
+pub type WaitListener = Arc<dyn Fn(&'static str, SystemTime) + Send + Sync>;
+
+type LoopFuture = Box<dyn Future01<Item = Loop<Response<Body>, u32>, Error = Error> + Send>;
+
+enum Reservation {
+    Go,
+    Wait(Duration),
+}
+
+/// A hyper client that keeps track of the `x-rate-limit-*` headers of every response, and holds
+/// back requests that would go over the limit until the window resets. Requests that get a 429
+/// anyway are retried once the window resets.
+///
+/// Clones share the same windows, so the same limits apply to all of them.
+#[derive(Clone)]
+pub struct ScheduledClient {
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    clock: Arc<dyn Clock>,
+    windows: Arc<Mutex<HashMap<WindowKey, RateLimit>>>,
+    max_wait: Option<Duration>,
+    wait_listener: Option<WaitListener>,
+}
+
+impl ScheduledClient {
+    pub fn new(client: Client<HttpsConnector<HttpConnector>, Body>, clock: Arc<dyn Clock>) -> Self {
+        ScheduledClient {
+            client,
+            clock,
+            windows: Arc::new(Mutex::new(HashMap::new())),
+            max_wait: None,
+            wait_listener: None,
+        }
+    }
+
+    /// Requests that would have to wait longer than `max_wait` for their window fail straight
+    /// away with `ErrorKind::RateLimited` instead, which is what you want while someone is waiting
+    /// on the other end of an http request. `None` means wait as long as it takes.
+    pub fn with_max_wait(&self, max_wait: Option<Duration>) -> Self {
+        ScheduledClient {
+            max_wait,
+            ..self.clone()
+        }
+    }
+
+    /// Calls `listener` every time a request has to wait for its window to reset.
+    pub fn with_wait_listener(&self, listener: WaitListener) -> Self {
+        ScheduledClient {
+            wait_listener: Some(listener),
+            ..self.clone()
+        }
+    }
+
+    /// Send a request to the given endpoint on behalf of the given token, once its window allows.
+    /// Requests get rebuilt for each attempt so that they can be signed with a fresh nonce.
+    pub fn request(
+        &self,
+        endpoint: &'static str,
+        token: &str,
+        make_request: impl Fn() -> Result<Request<Body>> + Send + 'static,
+    ) -> impl Future01<Item = Response<Body>, Error = Error> + Send {
+        let this = self.clone();
+        let key = WindowKey {
+            endpoint,
+            token: token.to_owned(),
+        };
+
+        future::loop_fn(0u32, move |retries| -> LoopFuture {
+            let wait = match this.reserve(&key) {
+                Ok(Reservation::Go) => None,
+                Ok(Reservation::Wait(wait)) => Some(wait),
+                Err(e) => return Box::new(future::err(e)),
+            };
+
+            if let Some(wait) = wait {
+                if this.max_wait.map_or(false, |max_wait| wait > max_wait) {
+                    return Box::new(future::err(this.rate_limited_error(&key)));
+                }
+                log::info!("Waiting {:?} for the {} rate limit window", wait, endpoint);
+                if let Some(ref listener) = this.wait_listener {
+                    listener(endpoint, this.clock.now() + wait);
+                }
+                return Box::new(
+                    this.clock
+                        .delay(wait)
+                        .map(move |()| Loop::Continue(retries)),
+                );
+            }
+
+            let request = match make_request() {
+                Ok(request) => request,
+                Err(e) => return Box::new(future::err(e)),
+            };
+            let this = this.clone();
+            let key = key.clone();
+            let response = this
+                .client
+                .request(request)
+                .chain_inspect_err_fut(move |_| {
+                    ErrorKind::TwitterRequestError(format!("requesting {}", endpoint))
+                })
+                .and_then(move |response| {
+                    let rate_limit = RateLimit::from_headers(response.headers());
+                    if response.status() == StatusCode::TOO_MANY_REQUESTS {
+                        this.exhaust(&key, rate_limit)?;
+                        if retries < MAX_RATE_LIMIT_RETRIES {
+                            log::info!("Rate limited by {}, will retry", endpoint);
+                            return Ok(Loop::Continue(retries + 1));
+                        }
+                    } else if let Some(rate_limit) = rate_limit {
+                        this.record(&key, rate_limit)?;
+                    }
+                    Ok(Loop::Break(response))
+                });
+            Box::new(response)
+        })
+    }
+
+    fn lock_windows(&self) -> Result<MutexGuard<HashMap<WindowKey, RateLimit>>> {
+        self.windows.lock().map_err(|_| {
+            let kind =
+                ErrorKind::OtherError("Could not get lock for rate limit windows".to_owned());
+            kind.into()
+        })
+    }
+
+    fn now_secs(&self) -> u64 {
+        self.clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .map(|dur| dur.as_secs())
+            .unwrap_or(0)
+    }
+
+    /// Take one request from the window, or say how long to wait until there's one free.
+    /// Windows we don't know about, or that have already reset, always let the request through.
+    fn reserve(&self, key: &WindowKey) -> Result<Reservation> {
+        let now = self.now_secs();
+        let mut windows = self.lock_windows()?;
+        match windows.get_mut(key) {
+            Some(window) if window.reset > now => {
+                if window.remaining > 0 {
+                    window.remaining -= 1;
+                    Ok(Reservation::Go)
+                } else {
+                    // Wait an extra second as twitter's clock won't be exactly the same as ours.
+                    Ok(Reservation::Wait(Duration::from_secs(
+                        window.reset - now + 1,
+                    )))
+                }
+            }
+            _ => Ok(Reservation::Go),
+        }
+    }
+
+    fn record(&self, key: &WindowKey, rate_limit: RateLimit) -> Result<()> {
+        let mut windows = self.lock_windows()?;
+        let window = windows.entry(key.clone()).or_insert(rate_limit);
+        // Requests we've reserved but that haven't come back yet aren't counted in the headers of
+        // the ones that have, so don't let an earlier response give those reservations back.
+        if window.reset == rate_limit.reset {
+            window.remaining = window.remaining.min(rate_limit.remaining);
+        } else {
+            *window = rate_limit;
+        }
+        Ok(())
+    }
+
+    fn exhaust(&self, key: &WindowKey, rate_limit: Option<RateLimit>) -> Result<()> {
+        let now = self.now_secs();
+        let rate_limit = rate_limit.unwrap_or(RateLimit {
+            limit: 0,
+            remaining: 0,
+            reset: now + DEFAULT_WINDOW.as_secs(),
+        });
+        let mut windows = self.lock_windows()?;
+        windows.insert(
+            key.clone(),
+            RateLimit {
+                remaining: 0,
+                // A reset that's already gone would let the retry straight through, into another
+                // 429, so always wait at least a second.
+                reset: rate_limit.reset.max(now + 1),
+                ..rate_limit
+            },
+        );
+        Ok(())
+    }
+
+    fn rate_limited_error(&self, key: &WindowKey) -> Error {
+        let rate_limit = self
+            .lock_windows()
+            .ok()
+            .and_then(|windows| windows.get(key).cloned());
+        let error = TwitterError {
+            status: StatusCode::TOO_MANY_REQUESTS.as_u16(),
+            errors: vec![],
+            rate_limit,
+        };
+        ErrorKind::RateLimited(error).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use failchain::ResultExt;
+    use hyper::service::service_fn_ok;
+    use hyper::Server;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const ENDPOINT: &'static str = "/1.1/test.json";
+
+    fn key() -> WindowKey {
+        WindowKey {
+            endpoint: ENDPOINT,
+            token: "token".to_owned(),
+        }
+    }
+
+    fn client(clock: Arc<ManualClock>) -> ScheduledClient {
+        let https = HttpsConnector::new(1).unwrap();
+        ScheduledClient::new(Client::builder().build::<_, Body>(https), clock)
+    }
+
+    fn secs(time: SystemTime) -> u64 {
+        time.duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    fn is_go(reservation: Reservation) -> bool {
+        match reservation {
+            Reservation::Go => true,
+            Reservation::Wait(_) => false,
+        }
+    }
+
+    /// Start a server that rate limits every request with a reset of `reset`, returning its url
+    /// and how many requests it's had.
+    fn rate_limited_server(
+        runtime: &mut tokio::runtime::Runtime,
+        reset: u64,
+    ) -> (String, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(move || {
+            let counter = counter.clone();
+            service_fn_ok(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Response::builder()
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .header("x-rate-limit-limit", "15")
+                    .header("x-rate-limit-remaining", "0")
+                    .header("x-rate-limit-reset", reset.to_string().as_str())
+                    .body(Body::empty())
+                    .unwrap()
+            })
+        });
+        let url = format!("http://{}{}", server.local_addr(), ENDPOINT);
+        runtime.spawn(server.map_err(|e| eprintln!("test server failed: {:?}", e)));
+        (url, requests)
+    }
+
+    fn get(url: String) -> impl Fn() -> Result<Request<Body>> + Send + 'static {
+        move || {
+            Request::get(url.as_str())
+                .body(Body::empty())
+                .chain_err(|| ErrorKind::OtherError("building test request".to_owned()))
+        }
+    }
+
+    #[test]
+    fn reserve_waits_once_the_window_is_used_up() {
+        let clock = Arc::new(ManualClock::new(SystemTime::now()));
+        let client = client(clock.clone());
+        let reset = secs(clock.now()) + 60;
+        let rate_limit = RateLimit {
+            limit: 15,
+            remaining: 2,
+            reset,
+        };
+        client.record(&key(), rate_limit).unwrap();
+
+        assert!(is_go(client.reserve(&key()).unwrap()));
+        assert!(is_go(client.reserve(&key()).unwrap()));
+        match client.reserve(&key()).unwrap() {
+            Reservation::Wait(wait) => assert_eq!(wait, Duration::from_secs(61)),
+            Reservation::Go => panic!("reserved more requests than the window had"),
+        }
+
+        clock.advance(Duration::from_secs(61));
+        assert!(is_go(client.reserve(&key()).unwrap()));
+    }
+
+    #[test]
+    fn record_never_gives_back_reservations_in_the_same_window() {
+        let clock = Arc::new(ManualClock::new(SystemTime::now()));
+        let client = client(clock.clone());
+        let reset = secs(clock.now()) + 60;
+        let rate_limit = |remaining, reset| RateLimit {
+            limit: 15,
+            remaining,
+            reset,
+        };
+
+        client.record(&key(), rate_limit(5, reset)).unwrap();
+        // A response that was sent before the last one came back after it.
+        client.record(&key(), rate_limit(8, reset)).unwrap();
+        assert_eq!(client.lock_windows().unwrap()[&key()].remaining, 5);
+
+        client.record(&key(), rate_limit(14, reset + 900)).unwrap();
+        assert_eq!(client.lock_windows().unwrap()[&key()].remaining, 14);
+    }
+
+    #[test]
+    fn exhaust_waits_even_if_the_reset_has_gone() {
+        let clock = Arc::new(ManualClock::new(SystemTime::now()));
+        let client = client(clock.clone());
+        let rate_limit = RateLimit {
+            limit: 15,
+            remaining: 0,
+            reset: secs(clock.now()) - 10,
+        };
+        client.exhaust(&key(), Some(rate_limit)).unwrap();
+        assert!(!is_go(client.reserve(&key()).unwrap()));
+    }
+
+    #[test]
+    fn too_many_requests_are_retried_up_to_the_limit() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let clock = Arc::new(ManualClock::new(SystemTime::now()));
+        let client = client(clock.clone());
+        let (url, requests) = rate_limited_server(&mut runtime, secs(clock.now()));
+
+        let response = runtime
+            .block_on(client.request(ENDPOINT, "token", get(url)))
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            requests.load(Ordering::SeqCst),
+            MAX_RATE_LIMIT_RETRIES as usize + 1
+        );
+        // Each retry waited for the window rather than going straight back.
+        assert!(clock.now() > SystemTime::now());
+    }
+
+    #[test]
+    fn waits_longer_than_max_wait_are_rate_limited() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let clock = Arc::new(ManualClock::new(SystemTime::now()));
+        let client = client(clock.clone()).with_max_wait(Some(Duration::from_secs(30)));
+        let (url, requests) = rate_limited_server(&mut runtime, secs(clock.now()));
+        let rate_limit = RateLimit {
+            limit: 15,
+            remaining: 0,
+            reset: secs(clock.now()) + 600,
+        };
+        client.record(&key(), rate_limit).unwrap();
+
+        let error = runtime
+            .block_on(client.request(ENDPOINT, "token", get(url)))
+            .err()
+            .unwrap();
+        match error.kind() {
+            ErrorKind::RateLimited(e) => assert_eq!(e.rate_limit, Some(rate_limit)),
+            other => panic!("expected to be rate limited, got {:?}", other),
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 0);
+    }
+}
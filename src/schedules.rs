@@ -0,0 +1,545 @@
+use crate::db::{self, Database};
+use crate::egg_mode_2::{self, ApiEndpoints};
+use crate::error::*;
+use crate::jobs::JobStore;
+use crate::scheduler::ScheduledClient;
+use crate::snapshots::SnapshotStore;
+use crate::token_store::TokenStore;
+use egg_mode::KeyPair;
+use failchain::ResultExt;
+use futures01::Future as Future01;
+use rusqlite::{params, OptionalExtension};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// How often to look for schedules that are due.
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+const SECS_PER_HOUR: i64 = 60 * 60;
+const SECS_PER_DAY: i64 = 24 * SECS_PER_HOUR;
+
+pub const WEEKDAYS: [&'static str; 7] = [
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+    "Sunday",
+];
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Frequency {
+    Daily,
+    Weekly,
+}
+
+impl Frequency {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Frequency::Daily => "daily",
+            Frequency::Weekly => "weekly",
+        }
+    }
+}
+
+impl FromStr for Frequency {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "daily" => Ok(Frequency::Daily),
+            "weekly" => Ok(Frequency::Weekly),
+            other => Err(format!("expected daily or weekly, got {}", other)),
+        }
+    }
+}
+
+/// When a recurring removal runs, like a cron entry of `0 hour * * *` or `0 hour * * weekday`.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Serialize)]
+pub struct When {
+    pub frequency: Frequency,
+    /// 0 is Monday. Only used by weekly schedules.
+    pub weekday: u8,
+    /// In UTC.
+    pub hour: u8,
+}
+
+impl When {
+    pub fn new(frequency: Frequency, weekday: u8, hour: u8) -> std::result::Result<When, String> {
+        if weekday as usize >= WEEKDAYS.len() {
+            return Err(format!("weekday must be 0 to 6, got {}", weekday));
+        }
+        if hour >= 24 {
+            return Err(format!("hour must be 0 to 23, got {}", hour));
+        }
+        Ok(When {
+            frequency,
+            weekday,
+            hour,
+        })
+    }
+
+    /// The first time it's due strictly after `after`.
+    pub fn next_after(self, after: SystemTime) -> SystemTime {
+        let after = db::to_timestamp(after);
+        let mut next = after - after % SECS_PER_DAY + i64::from(self.hour) * SECS_PER_HOUR;
+        if next <= after {
+            next += SECS_PER_DAY;
+        }
+        if self.frequency == Frequency::Weekly {
+            // The epoch was a Thursday.
+            let weekday = (next / SECS_PER_DAY + 3) % 7;
+            next += (i64::from(self.weekday) + 7 - weekday) % 7 * SECS_PER_DAY;
+        }
+        db::from_timestamp(next)
+    }
+}
+
+/// A user's recurring removal. Each run takes them off every list they're on at the time, apart
+/// from those of the owners they've chosen to stay on, see `ScheduleStore::kept_owners`.
+#[derive(Clone, Debug, Serialize)]
+pub struct Schedule {
+    pub user_id: u64,
+    pub when: When,
+    /// Whether to follow the owners they follow again afterwards.
+    pub refollow: bool,
+    pub paused: bool,
+    #[serde(skip)]
+    pub next_run_at: SystemTime,
+    pub last_job_id: Option<i64>,
+    /// Why the last run couldn't start, if it couldn't.
+    pub last_error: Option<String>,
+}
+
+/// At most one schedule per user, persisted so that they survive a restart.
+#[derive(Clone)]
+pub struct ScheduleStore {
+    db: Database,
+}
+
+type ScheduleRow = (
+    i64,
+    String,
+    i64,
+    i64,
+    bool,
+    bool,
+    i64,
+    Option<i64>,
+    Option<String>,
+);
+
+const SCHEDULE_COLUMNS: &'static str = "user_id, frequency, weekday, hour, refollow, paused, \
+                                        next_run_at, last_job_id, last_error";
+
+impl ScheduleStore {
+    pub fn new(db: Database) -> Self {
+        ScheduleStore { db }
+    }
+
+    pub fn get(&self, user_id: u64) -> Result<Option<Schedule>> {
+        let conn = db::lock(&self.db)?;
+        let row: Option<ScheduleRow> = conn
+            .query_row(
+                &format!(
+                    "SELECT {} FROM schedules WHERE user_id = ?1",
+                    SCHEDULE_COLUMNS
+                ),
+                params![user_id as i64],
+                schedule_row,
+            )
+            .optional()
+            .chain_err(|| ErrorKind::DatabaseError("loading schedule".to_owned()))?;
+        row.map(parse_schedule).transpose()
+    }
+
+    /// Set up the user's schedule, replacing any they already had, with its first run at the
+    /// next time it's due. Runs leave the user on the lists of everyone in `kept_owners`.
+    pub fn save(
+        &self,
+        user_id: u64,
+        when: When,
+        refollow: bool,
+        kept_owners: &BTreeSet<u64>,
+    ) -> Result<()> {
+        let next_run_at = when.next_after(SystemTime::now());
+        let mut conn = db::lock(&self.db)?;
+        let tx = conn
+            .transaction()
+            .chain_err(|| ErrorKind::DatabaseError("starting saving schedule".to_owned()))?;
+        tx.execute(
+            "INSERT OR REPLACE INTO schedules
+                 (user_id, frequency, weekday, hour, refollow, paused, next_run_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, 0, ?6)",
+            params![
+                user_id as i64,
+                when.frequency.as_str(),
+                i64::from(when.weekday),
+                i64::from(when.hour),
+                refollow,
+                db::to_timestamp(next_run_at)
+            ],
+        )
+        .chain_err(|| ErrorKind::DatabaseError("saving schedule".to_owned()))?;
+
+        tx.execute(
+            "DELETE FROM schedule_kept_owners WHERE user_id = ?1",
+            params![user_id as i64],
+        )
+        .chain_err(|| ErrorKind::DatabaseError("clearing kept owners".to_owned()))?;
+        for &owner_id in kept_owners {
+            tx.execute(
+                "INSERT INTO schedule_kept_owners (user_id, owner_id) VALUES (?1, ?2)",
+                params![user_id as i64, owner_id as i64],
+            )
+            .chain_err(|| ErrorKind::DatabaseError("adding kept owner".to_owned()))?;
+        }
+
+        tx.commit()
+            .chain_err(|| ErrorKind::DatabaseError("committing schedule".to_owned()))?;
+        Ok(())
+    }
+
+    /// The owners whose lists the user's recurring removals leave them on.
+    pub fn kept_owners(&self, user_id: u64) -> Result<BTreeSet<u64>> {
+        let conn = db::lock(&self.db)?;
+        let mut statement = conn
+            .prepare("SELECT owner_id FROM schedule_kept_owners WHERE user_id = ?1")
+            .chain_err(|| ErrorKind::DatabaseError("loading kept owners".to_owned()))?;
+        let owners: Vec<i64> = statement
+            .query_map(params![user_id as i64], |row| row.get(0))
+            .and_then(|rows| rows.collect())
+            .chain_err(|| ErrorKind::DatabaseError("loading kept owners".to_owned()))?;
+        Ok(owners.into_iter().map(|owner_id| owner_id as u64).collect())
+    }
+
+    pub fn pause(&self, user_id: u64) -> Result<()> {
+        let conn = db::lock(&self.db)?;
+        conn.execute(
+            "UPDATE schedules SET paused = 1 WHERE user_id = ?1",
+            params![user_id as i64],
+        )
+        .chain_err(|| ErrorKind::DatabaseError("pausing schedule".to_owned()))?;
+        Ok(())
+    }
+
+    /// Runs missed while it was paused are skipped, the next is whenever it's next due.
+    pub fn resume(&self, user_id: u64) -> Result<()> {
+        let schedule = match self.get(user_id)? {
+            Some(schedule) => schedule,
+            None => return Ok(()),
+        };
+        let next_run_at = schedule.when.next_after(SystemTime::now());
+        let conn = db::lock(&self.db)?;
+        conn.execute(
+            "UPDATE schedules SET paused = 0, next_run_at = ?1, last_error = NULL
+             WHERE user_id = ?2",
+            params![db::to_timestamp(next_run_at), user_id as i64],
+        )
+        .chain_err(|| ErrorKind::DatabaseError("resuming schedule".to_owned()))?;
+        Ok(())
+    }
+
+    /// Cancel the user's schedule, returning whether they had one.
+    pub fn delete(&self, user_id: u64) -> Result<bool> {
+        let mut conn = db::lock(&self.db)?;
+        let tx = conn
+            .transaction()
+            .chain_err(|| ErrorKind::DatabaseError("starting deleting schedule".to_owned()))?;
+        tx.execute(
+            "DELETE FROM schedule_kept_owners WHERE user_id = ?1",
+            params![user_id as i64],
+        )
+        .chain_err(|| ErrorKind::DatabaseError("deleting kept owners".to_owned()))?;
+        let deleted = tx
+            .execute(
+                "DELETE FROM schedules WHERE user_id = ?1",
+                params![user_id as i64],
+            )
+            .chain_err(|| ErrorKind::DatabaseError("deleting schedule".to_owned()))?;
+        tx.commit()
+            .chain_err(|| ErrorKind::DatabaseError("committing deleting schedule".to_owned()))?;
+        Ok(deleted > 0)
+    }
+
+    /// Every schedule that isn't paused and should have run by `now`.
+    fn due(&self, now: SystemTime) -> Result<Vec<Schedule>> {
+        let conn = db::lock(&self.db)?;
+        let mut statement = conn
+            .prepare(&format!(
+                "SELECT {} FROM schedules WHERE paused = 0 AND next_run_at <= ?1",
+                SCHEDULE_COLUMNS
+            ))
+            .chain_err(|| ErrorKind::DatabaseError("loading due schedules".to_owned()))?;
+        let rows: Vec<ScheduleRow> = statement
+            .query_map(params![db::to_timestamp(now)], schedule_row)
+            .and_then(|rows| rows.collect())
+            .chain_err(|| ErrorKind::DatabaseError("loading due schedules".to_owned()))?;
+        rows.into_iter().map(parse_schedule).collect()
+    }
+
+    /// Move the schedule on to its next run, recording how this one went. A schedule that can't
+    /// run without the user doing something is paused with `pause`, so that it doesn't fail
+    /// every time.
+    fn record_run(
+        &self,
+        schedule: &Schedule,
+        next_run_at: SystemTime,
+        result: &Result<Option<i64>>,
+        pause: bool,
+    ) -> Result<()> {
+        let (job_id, error) = match result {
+            Ok(job_id) => (job_id.or(schedule.last_job_id), None),
+            Err(e) => (schedule.last_job_id, Some(e.to_string())),
+        };
+        let conn = db::lock(&self.db)?;
+        conn.execute(
+            "UPDATE schedules
+             SET next_run_at = ?1, last_job_id = ?2, last_error = ?3, paused = paused OR ?4
+             WHERE user_id = ?5",
+            params![
+                db::to_timestamp(next_run_at),
+                job_id,
+                error,
+                pause,
+                schedule.user_id as i64
+            ],
+        )
+        .chain_err(|| ErrorKind::DatabaseError("recording schedule run".to_owned()))?;
+        Ok(())
+    }
+}
+
+fn schedule_row(row: &rusqlite::Row) -> ScheduleRow {
+    (
+        row.get(0),
+        row.get(1),
+        row.get(2),
+        row.get(3),
+        row.get(4),
+        row.get(5),
+        row.get(6),
+        row.get(7),
+        row.get(8),
+    )
+}
+
+fn parse_schedule(row: ScheduleRow) -> Result<Schedule> {
+    let (user_id, frequency, weekday, hour, refollow, paused, next_run_at, last_job_id, last_error) =
+        row;
+    let when = frequency
+        .parse()
+        .and_then(|frequency| When::new(frequency, weekday as u8, hour as u8))
+        .map_err(|e| -> Error {
+            ErrorKind::DatabaseError(format!("invalid schedule: {}", e)).into()
+        })?;
+    Ok(Schedule {
+        user_id: user_id as u64,
+        when,
+        refollow,
+        paused,
+        next_run_at: db::from_timestamp(next_run_at),
+        last_job_id,
+        last_error,
+    })
+}
+
+/// Queues up a removal job for each schedule when it's due, using the access token that the
+/// user's schedule keeps for us.
+pub struct ScheduleRunner {
+    pub schedules: ScheduleStore,
+    pub jobs: JobStore,
+    pub snapshots: SnapshotStore,
+    pub token_store: &'static dyn TokenStore,
+    pub consumer_token: &'static KeyPair,
+    pub endpoints: &'static ApiEndpoints,
+    pub client: ScheduledClient,
+}
+
+impl ScheduleRunner {
+    pub fn start(self) -> std::io::Result<()> {
+        thread::Builder::new()
+            .name("schedule-runner".to_owned())
+            .spawn(move || {
+                let mut runtime = match tokio::runtime::current_thread::Runtime::new() {
+                    Ok(runtime) => runtime,
+                    Err(e) => {
+                        log::error!("Could not start schedule runner runtime: {:?}", e);
+                        return;
+                    }
+                };
+                loop {
+                    if let Err(e) = self.run_due(&mut runtime) {
+                        log::error!("Could not run due schedules: {:?}", e);
+                    }
+                    thread::sleep(POLL_INTERVAL);
+                }
+            })?;
+        Ok(())
+    }
+
+    /// A problem with one schedule is logged, and doesn't stop the rest from running.
+    fn run_due(&self, runtime: &mut tokio::runtime::current_thread::Runtime) -> Result<()> {
+        let now = SystemTime::now();
+        for schedule in self.schedules.due(now)? {
+            if let Err(e) = self.run_due_schedule(runtime, &schedule, now) {
+                log::error!("Could not run schedule of {}: {:?}", schedule.user_id, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the schedule and move it on to its next run, recording anything that went wrong.
+    fn run_due_schedule(
+        &self,
+        runtime: &mut tokio::runtime::current_thread::Runtime,
+        schedule: &Schedule,
+        now: SystemTime,
+    ) -> Result<()> {
+        let access_token = self.token_store.get_access_token(schedule.user_id);
+        let lost_access = match access_token {
+            Ok(None) => true,
+            _ => false,
+        };
+        let result = match access_token {
+            Ok(Some(ref access_token)) => self.run_schedule(runtime, schedule, access_token),
+            Ok(None) => {
+                let message = "We no longer have access to your account, please log in again";
+                Err(ErrorKind::OtherError(message.to_owned()).into())
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(Some(job_id)) => {
+                log::info!("Queued job {} for schedule of {}", job_id, schedule.user_id)
+            }
+            Ok(None) => {}
+            Err(ref e) => log::warn!("Schedule of {} failed: {:?}", schedule.user_id, e),
+        }
+        let next_run_at = schedule.when.next_after(now);
+        self.schedules
+            .record_run(schedule, next_run_at, &result, lost_access)
+    }
+
+    /// Queue a job to take the user off every list they're on now, apart from those of the owners
+    /// they've chosen to stay on. Nothing is queued if there aren't any, or if their last job is
+    /// still going.
+    fn run_schedule(
+        &self,
+        runtime: &mut tokio::runtime::current_thread::Runtime,
+        schedule: &Schedule,
+        access_token: &KeyPair,
+    ) -> Result<Option<i64>> {
+        let user_id = schedule.user_id;
+        if self.jobs.has_unfinished_job(user_id)? {
+            log::info!(
+                "Skipping schedule of {} as their last job is still going",
+                user_id
+            );
+            return Ok(None);
+        }
+
+        let lists = runtime.block_on(egg_mode_2::get_memberships(
+            user_id,
+            self.consumer_token,
+            access_token,
+            self.endpoints,
+            &self.client,
+        ))?;
+        if let Err(e) = self.snapshots.save(user_id, &lists, SystemTime::now()) {
+            log::warn!("Could not save snapshot of {}: {:?}", user_id, e);
+        }
+        let kept_owners = self.schedules.kept_owners(user_id)?;
+        let owners: BTreeSet<u64> = egg_mode_2::list_owners(&lists)
+            .difference(&kept_owners)
+            .cloned()
+            .collect();
+        if owners.is_empty() {
+            return Ok(None);
+        }
+
+        // As when the user starts a removal themselves, not knowing who they follow shouldn't stop
+        // them being removed.
+        let refollow = if schedule.refollow {
+            let relationships = egg_mode_2::get_relationships(
+                &owners,
+                self.consumer_token,
+                access_token,
+                self.endpoints,
+                &self.client,
+            )
+            .map(|relationships| {
+                relationships
+                    .into_iter()
+                    .filter(|(_, relationship)| relationship.following)
+                    .map(|(owner_id, _)| owner_id)
+                    .collect()
+            });
+            runtime.block_on(relationships).unwrap_or_else(|e| {
+                log::warn!("Could not look up friendships of {}: {:?}", user_id, e);
+                BTreeSet::new()
+            })
+        } else {
+            BTreeSet::new()
+        };
+        self.jobs.create_job(user_id, owners, &refollow).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    /// 2019-04-01, a Monday.
+    const MONDAY: u64 = 1_554_076_800;
+
+    /// `days` after midnight on `MONDAY`, at `hour:minute`.
+    fn at(days: u64, hour: u64, minute: u64) -> SystemTime {
+        let secs = days * 24 * 60 * 60 + hour * 60 * 60 + minute * 60;
+        UNIX_EPOCH + Duration::from_secs(MONDAY + secs)
+    }
+
+    fn daily(hour: u8) -> When {
+        When::new(Frequency::Daily, 0, hour).unwrap()
+    }
+
+    fn weekly(weekday: u8, hour: u8) -> When {
+        When::new(Frequency::Weekly, weekday, hour).unwrap()
+    }
+
+    #[test]
+    fn daily_runs_later_the_same_day() {
+        assert_eq!(daily(9).next_after(at(0, 8, 30)), at(0, 9, 0));
+    }
+
+    #[test]
+    fn daily_runs_the_next_day_once_the_hour_has_passed() {
+        assert_eq!(daily(9).next_after(at(0, 10, 0)), at(1, 9, 0));
+        assert_eq!(daily(9).next_after(at(0, 9, 0)), at(1, 9, 0));
+        assert_eq!(daily(0).next_after(at(6, 23, 59)), at(7, 0, 0));
+    }
+
+    #[test]
+    fn weekly_runs_on_its_weekday() {
+        assert_eq!(weekly(2, 9).next_after(at(0, 10, 0)), at(2, 9, 0));
+        assert_eq!(weekly(0, 9).next_after(at(0, 8, 0)), at(0, 9, 0));
+    }
+
+    #[test]
+    fn weekly_runs_the_next_week_once_the_hour_has_passed() {
+        assert_eq!(weekly(0, 9).next_after(at(0, 10, 0)), at(7, 9, 0));
+        assert_eq!(weekly(6, 23).next_after(at(6, 23, 30)), at(13, 23, 0));
+    }
+
+    #[test]
+    fn weekly_wraps_around_the_end_of_the_week() {
+        assert_eq!(weekly(0, 9).next_after(at(5, 12, 0)), at(7, 9, 0));
+        assert_eq!(weekly(1, 0).next_after(at(6, 12, 0)), at(8, 0, 0));
+    }
+}
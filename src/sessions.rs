@@ -0,0 +1,166 @@
+use crate::crypto::SigningKey;
+use crate::db::{self, Database};
+use crate::error::*;
+use failchain::ResultExt;
+use hyper::header::{HeaderMap, HeaderValue, COOKIE};
+use rand::distributions::{Alphanumeric, Distribution};
+use rusqlite::{params, OptionalExtension};
+use std::time::{Duration, SystemTime};
+
+const COOKIE_NAME: &'static str = "session";
+
+/// Someone who's logged in with twitter. Their access token is in the token store under their
+/// user id, so it never goes near the cookie.
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub id: String,
+    pub user_id: u64,
+}
+
+/// Server side sessions, identified by a random id in a signed cookie. The signature means a
+/// cookie can't be made up from what's in the database alone.
+pub struct SessionStore {
+    db: Database,
+    signing_key: SigningKey,
+    ttl: Duration,
+    /// Only send the cookie over https, if that's how users reach us.
+    secure: bool,
+}
+
+impl SessionStore {
+    pub fn new(db: Database, signing_key: SigningKey, ttl: Duration, secure: bool) -> Self {
+        SessionStore {
+            db,
+            signing_key,
+            ttl,
+            secure,
+        }
+    }
+
+    pub fn create(&self, user_id: u64) -> Result<Session> {
+        let id = Alphanumeric
+            .sample_iter(&mut rand::thread_rng())
+            .take(32)
+            .collect::<String>();
+        let conn = db::lock(&self.db)?;
+        conn.execute(
+            "INSERT INTO sessions (id, user_id, created_at) VALUES (?1, ?2, ?3)",
+            params![id, user_id as i64, db::to_timestamp(SystemTime::now())],
+        )
+        .chain_err(|| ErrorKind::DatabaseError("creating session".to_owned()))?;
+        Ok(Session { id, user_id })
+    }
+
+    /// The session that the request's cookie is for, if it has a valid one that hasn't expired.
+    pub fn from_headers(&self, headers: &HeaderMap) -> Result<Option<Session>> {
+        let id = headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|header| header.to_str().ok())
+            .flat_map(|header| header.split(';'))
+            .filter_map(|cookie| {
+                let index = cookie.find('=')?;
+                match cookie[..index].trim() {
+                    COOKIE_NAME => self.signing_key.verify(cookie[index + 1..].trim()),
+                    _ => None,
+                }
+            })
+            .next();
+        match id {
+            Some(id) => self.get(id),
+            None => Ok(None),
+        }
+    }
+
+    fn get(&self, id: &str) -> Result<Option<Session>> {
+        let conn = db::lock(&self.db)?;
+        let row: Option<(i64, i64)> = conn
+            .query_row(
+                "SELECT user_id, created_at FROM sessions WHERE id = ?1",
+                params![id],
+                |row| (row.get(0), row.get(1)),
+            )
+            .optional()
+            .chain_err(|| ErrorKind::DatabaseError("loading session".to_owned()))?;
+        Ok(row.and_then(|(user_id, created_at)| {
+            let age = db::from_timestamp(created_at).elapsed().unwrap_or_default();
+            if age > self.ttl {
+                None
+            } else {
+                Some(Session {
+                    id: id.to_owned(),
+                    user_id: user_id as u64,
+                })
+            }
+        }))
+    }
+
+    /// The `Set-Cookie` header that gives the user their session. Javascript can't read it, and
+    /// it's only sent with requests from other sites if they're top level navigations, such as
+    /// twitter sending the user back to us.
+    pub fn set_cookie(&self, session: &Session) -> Result<HeaderValue> {
+        let signed_id = self.signing_key.sign(&session.id)?;
+        self.cookie_header(&signed_id, self.ttl)
+    }
+
+    /// The `Set-Cookie` header that logs the user out of this browser.
+    pub fn clear_cookie(&self) -> Result<HeaderValue> {
+        self.cookie_header("", Duration::from_secs(0))
+    }
+
+    fn cookie_header(&self, value: &str, max_age: Duration) -> Result<HeaderValue> {
+        let mut cookie = format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+            COOKIE_NAME,
+            value,
+            max_age.as_secs()
+        );
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        HeaderValue::from_str(&cookie)
+            .chain_err(|| ErrorKind::OtherError("constructing session cookie".to_owned()))
+    }
+
+    /// The token that forms shown to this session have to be submitted with, so we know they came
+    /// from us and not another site. It's the session id signed for a different purpose than the
+    /// cookie, so it's the same for the life of the session and doesn't need storing.
+    pub fn csrf_token(&self, session: &Session) -> Result<String> {
+        self.signing_key.signature(&csrf_value(session))
+    }
+
+    pub fn check_csrf_token(&self, session: &Session, token: &str) -> bool {
+        self.signing_key
+            .check_signature(&csrf_value(session), token)
+    }
+
+    /// Log the user out everywhere, returning how many sessions they had.
+    pub fn delete_user_sessions(&self, user_id: u64) -> Result<usize> {
+        let conn = db::lock(&self.db)?;
+        let deleted = conn
+            .execute(
+                "DELETE FROM sessions WHERE user_id = ?1",
+                params![user_id as i64],
+            )
+            .chain_err(|| ErrorKind::DatabaseError("deleting user's sessions".to_owned()))?;
+        Ok(deleted)
+    }
+
+    /// Remove every session that's expired, returning how many there were.
+    pub fn delete_expired(&self) -> Result<usize> {
+        let cutoff = SystemTime::now() - self.ttl;
+        let conn = db::lock(&self.db)?;
+        let deleted = conn
+            .execute(
+                "DELETE FROM sessions WHERE created_at < ?1",
+                params![db::to_timestamp(cutoff)],
+            )
+            .chain_err(|| ErrorKind::DatabaseError("deleting expired sessions".to_owned()))?;
+        Ok(deleted)
+    }
+}
+
+/// What CSRF tokens sign. The prefix means a cookie's signature can never be used as a token.
+fn csrf_value(session: &Session) -> String {
+    format!("csrf:{}", session.id)
+}
@@ -0,0 +1,313 @@
+use crate::db::{self, Database};
+use crate::egg_mode_2::TwitterList;
+use crate::error::*;
+use failchain::ResultExt;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+/// How many snapshots to keep for each user, as only the latest two are ever compared.
+const MAX_SNAPSHOTS_PER_USER: i64 = 20;
+
+/// One list from a snapshot, as much of it as we keep.
+#[derive(Clone, Debug, Serialize)]
+pub struct SnapshotList {
+    pub list_id: u64,
+    pub owner_id: u64,
+    pub list_name: String,
+}
+
+/// How the user's lists changed between their two most recent snapshots.
+#[derive(Clone, Debug)]
+pub struct Changes {
+    /// When the earlier of the two was taken.
+    pub since: SystemTime,
+    pub taken_at: SystemTime,
+    /// Lists they're on now that they weren't before.
+    pub added: Vec<SnapshotList>,
+    /// Lists they were on before that they aren't now, whether they were removed or the list was
+    /// deleted.
+    pub removed: Vec<SnapshotList>,
+}
+
+/// Every set of list memberships we've fetched for each user, so that they can see what's changed.
+#[derive(Clone)]
+pub struct SnapshotStore {
+    db: Database,
+}
+
+impl SnapshotStore {
+    pub fn new(db: Database) -> Self {
+        SnapshotStore { db }
+    }
+
+    /// Record every list the user is on, as just fetched from twitter. If nothing's changed since
+    /// their latest snapshot then that one is just marked as taken again, so that reloading doesn't
+    /// hide what `changes` would show. Only the newest `MAX_SNAPSHOTS_PER_USER` are kept.
+    pub fn save(&self, user_id: u64, lists: &[TwitterList], taken_at: SystemTime) -> Result<i64> {
+        let mut conn = db::lock(&self.db)?;
+        let tx = conn
+            .transaction()
+            .chain_err(|| ErrorKind::DatabaseError("starting saving snapshot".to_owned()))?;
+        let latest_id: Option<i64> = tx
+            .query_row(
+                "SELECT MAX(id) FROM membership_snapshots WHERE user_id = ?1",
+                params![user_id as i64],
+                |row| row.get(0),
+            )
+            .chain_err(|| ErrorKind::DatabaseError("loading latest snapshot".to_owned()))?;
+        if let Some(latest_id) = latest_id {
+            if same_lists(&snapshot_lists(&tx, latest_id)?, lists) {
+                tx.execute(
+                    "UPDATE membership_snapshots SET taken_at = ?1 WHERE id = ?2",
+                    params![db::to_timestamp(taken_at), latest_id],
+                )
+                .chain_err(|| ErrorKind::DatabaseError("updating snapshot".to_owned()))?;
+                tx.commit()
+                    .chain_err(|| ErrorKind::DatabaseError("committing snapshot".to_owned()))?;
+                return Ok(latest_id);
+            }
+        }
+
+        tx.execute(
+            "INSERT INTO membership_snapshots (user_id, taken_at) VALUES (?1, ?2)",
+            params![user_id as i64, db::to_timestamp(taken_at)],
+        )
+        .chain_err(|| ErrorKind::DatabaseError("saving snapshot".to_owned()))?;
+        let snapshot_id = tx.last_insert_rowid();
+
+        for list in lists {
+            tx.execute(
+                "INSERT OR REPLACE INTO snapshot_lists (snapshot_id, list_id, owner_id, list_name)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![snapshot_id, list.id as i64, list.user.id as i64, list.name],
+            )
+            .chain_err(|| ErrorKind::DatabaseError("adding list to snapshot".to_owned()))?;
+        }
+
+        let oldest = "SELECT id FROM membership_snapshots WHERE user_id = ?1
+                      ORDER BY id DESC LIMIT -1 OFFSET ?2";
+        tx.execute(
+            &format!(
+                "DELETE FROM snapshot_lists WHERE snapshot_id IN ({})",
+                oldest
+            ),
+            params![user_id as i64, MAX_SNAPSHOTS_PER_USER],
+        )
+        .chain_err(|| ErrorKind::DatabaseError("pruning snapshot lists".to_owned()))?;
+        tx.execute(
+            &format!("DELETE FROM membership_snapshots WHERE id IN ({})", oldest),
+            params![user_id as i64, MAX_SNAPSHOTS_PER_USER],
+        )
+        .chain_err(|| ErrorKind::DatabaseError("pruning snapshots".to_owned()))?;
+
+        tx.commit()
+            .chain_err(|| ErrorKind::DatabaseError("committing snapshot".to_owned()))?;
+        Ok(snapshot_id)
+    }
+
+    /// What changed between the user's last two snapshots, or `None` if they don't have two yet.
+    pub fn changes(&self, user_id: u64) -> Result<Option<Changes>> {
+        let (latest, previous) = match self.latest_two(user_id)? {
+            Some(snapshots) => snapshots,
+            None => return Ok(None),
+        };
+        let (latest_id, taken_at) = latest;
+        let (previous_id, since) = previous;
+
+        let conn = db::lock(&self.db)?;
+        let mut previous_lists = snapshot_lists(&conn, previous_id)?;
+        let mut added = Vec::new();
+        for (list_id, list) in snapshot_lists(&conn, latest_id)? {
+            if previous_lists.remove(&list_id).is_none() {
+                added.push(list);
+            }
+        }
+        Ok(Some(Changes {
+            since,
+            taken_at,
+            added,
+            removed: previous_lists.into_iter().map(|(_, list)| list).collect(),
+        }))
+    }
+
+    /// Every list in the user's latest snapshot, by owner.
+    pub fn latest_lists(&self, user_id: u64) -> Result<Vec<SnapshotList>> {
+        let conn = db::lock(&self.db)?;
+        let mut statement = conn
+            .prepare(
+                "SELECT list_id, owner_id, list_name FROM snapshot_lists WHERE snapshot_id =
+                     (SELECT MAX(id) FROM membership_snapshots WHERE user_id = ?1)
+                 ORDER BY owner_id, list_name",
+            )
+            .chain_err(|| ErrorKind::DatabaseError("loading latest snapshot".to_owned()))?;
+        let rows: Vec<(i64, i64, String)> = statement
+            .query_map(params![user_id as i64], |row| {
+                (row.get(0), row.get(1), row.get(2))
+            })
+            .and_then(|rows| rows.collect())
+            .chain_err(|| ErrorKind::DatabaseError("loading latest snapshot".to_owned()))?;
+        Ok(rows.into_iter().map(snapshot_list).collect())
+    }
+
+    /// Forget every snapshot of the user, returning how many there were.
+    pub fn delete_user_snapshots(&self, user_id: u64) -> Result<usize> {
+        let mut conn = db::lock(&self.db)?;
+        let tx = conn
+            .transaction()
+            .chain_err(|| ErrorKind::DatabaseError("starting deleting snapshots".to_owned()))?;
+        tx.execute(
+            "DELETE FROM snapshot_lists WHERE snapshot_id IN
+                 (SELECT id FROM membership_snapshots WHERE user_id = ?1)",
+            params![user_id as i64],
+        )
+        .chain_err(|| ErrorKind::DatabaseError("deleting snapshot lists".to_owned()))?;
+        let deleted = tx
+            .execute(
+                "DELETE FROM membership_snapshots WHERE user_id = ?1",
+                params![user_id as i64],
+            )
+            .chain_err(|| ErrorKind::DatabaseError("deleting snapshots".to_owned()))?;
+        tx.commit()
+            .chain_err(|| ErrorKind::DatabaseError("committing deleting snapshots".to_owned()))?;
+        Ok(deleted)
+    }
+
+    /// The id and time of the user's latest snapshot and the one before it.
+    fn latest_two(&self, user_id: u64) -> Result<Option<((i64, SystemTime), (i64, SystemTime))>> {
+        let conn = db::lock(&self.db)?;
+        let mut statement = conn
+            .prepare(
+                "SELECT id, taken_at FROM membership_snapshots WHERE user_id = ?1
+                 ORDER BY id DESC LIMIT 2",
+            )
+            .chain_err(|| ErrorKind::DatabaseError("loading snapshots".to_owned()))?;
+        let rows: Vec<(i64, i64)> = statement
+            .query_map(params![user_id as i64], |row| (row.get(0), row.get(1)))
+            .and_then(|rows| rows.collect())
+            .chain_err(|| ErrorKind::DatabaseError("loading snapshots".to_owned()))?;
+        match rows.as_slice() {
+            [(latest_id, latest_at), (previous_id, previous_at)] => Ok(Some((
+                (*latest_id, db::from_timestamp(*latest_at)),
+                (*previous_id, db::from_timestamp(*previous_at)),
+            ))),
+            _ => Ok(None),
+        }
+    }
+}
+
+fn snapshot_lists(conn: &Connection, snapshot_id: i64) -> Result<BTreeMap<u64, SnapshotList>> {
+    let mut statement = conn
+        .prepare("SELECT list_id, owner_id, list_name FROM snapshot_lists WHERE snapshot_id = ?1")
+        .chain_err(|| ErrorKind::DatabaseError("loading snapshot lists".to_owned()))?;
+    let rows: Vec<(i64, i64, String)> = statement
+        .query_map(params![snapshot_id], |row| {
+            (row.get(0), row.get(1), row.get(2))
+        })
+        .and_then(|rows| rows.collect())
+        .chain_err(|| ErrorKind::DatabaseError("loading snapshot lists".to_owned()))?;
+    Ok(rows
+        .into_iter()
+        .map(snapshot_list)
+        .map(|list| (list.list_id, list))
+        .collect())
+}
+
+/// Whether the snapshot has exactly these lists, with the same owners and names.
+fn same_lists(snapshot: &BTreeMap<u64, SnapshotList>, lists: &[TwitterList]) -> bool {
+    let saved = snapshot
+        .values()
+        .map(|list| (list.list_id, (list.owner_id, list.list_name.as_str())))
+        .collect::<BTreeMap<_, _>>();
+    let current = lists
+        .iter()
+        .map(|list| (list.id, (list.user.id, list.name.as_str())))
+        .collect::<BTreeMap<_, _>>();
+    saved == current
+}
+
+fn snapshot_list((list_id, owner_id, list_name): (i64, i64, String)) -> SnapshotList {
+    SnapshotList {
+        list_id: list_id as u64,
+        owner_id: owner_id as u64,
+        list_name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::egg_mode_2::TwitterUser;
+    use rusqlite::NO_PARAMS;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    const USER: u64 = 1;
+
+    fn list(list_id: u64, owner_id: u64) -> TwitterList {
+        TwitterList {
+            id: list_id,
+            name: format!("list {}", list_id),
+            slug: format!("list-{}", list_id),
+            description: String::new(),
+            member_count: 1,
+            subscriber_count: 0,
+            user: TwitterUser {
+                id: owner_id,
+                screen_name: format!("owner{}", owner_id),
+                name: format!("Owner {}", owner_id),
+                profile_image_url_https: String::new(),
+            },
+        }
+    }
+
+    fn at(hours: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(hours * 60 * 60)
+    }
+
+    fn list_ids(lists: &[SnapshotList]) -> Vec<u64> {
+        lists.iter().map(|list| list.list_id).collect()
+    }
+
+    #[test]
+    fn reloading_the_same_lists_keeps_what_changed() {
+        let db = db::open(":memory:").unwrap();
+        let snapshots = SnapshotStore::new(db);
+        snapshots.save(USER, &[list(10, 100)], at(1)).unwrap();
+        snapshots
+            .save(USER, &[list(10, 100), list(11, 101)], at(2))
+            .unwrap();
+        snapshots
+            .save(USER, &[list(10, 100), list(11, 101)], at(3))
+            .unwrap();
+
+        let changes = snapshots.changes(USER).unwrap().unwrap();
+        assert_eq!(changes.since, at(1));
+        assert_eq!(changes.taken_at, at(3));
+        assert_eq!(list_ids(&changes.added), vec![11]);
+        assert!(changes.removed.is_empty());
+    }
+
+    #[test]
+    fn only_the_newest_snapshots_are_kept() {
+        let db = db::open(":memory:").unwrap();
+        let snapshots = SnapshotStore::new(db.clone());
+        let saves = MAX_SNAPSHOTS_PER_USER as u64 + 5;
+        for hour in 0..saves {
+            snapshots.save(USER, &[list(hour, 100)], at(hour)).unwrap();
+        }
+
+        let conn = db::lock(&db).unwrap();
+        let count = |table: &str| -> i64 {
+            let query = format!("SELECT COUNT(*) FROM {}", table);
+            conn.query_row(&query, NO_PARAMS, |row| row.get(0)).unwrap()
+        };
+        assert_eq!(count("membership_snapshots"), MAX_SNAPSHOTS_PER_USER);
+        assert_eq!(count("snapshot_lists"), MAX_SNAPSHOTS_PER_USER);
+        drop(conn);
+
+        let changes = snapshots.changes(USER).unwrap().unwrap();
+        assert_eq!(list_ids(&changes.added), vec![saves - 1]);
+        assert_eq!(list_ids(&changes.removed), vec![saves - 2]);
+    }
+}
@@ -0,0 +1,483 @@
+use crate::crypto::Keyring;
+use crate::db::{self, Database};
+use crate::error::*;
+use egg_mode::KeyPair;
+use failchain::ResultExt;
+use rusqlite::{params, OptionalExtension, NO_PARAMS};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+/// A request token that's waiting for the user to come back from twitter.
+pub struct PendingRequestToken {
+    pub keypair: KeyPair,
+    pub created_at: SystemTime,
+}
+
+/// What the retention policy needs to know about an access token, without its secret.
+#[derive(Clone, Debug)]
+pub struct StoredAccessToken {
+    pub user_id: u64,
+    /// When the user last logged in.
+    pub saved_at: SystemTime,
+    /// The user has opted into recurring runs, so it's kept until they revoke it.
+    pub keep_until_revoked: bool,
+}
+
+/// Somewhere to keep the request tokens of logins in progress and the access tokens of users that
+/// have finished logging in.
+pub trait TokenStore: Send + Sync {
+    fn save_request_token(&self, request_token: &KeyPair, created_at: SystemTime) -> Result<()>;
+
+    /// Remove and return a request token, so that each one can only be used once.
+    fn take_request_token(&self, oauth_token: &str) -> Result<Option<PendingRequestToken>>;
+
+    /// Remove every request token created before `cutoff`, returning how many there were.
+    fn delete_request_tokens_before(&self, cutoff: SystemTime) -> Result<usize>;
+
+    /// Save the user's access token when they log in, replacing any they had before. Whether
+    /// it's kept until revoked carries over.
+    fn save_access_token(
+        &self,
+        user_id: u64,
+        access_token: &KeyPair,
+        saved_at: SystemTime,
+    ) -> Result<()>;
+
+    fn get_access_token(&self, user_id: u64) -> Result<Option<KeyPair>>;
+
+    fn stored_access_token(&self, user_id: u64) -> Result<Option<StoredAccessToken>>;
+
+    /// Every access token we have, for the retention policy to go through.
+    fn stored_access_tokens(&self) -> Result<Vec<StoredAccessToken>>;
+
+    /// Keep the user's access token until they revoke it, whatever the retention policy says.
+    fn set_keep_until_revoked(&self, user_id: u64, keep_until_revoked: bool) -> Result<()>;
+
+    /// Forget the user's access token, returning whether there was one.
+    fn delete_access_token(&self, user_id: u64) -> Result<bool>;
+}
+
+/// Token secrets are sealed with the keyring before they're written, using the oauth token as the
+/// associated data so that a secret can't be swapped onto a different token's row.
+pub struct SqliteTokenStore {
+    db: Database,
+    keyring: Arc<Keyring>,
+}
+
+impl SqliteTokenStore {
+    pub fn new(db: Database, keyring: Arc<Keyring>) -> Self {
+        SqliteTokenStore { db, keyring }
+    }
+
+    /// Re-encrypt every secret that isn't sealed with the current master key, so that previous
+    /// master keys can be retired. Returns how many secrets were resealed.
+    pub fn reseal_secrets(&self) -> Result<usize> {
+        let mut conn = db::lock(&self.db)?;
+        let tx = conn
+            .transaction()
+            .chain_err(|| ErrorKind::DatabaseError("starting resealing".to_owned()))?;
+
+        let mut resealed = 0;
+        for table in &["request_tokens", "access_tokens"] {
+            let rows = {
+                let mut statement = tx
+                    .prepare(&format!(
+                        "SELECT oauth_token, oauth_token_secret FROM {}",
+                        table
+                    ))
+                    .chain_err(|| ErrorKind::DatabaseError(format!("reading {}", table)))?;
+                let rows = statement
+                    .query_map(NO_PARAMS, |row| (row.get(0), row.get(1)))
+                    .and_then(|rows| rows.collect::<rusqlite::Result<Vec<(String, String)>>>())
+                    .chain_err(|| ErrorKind::DatabaseError(format!("reading {}", table)))?;
+                rows
+            };
+
+            for (oauth_token, secret) in rows {
+                if !self.keyring.needs_resealing(&secret) {
+                    continue;
+                }
+                let sealed = self.keyring.reseal(&secret, &oauth_token)?;
+                tx.execute(
+                    &format!(
+                        "UPDATE {} SET oauth_token_secret = ?1 WHERE oauth_token = ?2",
+                        table
+                    ),
+                    params![sealed, oauth_token],
+                )
+                .chain_err(|| ErrorKind::DatabaseError(format!("resealing {}", table)))?;
+                resealed += 1;
+            }
+        }
+
+        tx.commit()
+            .chain_err(|| ErrorKind::DatabaseError("committing resealing".to_owned()))?;
+        Ok(resealed)
+    }
+}
+
+impl TokenStore for SqliteTokenStore {
+    fn save_request_token(&self, request_token: &KeyPair, created_at: SystemTime) -> Result<()> {
+        let sealed_secret = self
+            .keyring
+            .seal(&request_token.secret, &request_token.key)?;
+        let conn = db::lock(&self.db)?;
+        conn.execute(
+            "INSERT OR REPLACE INTO request_tokens (oauth_token, oauth_token_secret, created_at)
+             VALUES (?1, ?2, ?3)",
+            params![
+                &*request_token.key,
+                sealed_secret,
+                db::to_timestamp(created_at)
+            ],
+        )
+        .chain_err(|| ErrorKind::DatabaseError("saving request token".to_owned()))?;
+        Ok(())
+    }
+
+    fn take_request_token(&self, oauth_token: &str) -> Result<Option<PendingRequestToken>> {
+        let mut conn = db::lock(&self.db)?;
+        let tx = conn
+            .transaction()
+            .chain_err(|| ErrorKind::DatabaseError("starting taking request token".to_owned()))?;
+        let row: Option<(String, i64)> = tx
+            .query_row(
+                "SELECT oauth_token_secret, created_at FROM request_tokens WHERE oauth_token = ?1",
+                params![oauth_token],
+                |row| (row.get(0), row.get(1)),
+            )
+            .optional()
+            .chain_err(|| ErrorKind::DatabaseError("loading request token".to_owned()))?;
+        tx.execute(
+            "DELETE FROM request_tokens WHERE oauth_token = ?1",
+            params![oauth_token],
+        )
+        .chain_err(|| ErrorKind::DatabaseError("deleting request token".to_owned()))?;
+        tx.commit()
+            .chain_err(|| ErrorKind::DatabaseError("committing taking request token".to_owned()))?;
+
+        match row {
+            Some((sealed_secret, created_at)) => {
+                let secret = self.keyring.open(&sealed_secret, oauth_token)?;
+                Ok(Some(PendingRequestToken {
+                    keypair: KeyPair::new(oauth_token.to_owned(), secret),
+                    created_at: db::from_timestamp(created_at),
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn delete_request_tokens_before(&self, cutoff: SystemTime) -> Result<usize> {
+        let conn = db::lock(&self.db)?;
+        let deleted = conn
+            .execute(
+                "DELETE FROM request_tokens WHERE created_at < ?1",
+                params![db::to_timestamp(cutoff)],
+            )
+            .chain_err(|| ErrorKind::DatabaseError("deleting expired request tokens".to_owned()))?;
+        Ok(deleted)
+    }
+
+    fn save_access_token(
+        &self,
+        user_id: u64,
+        access_token: &KeyPair,
+        saved_at: SystemTime,
+    ) -> Result<()> {
+        let sealed_secret = self.keyring.seal(&access_token.secret, &access_token.key)?;
+        let conn = db::lock(&self.db)?;
+        conn.execute(
+            "INSERT OR REPLACE INTO access_tokens
+                 (user_id, oauth_token, oauth_token_secret, saved_at, keep_until_revoked)
+             VALUES (?1, ?2, ?3, ?4, COALESCE(
+                 (SELECT keep_until_revoked FROM access_tokens WHERE user_id = ?1), 0
+             ))",
+            params![
+                user_id as i64,
+                &*access_token.key,
+                sealed_secret,
+                db::to_timestamp(saved_at)
+            ],
+        )
+        .chain_err(|| ErrorKind::DatabaseError("saving access token".to_owned()))?;
+        Ok(())
+    }
+
+    fn get_access_token(&self, user_id: u64) -> Result<Option<KeyPair>> {
+        let conn = db::lock(&self.db)?;
+        let key_and_secret: Option<(String, String)> = conn
+            .query_row(
+                "SELECT oauth_token, oauth_token_secret FROM access_tokens WHERE user_id = ?1",
+                params![user_id as i64],
+                |row| (row.get(0), row.get(1)),
+            )
+            .optional()
+            .chain_err(|| ErrorKind::DatabaseError("loading access token".to_owned()))?;
+        match key_and_secret {
+            Some((key, sealed_secret)) => {
+                let secret = self.keyring.open(&sealed_secret, &key)?;
+                Ok(Some(KeyPair::new(key, secret)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn delete_access_token(&self, user_id: u64) -> Result<bool> {
+        let conn = db::lock(&self.db)?;
+        let deleted = conn
+            .execute(
+                "DELETE FROM access_tokens WHERE user_id = ?1",
+                params![user_id as i64],
+            )
+            .chain_err(|| ErrorKind::DatabaseError("deleting access token".to_owned()))?;
+        Ok(deleted > 0)
+    }
+
+    fn stored_access_token(&self, user_id: u64) -> Result<Option<StoredAccessToken>> {
+        let conn = db::lock(&self.db)?;
+        let row: Option<(i64, bool)> = conn
+            .query_row(
+                "SELECT saved_at, keep_until_revoked FROM access_tokens WHERE user_id = ?1",
+                params![user_id as i64],
+                |row| (row.get(0), row.get(1)),
+            )
+            .optional()
+            .chain_err(|| ErrorKind::DatabaseError("loading access token".to_owned()))?;
+        Ok(row.map(|(saved_at, keep_until_revoked)| StoredAccessToken {
+            user_id,
+            saved_at: db::from_timestamp(saved_at),
+            keep_until_revoked,
+        }))
+    }
+
+    fn stored_access_tokens(&self) -> Result<Vec<StoredAccessToken>> {
+        let conn = db::lock(&self.db)?;
+        let mut statement = conn
+            .prepare("SELECT user_id, saved_at, keep_until_revoked FROM access_tokens")
+            .chain_err(|| ErrorKind::DatabaseError("loading access tokens".to_owned()))?;
+        let rows: Vec<(i64, i64, bool)> = statement
+            .query_map(NO_PARAMS, |row| (row.get(0), row.get(1), row.get(2)))
+            .and_then(|rows| rows.collect())
+            .chain_err(|| ErrorKind::DatabaseError("loading access tokens".to_owned()))?;
+        Ok(rows
+            .into_iter()
+            .map(
+                |(user_id, saved_at, keep_until_revoked)| StoredAccessToken {
+                    user_id: user_id as u64,
+                    saved_at: db::from_timestamp(saved_at),
+                    keep_until_revoked,
+                },
+            )
+            .collect())
+    }
+
+    fn set_keep_until_revoked(&self, user_id: u64, keep_until_revoked: bool) -> Result<()> {
+        let conn = db::lock(&self.db)?;
+        conn.execute(
+            "UPDATE access_tokens SET keep_until_revoked = ?1 WHERE user_id = ?2",
+            params![keep_until_revoked, user_id as i64],
+        )
+        .chain_err(|| ErrorKind::DatabaseError("setting access token retention".to_owned()))?;
+        Ok(())
+    }
+}
+
+/// Keeps everything in memory, so all logins in progress are lost on restart.
+/// Only really useful for tests and trying things out locally.
+#[derive(Default)]
+pub struct MemoryTokenStore {
+    request_tokens: Mutex<HashMap<String, (KeyPair, SystemTime)>>,
+    access_tokens: Mutex<HashMap<u64, (KeyPair, StoredAccessToken)>>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<T>> {
+    mutex.lock().map_err(|_| {
+        let kind = ErrorKind::OtherError("Could not get lock for MemoryTokenStore".to_owned());
+        kind.into()
+    })
+}
+
+impl TokenStore for MemoryTokenStore {
+    fn save_request_token(&self, request_token: &KeyPair, created_at: SystemTime) -> Result<()> {
+        let mut map = lock(&self.request_tokens)?;
+        map.insert(
+            request_token.key.clone().into_owned(),
+            (request_token.clone(), created_at),
+        );
+        Ok(())
+    }
+
+    fn take_request_token(&self, oauth_token: &str) -> Result<Option<PendingRequestToken>> {
+        let mut map = lock(&self.request_tokens)?;
+        Ok(map
+            .remove(oauth_token)
+            .map(|(keypair, created_at)| PendingRequestToken {
+                keypair,
+                created_at,
+            }))
+    }
+
+    fn delete_request_tokens_before(&self, cutoff: SystemTime) -> Result<usize> {
+        let mut map = lock(&self.request_tokens)?;
+        let before = map.len();
+        map.retain(|_, (_, created_at)| *created_at >= cutoff);
+        Ok(before - map.len())
+    }
+
+    fn save_access_token(
+        &self,
+        user_id: u64,
+        access_token: &KeyPair,
+        saved_at: SystemTime,
+    ) -> Result<()> {
+        let mut map = lock(&self.access_tokens)?;
+        let keep_until_revoked = map
+            .get(&user_id)
+            .map_or(false, |(_, stored)| stored.keep_until_revoked);
+        let stored = StoredAccessToken {
+            user_id,
+            saved_at,
+            keep_until_revoked,
+        };
+        map.insert(user_id, (access_token.clone(), stored));
+        Ok(())
+    }
+
+    fn get_access_token(&self, user_id: u64) -> Result<Option<KeyPair>> {
+        let map = lock(&self.access_tokens)?;
+        Ok(map
+            .get(&user_id)
+            .map(|(access_token, _)| access_token.clone()))
+    }
+
+    fn stored_access_token(&self, user_id: u64) -> Result<Option<StoredAccessToken>> {
+        let map = lock(&self.access_tokens)?;
+        Ok(map.get(&user_id).map(|(_, stored)| stored.clone()))
+    }
+
+    fn stored_access_tokens(&self) -> Result<Vec<StoredAccessToken>> {
+        let map = lock(&self.access_tokens)?;
+        Ok(map.values().map(|(_, stored)| stored.clone()).collect())
+    }
+
+    fn set_keep_until_revoked(&self, user_id: u64, keep_until_revoked: bool) -> Result<()> {
+        let mut map = lock(&self.access_tokens)?;
+        if let Some((_, stored)) = map.get_mut(&user_id) {
+            stored.keep_until_revoked = keep_until_revoked;
+        }
+        Ok(())
+    }
+
+    fn delete_access_token(&self, user_id: u64) -> Result<bool> {
+        let mut map = lock(&self.access_tokens)?;
+        Ok(map.remove(&user_id).is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const OLD_MASTER_KEY: &'static str = "YWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWE=";
+    const NEW_MASTER_KEY: &'static str = "YmJiYmJiYmJiYmJiYmJiYmJiYmJiYmJiYmJiYmJiYmI=";
+
+    fn sqlite_store(db: &Database, current: &str, previous: &[&str]) -> SqliteTokenStore {
+        let keyring = Keyring::new(current, previous.iter().cloned()).unwrap();
+        SqliteTokenStore::new(db.clone(), Arc::new(keyring))
+    }
+
+    /// Every backend, so that each test checks they all behave the same.
+    fn stores() -> Vec<Box<dyn TokenStore>> {
+        let db = db::open(":memory:").unwrap();
+        vec![
+            Box::new(MemoryTokenStore::default()),
+            Box::new(sqlite_store(&db, OLD_MASTER_KEY, &[])),
+        ]
+    }
+
+    #[test]
+    fn request_tokens_can_only_be_taken_once() {
+        for store in stores() {
+            let request_token = KeyPair::new("request", "request secret");
+            store
+                .save_request_token(&request_token, SystemTime::now())
+                .unwrap();
+
+            let taken = store.take_request_token("request").unwrap().unwrap();
+            assert_eq!(taken.keypair.key, request_token.key);
+            assert_eq!(taken.keypair.secret, request_token.secret);
+            assert!(store.take_request_token("request").unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn request_tokens_expire_after_the_ttl() {
+        let ttl = Duration::from_secs(15 * 60);
+        for store in stores() {
+            let now = SystemTime::now();
+            let expired = KeyPair::new("expired", "expired secret");
+            let fresh = KeyPair::new("fresh", "fresh secret");
+            store
+                .save_request_token(&expired, now - ttl - Duration::from_secs(1))
+                .unwrap();
+            store.save_request_token(&fresh, now).unwrap();
+
+            assert_eq!(store.delete_request_tokens_before(now - ttl).unwrap(), 1);
+            assert!(store.take_request_token("expired").unwrap().is_none());
+            assert!(store.take_request_token("fresh").unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn saving_an_access_token_keeps_whether_its_kept_until_revoked() {
+        for store in stores() {
+            let access_token = KeyPair::new("access", "access secret");
+            store
+                .save_access_token(1, &access_token, SystemTime::now())
+                .unwrap();
+            store.set_keep_until_revoked(1, true).unwrap();
+            store
+                .save_access_token(1, &access_token, SystemTime::now())
+                .unwrap();
+
+            let stored = store.stored_access_token(1).unwrap().unwrap();
+            assert!(stored.keep_until_revoked);
+            assert!(store.delete_access_token(1).unwrap());
+            assert!(store.get_access_token(1).unwrap().is_none());
+            assert!(!store.delete_access_token(1).unwrap());
+        }
+    }
+
+    #[test]
+    fn reseal_secrets_survives_rotating_the_master_key() {
+        let db = db::open(":memory:").unwrap();
+        let old_store = sqlite_store(&db, OLD_MASTER_KEY, &[]);
+        old_store
+            .save_request_token(
+                &KeyPair::new("request", "request secret"),
+                SystemTime::now(),
+            )
+            .unwrap();
+        old_store
+            .save_access_token(
+                1,
+                &KeyPair::new("access", "access secret"),
+                SystemTime::now(),
+            )
+            .unwrap();
+
+        let rotated_store = sqlite_store(&db, NEW_MASTER_KEY, &[OLD_MASTER_KEY]);
+        assert_eq!(rotated_store.reseal_secrets().unwrap(), 2);
+        assert_eq!(rotated_store.reseal_secrets().unwrap(), 0);
+
+        // Once everything's resealed the old key isn't needed any more.
+        let new_store = sqlite_store(&db, NEW_MASTER_KEY, &[]);
+        let access_token = new_store.get_access_token(1).unwrap().unwrap();
+        assert_eq!(access_token.secret, "access secret");
+        let request_token = new_store.take_request_token("request").unwrap().unwrap();
+        assert_eq!(request_token.keypair.secret, "request secret");
+    }
+}
@@ -0,0 +1,246 @@
+#![feature(futures_api)]
+
+//! Logs in, fetches lists and runs a removal against the fake twitter server, so that the whole
+//! flow is checked without twitter credentials or a network connection.
+
+use de_list_server::{egg_mode_2, job_events, removal, scheduler};
+use egg_mode::KeyPair;
+use egg_mode_2::{ApiEndpoints, Follow};
+use hyper::client::Client;
+use hyper::header::LOCATION;
+use hyper::{Body, StatusCode, Uri};
+use hyper_tls::HttpsConnector;
+use removal::{Journal, Outcome, Step};
+use scheduler::{ScheduledClient, SystemClock};
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use url::Url;
+
+const CONSUMER_KEY: &'static str = "offline-consumer-key";
+const CONSUMER_SECRET: &'static str = "offline-consumer-secret";
+
+/// From `fixtures/fake_twitter.json`.
+const ALICE: u64 = 1001;
+const BOB: u64 = 1002;
+const CAROL: u64 = 1003;
+const ALICES_OWN_LIST: u64 = 2004;
+
+/// The fake twitter binary, killed once the test is done with it.
+struct FakeTwitter {
+    child: Child,
+    base_url: String,
+}
+
+impl FakeTwitter {
+    /// Cargo builds the package's binaries next to the directory that tests run from.
+    fn start() -> FakeTwitter {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .port();
+        let address = format!("127.0.0.1:{}", port);
+        let binary = env::current_exe()
+            .unwrap()
+            .parent()
+            .and_then(|deps| deps.parent())
+            .unwrap()
+            .join(format!("fake_twitter{}", env::consts::EXE_SUFFIX));
+        let child = Command::new(&binary)
+            .env("FAKE_TWITTER_ADDRESS", &address)
+            .env("CONSUMER_KEY", CONSUMER_KEY)
+            .env("CONSUMER_SECRET", CONSUMER_SECRET)
+            .env_remove("FAKE_TWITTER_BASE_URL")
+            .env_remove("FAKE_TWITTER_FIXTURE")
+            .spawn()
+            .unwrap_or_else(|e| panic!("could not start {}: {}", binary.display(), e));
+        let fake_twitter = FakeTwitter {
+            child,
+            base_url: format!("http://{}", address),
+        };
+
+        for _ in 0..100 {
+            if TcpStream::connect(&address).is_ok() {
+                return fake_twitter;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("fake twitter didn't start listening on {}", address);
+    }
+}
+
+impl Drop for FakeTwitter {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Pick alice on the fake login page, returning the verifier that twitter sends the user back
+/// with.
+fn approve_login(
+    runtime: &mut tokio::runtime::Runtime,
+    base_url: &str,
+    request_token: &KeyPair,
+) -> String {
+    let approve: Uri = format!(
+        "{}/oauth/authenticate/approve?oauth_token={}&user_id={}",
+        base_url, request_token.key, ALICE
+    )
+    .parse()
+    .unwrap();
+    let response = runtime.block_on(Client::new().get(approve)).unwrap();
+    assert_eq!(response.status(), StatusCode::FOUND);
+
+    let callback = response.headers()[LOCATION].to_str().unwrap();
+    let params: HashMap<String, String> = Url::parse(callback)
+        .unwrap()
+        .query_pairs()
+        .into_owned()
+        .collect();
+    assert_eq!(params["oauth_token"], request_token.key);
+    params["oauth_verifier"].clone()
+}
+
+#[test]
+fn sign_in_fetch_lists_and_remove() {
+    let fake_twitter = FakeTwitter::start();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let consumer_token = KeyPair::new(CONSUMER_KEY, CONSUMER_SECRET);
+    let endpoints = ApiEndpoints::new(&fake_twitter.base_url).unwrap();
+    let https = HttpsConnector::new(1).unwrap();
+    let client = ScheduledClient::new(
+        Client::builder().build::<_, Body>(https),
+        Arc::new(SystemClock),
+    );
+
+    // Sign in
+    let request_token = runtime
+        .block_on(egg_mode_2::request_token(
+            &consumer_token,
+            "http://127.0.0.1/sign-in-with-twitter",
+            &endpoints,
+            &client,
+        ))
+        .unwrap();
+    let verifier = approve_login(&mut runtime, &fake_twitter.base_url, &request_token);
+    let (access_token, user_id) = runtime
+        .block_on(egg_mode_2::access_token(
+            &consumer_token,
+            &request_token,
+            verifier,
+            &endpoints,
+            &client,
+        ))
+        .unwrap();
+    assert_eq!(user_id, ALICE);
+
+    // Fetch lists
+    let lists = runtime
+        .block_on(egg_mode_2::get_memberships(
+            user_id,
+            &consumer_token,
+            &access_token,
+            &endpoints,
+            &client,
+        ))
+        .unwrap();
+    let mut owners = egg_mode_2::list_owners(&lists);
+    assert_eq!(
+        owners,
+        [BOB, CAROL, ALICE].iter().cloned().collect::<BTreeSet<_>>()
+    );
+    owners.remove(&ALICE);
+    let already_blocked = runtime
+        .block_on(egg_mode_2::get_blocked_ids(
+            &consumer_token,
+            &access_token,
+            &endpoints,
+            &client,
+        ))
+        .unwrap();
+    assert_eq!(
+        already_blocked,
+        [CAROL].iter().cloned().collect::<BTreeSet<_>>()
+    );
+    let relationships = runtime
+        .block_on(egg_mode_2::get_relationships(
+            &owners,
+            &consumer_token,
+            &access_token,
+            &endpoints,
+            &client,
+        ))
+        .unwrap();
+    assert!(relationships[&BOB].following);
+
+    // Remove
+    let steps = Arc::new(Mutex::new(Vec::new()));
+    let recorded_steps = steps.clone();
+    let journal: Journal = Arc::new(move |owner_id, step| {
+        recorded_steps.lock().unwrap().push((owner_id, step));
+        Ok(())
+    });
+    let report: job_events::Reporter = Arc::new(|_| {});
+    let refollow: BTreeSet<u64> = [BOB].iter().cloned().collect();
+    let removals = runtime
+        .block_on(removal::remove_batch(
+            owners,
+            &already_blocked,
+            &refollow,
+            &consumer_token,
+            &access_token,
+            &endpoints,
+            &client,
+            &report,
+            &journal,
+        ))
+        .unwrap();
+    for removal in &removals {
+        match removal.owner_id {
+            BOB => {
+                assert_eq!(*removal.result.as_ref().unwrap(), Outcome::Unblocked);
+                let follow = removal.refollow.as_ref().unwrap().as_ref().unwrap();
+                assert_eq!(*follow, Follow::Following);
+            }
+            CAROL => assert_eq!(*removal.result.as_ref().unwrap(), Outcome::KeptBlocked),
+            other => panic!("removed {} who wasn't asked for", other),
+        }
+        assert!(!removal.still_blocked);
+    }
+    assert_eq!(
+        *steps.lock().unwrap(),
+        vec![
+            (BOB, Step::Blocking),
+            (BOB, Step::Blocked),
+            (BOB, Step::Unblocking),
+            (BOB, Step::Following),
+        ]
+    );
+
+    // Alice is only on her own list now, and only carol is still blocked.
+    let lists = runtime
+        .block_on(egg_mode_2::get_memberships(
+            user_id,
+            &consumer_token,
+            &access_token,
+            &endpoints,
+            &client,
+        ))
+        .unwrap();
+    let list_ids = lists.iter().map(|list| list.id).collect::<Vec<_>>();
+    assert_eq!(list_ids, vec![ALICES_OWN_LIST]);
+    let blocked = runtime
+        .block_on(egg_mode_2::get_blocked_ids(
+            &consumer_token,
+            &access_token,
+            &endpoints,
+            &client,
+        ))
+        .unwrap();
+    assert_eq!(blocked, already_blocked);
+}